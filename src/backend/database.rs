@@ -1,5 +1,9 @@
+use std::fs::remove_file;
+use std::fs::rename;
 use std::thread::spawn;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread::JoinHandle;
 
 use async_channel::Sender;
@@ -8,30 +12,47 @@ use async_channel::unbounded;
 
 use rusqlite::types::Value;
 use rusqlite::Connection;
+use rusqlite::ErrorCode;
+use rusqlite::OptionalExtension;
+use rusqlite::Transaction;
+use rusqlite::params;
 use rusqlite::params_from_iter;
 use rusqlite::ParamsFromIter;
 use rusqlite::types::ValueRef;
 
+use tracing::{error, trace, warn};
+
 use crate::error::Error;
 use crate::error::Res;
 
+use super::sql::ATTACH_EXPORT;
+use super::sql::DETACH_EXPORT;
+use super::sql::EXPORT_DATABASE;
+use super::sql::VERIFY_KEY;
+use super::sql::SELECT_SCHEMA_VERSION;
+use super::sql::SELECT_NODEID_TABLE;
+use super::sql::CREATE_IDENTITY_TABLE;
+use super::sql::MIGRATE_NODEID;
+use super::sql::SELECT_NODEID;
+use super::sql::SELECT_IDENTITY;
+use super::sql::DROP_NODEID;
+use super::sql::DROP_UNCHECKED_TRIGGERS;
+
+const DATABASE_FILE: &str = "data.db";
+const EXPORT_FILE: &str = "export.db";
+
 #[derive(Clone, Debug)]
 pub enum ItemStream {
     Value(Vec<DatabaseParam>),
-    Error,
+    Error(Error),
     End
 }
 
-pub enum InsertMessage {
-    Success(usize),
-    Error
-}
-
 pub enum DatabaseTask {
     Execute(&'static str, DatabaseParams),
-    WaitExecute(&'static str, DatabaseParams, Sender<()>),
-    Insert(&'static str, DatabaseParams, Sender<InsertMessage>),
+    WaitExecute(&'static str, DatabaseParams, Sender<Res<()>>),
     Query(&'static str, DatabaseParams, Sender<ItemStream>),
+    Rekey(Option<String>, Sender<bool>),
 }
 
 #[derive(Clone, Debug)]
@@ -51,15 +72,13 @@ impl DatabaseParam {
             Self::F64(v) => Value::Real(*v),
         }
     }
-    
-    pub fn usize(&self) -> usize {
-        if let Self::Usize(v) = self { return *v; }
-        panic!("Attempted to get a USIZE from a non-usize value");
-    }
 
-    pub fn string(&self) -> String {
-        if let Self::String(v) = self { return v.clone(); }
-        panic!("Attempted to get a STRING from a non-string value");
+    /// The text held, if this is text at all.
+    pub fn string(&self) -> Option<String> {
+        match self {
+            Self::String(v) => Some(v.clone()),
+            _ => None
+        }
     }
 }
 
@@ -100,28 +119,13 @@ impl DataLink {
     pub async fn execute_and_wait(&self, query: &'static str, params: DatabaseParams) -> Res<()> {
         let (sender, receiver) = unbounded();
         let _ = self.task_sender.send_blocking(DatabaseTask::WaitExecute(query, params, sender));
-        match receiver.recv().await {
-            Ok(_) => Ok(()),
-            Err(_) => Err(Error::ChannelDead)
-        }
-    }
-
-    /// Execute function with receiver callback intended for insert commands (returns row id)
-    pub async fn insert(&self, query: &'static str, params: DatabaseParams) -> Option<usize> {
-        let (sender, receiver) = unbounded();
-        let _ = self.task_sender.send_blocking(DatabaseTask::Insert(query, params, sender));
-        let result = match receiver.recv().await {
-            Ok(result) => result,
-            Err(_) => return None
-        };
-
-        match result { InsertMessage::Success(v) => Some(v), InsertMessage::Error => None }
+        receiver.recv().await.unwrap_or(Err(Error::ChannelDead))
     }
 
-    pub fn insert_stream(&self, query: &'static str, params: DatabaseParams) -> Receiver<InsertMessage> {
+    pub fn execute_blocking(&self, query: &'static str, params: DatabaseParams) -> Res<()> {
         let (sender, receiver) = unbounded();
-        let _ = self.task_sender.send_blocking(DatabaseTask::Insert(query, params, sender));
-        receiver
+        let _ = self.task_sender.send_blocking(DatabaseTask::WaitExecute(query, params, sender));
+        receiver.recv_blocking().unwrap_or(Err(Error::ChannelDead))
     }
 
     /// Return a receiver that receives the rows
@@ -139,18 +143,24 @@ impl DataLink {
         let _ = self.task_sender.send(DatabaseTask::Query(query, params, sender)).await;
 
         let mut values = Vec::new();
-        let mut error = false;
         while let Ok(item) = receiver.recv().await {
             match item {
                 ItemStream::End => break,
-                ItemStream::Error => { error = true; break },
+                ItemStream::Error(e) => return Err(e),
                 ItemStream::Value(v) => values.push(v)
             };
         }
+        Ok(values)
+    }
 
-        match error {
-            false => Ok(values),
-            true => Err(Error::Unknown)
+    /// Re-encrypt the database under a new passphrase, or remove encryption entirely with None.
+    pub async fn rekey(&self, key: Option<String>) -> Res<()> {
+        let (sender, receiver) = unbounded();
+        let _ = self.task_sender.send(DatabaseTask::Rekey(key, sender)).await;
+        match receiver.recv().await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(Error::RekeyFailed),
+            Err(_) => Err(Error::ChannelDead)
         }
     }
 
@@ -159,32 +169,44 @@ impl DataLink {
         let _ = self.task_sender.send_blocking(DatabaseTask::Query(query, params, sender));
 
         let mut values = Vec::new();
-        let mut error = false;
-
         while let Ok(item) = receiver.recv_blocking() {
             match item {
                 ItemStream::End => break,
-                ItemStream::Error => { error = true; break },
+                ItemStream::Error(e) => return Err(e),
                 ItemStream::Value(v) => values.push(v)
             }
         }
-
-        match error {
-            false => Ok(values),
-            true => Err(Error::Unknown)
-        }
+        Ok(values)
     }
 
 }
 
 impl Database {
-    pub fn new(root_dir: PathBuf) -> Database {
+    /// Open the database, using the passphrase as the SQLCipher key if one is given.
+    /// Blocks until the file has been opened so that a wrong passphrase can be reported.
+    pub fn new(root_dir: PathBuf, key: Option<String>) -> Res<Database> {
 
         let (task_sender, task_receiver) = unbounded();
+        let (ready_sender, ready_receiver) = unbounded();
+        let handle = spawn(move || database_thread(root_dir, key, task_receiver, ready_sender));
 
-        Database {
-            _handle: spawn(move || database_thread(root_dir, task_receiver)),
+        ready_receiver.recv_blocking().unwrap_or(Err(Error::ChannelDead))?;
+        Ok(Database {
+            _handle: handle,
             datalink: DataLink::new(task_sender)
+        })
+    }
+
+    /// Whether a database exists in the root directory that cannot be read without a passphrase. A file that
+    /// fails to open for any other reason is reported, as no passphrase would open it either.
+    pub fn is_encrypted(root_dir: &Path) -> Res<bool> {
+        let path = root_dir.join(DATABASE_FILE);
+        if !path.exists() { return Ok(false) }
+
+        match open_connection(&path, None) {
+            Ok(_) => Ok(false),
+            Err(e) if is_wrong_key(&e) => Ok(true),
+            Err(e) => Err(Error::Open(Arc::new(e)))
         }
     }
 
@@ -193,13 +215,156 @@ impl Database {
     }
 }
 
-fn database_thread(root_dir: PathBuf, task_receiver: Receiver<DatabaseTask>) {
+/// A fresh, unencrypted database in its own temporary directory, which is removed again once dropped.
+#[cfg(test)]
+pub struct TemporaryDatabase {
+    database: Database,
+    directory: tempfile::TempDir
+}
 
-    let connection = match Connection::open(root_dir.join("data.db")) {
+#[cfg(test)]
+impl TemporaryDatabase {
+    pub fn path(&self) -> &Path {
+        self.directory.path()
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TemporaryDatabase {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.database
+    }
+}
+
+#[cfg(test)]
+impl Database {
+    pub fn temporary() -> TemporaryDatabase {
+        let directory = tempfile::Builder::new().prefix("pingpong-test-").tempdir().expect("temporary directory");
+        let database = Database::new(directory.path().to_path_buf(), None).expect("temporary database");
+        TemporaryDatabase { database, directory }
+    }
+}
+
+/// Open a connection and check that the key (if any) actually decrypts the file.
+fn open_connection(path: &Path, key: Option<&str>) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+
+    if let Some(key) = key {
+        connection.pragma_update(None, "key", key)?;
+    }
+
+    // SQLCipher only rejects a key once the first page is read.
+    connection.query_row(VERIFY_KEY, [], |_| Ok(()))?;
+
+    // Overwrite freed pages, so erased and expired messages do not linger in the file.
+    connection.pragma_update(None, "secure_delete", true)?;
+    Ok(connection)
+}
+
+/// SQLCipher cannot tell a wrong key from a file that is not a database, and reports both the same way.
+fn is_wrong_key(error: &rusqlite::Error) -> bool {
+    error.sqlite_error_code() == Some(ErrorCode::NotADatabase)
+}
+
+/// Bring a database written by an older version up to date. Each migration runs in its own transaction
+/// together with the version bump, so a failure leaves the database as it was.
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row(SELECT_SCHEMA_VERSION, [], |row| row.get(0))?;
+
+    if version < 1 {
+        let transaction = connection.transaction()?;
+        migrate_nodeid(&transaction)?;
+        transaction.pragma_update(None, "user_version", 1)?;
+        transaction.commit()?;
+    }
+
+    if version < 2 {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(DROP_UNCHECKED_TRIGGERS)?;
+        transaction.pragma_update(None, "user_version", 2)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Move the identity out of the NodeID table. NodeID holds the only copy of the user's key, so it is only
+/// dropped once Identity is known to hold the same key.
+fn migrate_nodeid(transaction: &Transaction) -> rusqlite::Result<()> {
+    if transaction.query_row(SELECT_NODEID_TABLE, [], |_| Ok(())).optional()?.is_none() { return Ok(()) }
+
+    transaction.execute(CREATE_IDENTITY_TABLE, [])?;
+    transaction.execute(MIGRATE_NODEID, [])?;
+
+    let old: Option<String> = transaction.query_row(SELECT_NODEID, [], |row| row.get(0)).optional()?;
+    let migrated: Option<String> = transaction.query_row(SELECT_IDENTITY, [], |row| row.get(0)).optional()?;
+    if old.is_some() && old != migrated {
+        warn!("the stored identity differs from the key in NodeID, keeping both");
+        return Ok(())
+    }
+
+    transaction.execute(DROP_NODEID, [])?;
+    Ok(())
+}
+
+/// Export the whole database into a fresh file under the new key, then swap it in place of the old file.
+/// Returns the connection to carry on with, and whether the new key is now in use. No connection means the
+/// database could not be reopened under either key, and nothing more may be written.
+fn rekey(root_dir: &Path, connection: Connection, current: Option<&str>, key: Option<&str>) -> (Option<Connection>, bool) {
+    let database_path = root_dir.join(DATABASE_FILE);
+    let export_path = root_dir.join(EXPORT_FILE);
+    let _ = remove_file(&export_path);
+
+    let exported = connection.execute(ATTACH_EXPORT, params![export_path.to_string_lossy(), key.unwrap_or("")]).is_ok()
+        && connection.query_row(EXPORT_DATABASE, [], |_| Ok(())).is_ok();
+    let _ = connection.execute(DETACH_EXPORT, []);
+
+    // Only swap in an export that is known to open under the new key.
+    if !exported || open_connection(&export_path, key).is_err() {
+        warn!("rekey export failed, keeping the current key");
+        let _ = remove_file(&export_path);
+        return (Some(connection), false);
+    }
+
+    // The old handle must be closed before its file is replaced.
+    drop(connection);
+
+    if let Err(e) = rename(&export_path, &database_path) {
+        warn!(error = %e, "could not replace the database, keeping the current key");
+        let _ = remove_file(&export_path);
+        return (open_connection(&database_path, current).ok(), false);
+    }
+
+    match open_connection(&database_path, key) {
+        Ok(reopened) => (Some(reopened), true),
+        Err(_) => (None, false)
+    }
+}
+
+fn database_thread(root_dir: PathBuf, mut key: Option<String>, task_receiver: Receiver<DatabaseTask>, ready: Sender<Res<()>>) {
+
+    let mut connection = match open_connection(&root_dir.join(DATABASE_FILE), key.as_deref()) {
         Ok(connection) => connection,
-        Err(_) => return
+        Err(e) => {
+            warn!(error = %e, "could not open the database");
+            let _ = ready.send_blocking(Err(match is_wrong_key(&e) {
+                true => Error::IncorrectPassphrase,
+                false => Error::Open(Arc::new(e))
+            }));
+            return
+        }
     };
 
+    if let Err(e) = migrate(&mut connection) {
+        error!(error = %e, "could not migrate the database");
+        let _ = ready.send_blocking(Err(Error::Migrate(Arc::new(e))));
+        return
+    }
+
+    let _ = ready.send_blocking(Ok(()));
+
     'mainloop: loop {
         let current_task = match task_receiver.recv_blocking() {
             Ok(task) => task,
@@ -208,46 +373,56 @@ fn database_thread(root_dir: PathBuf, task_receiver: Receiver<DatabaseTask>) {
 
         match current_task {
             DatabaseTask::Execute(query, params) => {
-                if let Ok(mut statement) = connection.prepare(query) {
-                    let _ = statement.execute(params.to_params());
+                trace!(query = query.trim(), "execute");
+                if let Err(e) = connection.prepare(query).and_then(|mut statement| statement.execute(params.to_params())) {
+                    warn!(query = query.trim(), error = %e, "execute failed");
                 }
             },
             DatabaseTask::WaitExecute(query, params, sender) => {
-                if let Ok(mut statement) = connection.prepare(query) {
-                    let _ = statement.execute(params.to_params());
-                    let _ = sender.send_blocking(());
-                } else {
-                    let _ = sender.send_blocking(());
+                trace!(query = query.trim(), "execute");
+                let result = match connection.prepare(query) {
+                    Ok(mut statement) => statement.execute(params.to_params())
+                        .map(|_| ())
+                        .map_err(|e| Error::Execute { query, source: Arc::new(e) }),
+                    Err(e) => Err(Error::Prepare { query, source: Arc::new(e) })
+                };
+                if let Err(e) = &result {
+                    warn!(query = query.trim(), error = %e, "execute failed");
                 }
+                let _ = sender.send_blocking(result);
             },
-            DatabaseTask::Insert(query, params, sender) => {
-                if let Ok(mut statement) = connection.prepare(query) {
-                    let _ = statement.execute(params.to_params());
-                    let _ = sender.send_blocking(InsertMessage::Success(connection.last_insert_rowid() as usize));
-                } else {
-                    let _ = sender.send_blocking(InsertMessage::Error);
-                }
+            DatabaseTask::Rekey(new_key, sender) => {
+                let (reopened, rekeyed) = rekey(&root_dir, connection, key.as_deref(), new_key.as_deref());
+                let _ = sender.send_blocking(rekeyed);
+                connection = match reopened {
+                    Some(reopened) => reopened,
+                    None => {
+                        // Stop rather than let writes go anywhere but the real file. Every later request
+                        // fails with a dead channel.
+                        error!("database could not be reopened after rekey");
+                        return
+                    }
+                };
+                if rekeyed { key = new_key; }
             }
             DatabaseTask::Query(query, params, sender) => {
+                trace!(query = query.trim(), "query");
                 let mut statement = match connection.prepare(query) {
                     Ok(statement) => statement,
-                    Err(_) => {
-                        let _ = sender.send_blocking(ItemStream::Error);
+                    Err(e) => {
+                        warn!(query = query.trim(), error = %e, "prepare failed");
+                        let _ = sender.send_blocking(ItemStream::Error(Error::Prepare { query, source: Arc::new(e) }));
                         continue
                     }
                 };
 
                 let column_count = statement.column_count();
-                let rows = match statement.query_map(params.to_params(), |row| {
+                // A row that fails part way through fails the whole query, rather than leaving a silent gap.
+                let rows = statement.query_map(params.to_params(), |row| {
                     let mut values = Vec::new();
 
-                    'inner: for idx in 0..column_count {
-                        let value = match row.get_ref(idx) {
-                            Ok(value) => value,
-                            Err(_) => continue 'inner
-                        };
-
-                        let value = match value {
+                    for idx in 0..column_count {
+                        let value = match row.get_ref(idx)? {
                             ValueRef::Null => DatabaseParam::Null,
                             ValueRef::Integer(i) => DatabaseParam::Usize(i as usize),
                             ValueRef::Real(f) => DatabaseParam::F64(f),
@@ -258,12 +433,14 @@ fn database_thread(root_dir: PathBuf, task_receiver: Receiver<DatabaseTask>) {
                         values.push(value);
                     }
 
-                    if column_count == values.len() { Ok(values) }
-                    else { Err(rusqlite::Error::QueryReturnedNoRows) }
-                }) {
-                    Ok(rows) => rows.filter_map(|x| x.ok()).collect::<Vec<Vec<DatabaseParam>>>(),
-                    Err(_) => {
-                        let _ = sender.send_blocking(ItemStream::Error);
+                    Ok(values)
+                }).and_then(|rows| rows.collect::<Result<Vec<Vec<DatabaseParam>>, rusqlite::Error>>());
+
+                let rows = match rows {
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        warn!(query = query.trim(), error = %e, "query failed");
+                        let _ = sender.send_blocking(ItemStream::Error(Error::Query { query, source: Arc::new(e) }));
                         continue 'mainloop
                     }
                 };
@@ -276,3 +453,160 @@ fn database_thread(root_dir: PathBuf, task_receiver: Receiver<DatabaseTask>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREATE: &str = "CREATE TABLE Items (id INTEGER PRIMARY KEY, name TEXT NOT NULL);";
+    const INSERT: &str = "INSERT INTO Items VALUES(?, ?);";
+    const MISSING_TABLE: &str = "INSERT INTO Missing VALUES(1);";
+    // abs() of the smallest integer overflows, which only surfaces when the row is stepped to.
+    const FAILS_ON_SECOND_ROW: &str = "SELECT abs(id) FROM (SELECT 1 AS id UNION ALL SELECT -9223372036854775808) ORDER BY id DESC;";
+
+    #[tokio::test]
+    async fn failed_statements_are_reported() {
+        let database = Database::temporary();
+        let db = database.derive();
+        db.execute_and_wait(CREATE, DatabaseParams::empty()).await.unwrap();
+
+        let row = || DatabaseParams::new(vec![DatabaseParam::Usize(1), DatabaseParam::String(String::from("one"))]);
+        db.execute_and_wait(INSERT, row()).await.unwrap();
+
+        let duplicate = db.execute_and_wait(INSERT, row()).await;
+        assert!(matches!(duplicate, Err(Error::Execute { query: INSERT, .. })));
+        assert!(duplicate.unwrap_err().to_string().contains("UNIQUE constraint failed"));
+
+        let null = db.execute_and_wait(INSERT, DatabaseParams::new(vec![DatabaseParam::Usize(2), DatabaseParam::Null])).await;
+        assert!(matches!(null, Err(Error::Execute { .. })));
+
+        let missing = db.execute_and_wait(MISSING_TABLE, DatabaseParams::empty()).await;
+        assert!(matches!(missing, Err(Error::Prepare { query: MISSING_TABLE, .. })));
+    }
+
+    #[test]
+    fn only_text_reads_as_a_string() {
+        assert_eq!(DatabaseParam::String(String::from("one")).string(), Some(String::from("one")));
+        for param in [DatabaseParam::Usize(1), DatabaseParam::Null, DatabaseParam::F64(1.0)] {
+            assert_eq!(param.string(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_a_wrong_key_counts_as_encrypted() {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(!Database::is_encrypted(directory.path()).unwrap());
+
+        let database = Database::new(directory.path().to_path_buf(), Some(String::from("secret"))).unwrap();
+        database.derive().execute_and_wait(CREATE, DatabaseParams::empty()).await.unwrap();
+        assert!(Database::is_encrypted(directory.path()).unwrap());
+        assert!(matches!(Database::new(directory.path().to_path_buf(), Some(String::from("wrong"))), Err(Error::IncorrectPassphrase)));
+
+        let plain = Database::temporary();
+        assert!(!Database::is_encrypted(plain.path()).unwrap());
+    }
+
+    #[test]
+    fn unreadable_files_are_not_mistaken_for_encryption() {
+        // A directory in place of the database file cannot be opened under any passphrase.
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::create_dir(directory.path().join(DATABASE_FILE)).unwrap();
+
+        assert!(matches!(Database::is_encrypted(directory.path()), Err(Error::Open(_))));
+        assert!(matches!(Database::new(directory.path().to_path_buf(), None), Err(Error::Open(_))));
+        assert!(matches!(Database::new(directory.path().to_path_buf(), Some(String::from("secret"))), Err(Error::Open(_))));
+    }
+
+    /// The identity table of databases written before the identity had a table of its own.
+    const CREATE_NODEID: &str = "CREATE TABLE NodeID (id INTEGER PRIMARY KEY AUTOINCREMENT, secret TEXT NOT NULL, public TEXT NOT NULL);";
+
+    fn old_database(secret: &str) -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        let connection = Connection::open(directory.path().join(DATABASE_FILE)).unwrap();
+        connection.execute(CREATE_NODEID, []).unwrap();
+        connection.execute("INSERT INTO NodeID VALUES(null, ?, 'unused');", [secret]).unwrap();
+        directory
+    }
+
+    fn tables(directory: &Path) -> Vec<String> {
+        schema(directory, "table")
+    }
+
+    fn schema(directory: &Path, kind: &str) -> Vec<String> {
+        let connection = Connection::open(directory.join(DATABASE_FILE)).unwrap();
+        let mut statement = connection.prepare("SELECT name FROM sqlite_master WHERE type = ? ORDER BY name;").unwrap();
+        statement.query_map([kind], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    fn schema_version(directory: &Path) -> usize {
+        let connection = Connection::open(directory.join(DATABASE_FILE)).unwrap();
+        connection.query_row(SELECT_SCHEMA_VERSION, [], |row| row.get(0)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn old_identities_move_once_and_only_when_copied() {
+        let directory = old_database("aa");
+        let database = Database::new(directory.path().to_path_buf(), None).unwrap();
+        let rows = database.derive().query_map(SELECT_IDENTITY, DatabaseParams::empty()).await.unwrap();
+        assert!(matches!(rows.as_slice(), [row] if matches!(row.as_slice(), [DatabaseParam::String(secret)] if secret == "aa")));
+        assert!(!tables(directory.path()).contains(&String::from("NodeID")));
+        assert_eq!(schema_version(directory.path()), 2);
+
+        // An identity that differs from NodeID keeps NodeID, as it may hold the only copy of a key.
+        let directory = old_database("bb");
+        let connection = Connection::open(directory.path().join(DATABASE_FILE)).unwrap();
+        connection.execute(CREATE_IDENTITY_TABLE, []).unwrap();
+        connection.execute("INSERT INTO Identity VALUES(null, 'cc');", []).unwrap();
+        drop(connection);
+
+        let _database = Database::new(directory.path().to_path_buf(), None).unwrap();
+        assert!(tables(directory.path()).contains(&String::from("NodeID")));
+        assert_eq!(schema_version(directory.path()), 2);
+    }
+
+    #[test]
+    fn unchecked_triggers_are_dropped_once() {
+        let directory = tempfile::tempdir().unwrap();
+        let connection = Connection::open(directory.path().join(DATABASE_FILE)).unwrap();
+        connection.execute(CREATE, []).unwrap();
+        connection.execute("CREATE TRIGGER MessageSearchEdit AFTER INSERT ON Items BEGIN SELECT 1; END;", []).unwrap();
+        connection.execute("CREATE TRIGGER Kept AFTER INSERT ON Items BEGIN SELECT 1; END;", []).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+        drop(connection);
+
+        drop(Database::new(directory.path().to_path_buf(), None).unwrap());
+        assert_eq!(schema(directory.path(), "trigger"), ["Kept"]);
+        assert_eq!(schema_version(directory.path()), 2);
+
+        // Triggers made again afterwards are left alone.
+        let connection = Connection::open(directory.path().join(DATABASE_FILE)).unwrap();
+        connection.execute("CREATE TRIGGER MessageSearchEdit AFTER INSERT ON Items BEGIN SELECT 1; END;", []).unwrap();
+        drop(connection);
+
+        drop(Database::new(directory.path().to_path_buf(), None).unwrap());
+        assert_eq!(schema(directory.path(), "trigger"), ["Kept", "MessageSearchEdit"]);
+    }
+
+    #[test]
+    fn failed_migrations_leave_the_database_untouched() {
+        // Identity without its secret column makes the copy fail part way through the migration.
+        let directory = old_database("aa");
+        let connection = Connection::open(directory.path().join(DATABASE_FILE)).unwrap();
+        connection.execute("CREATE TABLE Identity (id INTEGER PRIMARY KEY);", []).unwrap();
+        drop(connection);
+
+        assert!(matches!(Database::new(directory.path().to_path_buf(), None), Err(Error::Migrate(_))));
+        assert!(tables(directory.path()).contains(&String::from("NodeID")));
+        assert_eq!(schema_version(directory.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_rows_fail_the_query() {
+        let database = Database::temporary();
+        let db = database.derive();
+
+        let rows = db.query_map(FAILS_ON_SECOND_ROW, DatabaseParams::empty()).await;
+        assert!(matches!(rows, Err(Error::Query { query: FAILS_ON_SECOND_ROW, .. })));
+        assert!(db.query_blocking(FAILS_ON_SECOND_ROW, DatabaseParams::empty()).is_err());
+    }
+}
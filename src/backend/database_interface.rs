@@ -1,60 +1,751 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, read_to_string, write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use async_channel::Receiver;
-use iroh::SecretKey;
+use ed25519_dalek::Signature;
+use iroh::{NodeId, SecretKey};
 
+use crate::error::{Error, Res};
 use crate::networking::contact::Contact;
+use crate::networking::packet::{timestamp, MessageId, Packet, PacketType, Reaction, Revision, RotationStatement, SignedMessage};
+use crate::networking::ticket::InviteSecret;
 
 use super::database::{DataLink, DatabaseParam, DatabaseParams, ItemStream};
-use super::sql::{CREATE_NODEID_TABLE, CREATE_USERNAME_TABLE, INSERT_USERNAME, SELECT_USERNAME};
-use super::sql::SELECT_NODEID;
-use super::sql::DELETE_NODEID;
+use super::sql::{CREATE_IDENTITY_TABLE, CREATE_USERNAME_TABLE, INSERT_USERNAME, SELECT_USERNAME};
+use super::sql::SELECT_IDENTITY;
+use super::sql::DELETE_IDENTITY;
 use super::sql::CREATE_CONTACTS_TABLE;
 use super::sql::SELECT_ALL_CONTACTS;
 use super::sql::INSERT_CONTACT;
-use super::sql::INSERT_NODEID;
-
-use rand::rngs::OsRng;
+use super::sql::INSERT_IDENTITY;
+use super::sql::CREATE_ROTATIONS_TABLE;
+use super::sql::INSERT_ROTATION;
+use super::sql::SELECT_ROTATIONS;
+use super::sql::SELECT_KEY_CHANGES;
+use super::sql::CREATE_PENDING_ROTATIONS_TABLE;
+use super::sql::QUEUE_ROTATION_FOR_CONTACTS;
+use super::sql::QUEUE_ROTATION;
+use super::sql::SELECT_PENDING_ROTATIONS;
+use super::sql::DELETE_PENDING_ROTATION;
+use super::sql::UPDATE_PENDING_ROTATIONS_CONVERSATION;
+use super::sql::UPDATE_CONTACT_NODE_ID;
+use super::sql::CREATE_MESSAGES_TABLE;
+use super::sql::INSERT_MESSAGE;
+use super::sql::SELECT_CONVERSATION;
+use super::sql::SELECT_CONVERSATION_PAGE;
+use super::sql::SELECT_AMENDMENTS;
+use super::sql::SELECT_MESSAGE_AUTHOR;
+use super::sql::UPDATE_MESSAGES_CONVERSATION;
+use super::sql::DELETE_EXPIRED_MESSAGES;
+use super::sql::SELECT_OLDEST_MESSAGE;
+use super::sql::CREATE_SEARCH_TABLE;
+use super::sql::CREATE_SEARCH_INSERT_TRIGGER;
+use super::sql::CREATE_SEARCH_EDIT_TRIGGER;
+use super::sql::CREATE_SEARCH_DELETE_TRIGGER;
+use super::sql::CREATE_ERASE_TRIGGER;
+use super::sql::ERASE_DELETED_MESSAGES;
+use super::sql::SEARCH_SECURE_DELETE;
+use super::sql::CREATE_SEARCH_EXPIRE_TRIGGER;
+use super::sql::BACKFILL_SEARCH;
+use super::sql::SEARCH_MESSAGES;
+use super::sql::CREATE_REACTIONS_TABLE;
+use super::sql::INSERT_REACTION;
+use super::sql::DELETE_REACTION;
+use super::sql::SELECT_REACTIONS;
+use super::sql::UPDATE_REACTIONS_CONVERSATION;
+use super::sql::DELETE_ORPHANED_REACTIONS;
+use super::sql::CREATE_REACTIONS_DELETE_TRIGGER;
+use super::sql::DELETE_REACTIONS_ON_DELETED;
+use super::sql::SELECT_MESSAGE_DELETED;
+use super::sql::CREATE_READ_CURSORS_TABLE;
+use super::sql::UPSERT_READ_CURSOR;
+use super::sql::UPDATE_READ_CURSOR_CONVERSATION;
+use super::sql::SELECT_UNREAD_COUNTS;
+use super::sql::CREATE_TIMERS_TABLE;
+use super::sql::INSERT_TIMER;
+use super::sql::DELETE_TIMER;
+use super::sql::SELECT_ALL_TIMERS;
+use super::sql::UPDATE_TIMER_CONVERSATION;
+use super::sql::CREATE_MUTES_TABLE;
+use super::sql::INSERT_MUTE;
+use super::sql::DELETE_MUTE;
+use super::sql::SELECT_ALL_MUTES;
+use super::sql::UPDATE_MUTE_CONVERSATION;
+use super::sql::CREATE_INVITES_TABLE;
+use super::sql::INSERT_INVITE;
+use super::sql::SELECT_INVITE;
+use super::sql::DELETE_INVITE;
+use super::sql::DELETE_EXPIRED_INVITES;
+use super::sql::CREATE_SETTINGS_TABLE;
+use super::sql::INSERT_SETTING;
+use super::sql::SELECT_SETTING;
+use super::sql::DELETE_SETTING;
 
 pub struct DatabaseInterface;
 
+/// Keys of the persisted user settings.
+#[derive(Clone, Copy, Debug)]
+pub enum Setting {
+    LockPin,
+    IdleTimeout,
+    NotificationPreview,
+    QuietHours,
+    Discovery,
+    Relay,
+    AddressBook,
+    BroadcastNearby,
+    ListenNearby,
+    RelayFallback
+}
+
+impl Setting {
+    fn key(self) -> &'static str {
+        match self {
+            Self::LockPin => "lock_pin",
+            Self::IdleTimeout => "idle_timeout",
+            Self::NotificationPreview => "notification_preview",
+            Self::QuietHours => "quiet_hours",
+            Self::Discovery => "discovery",
+            Self::Relay => "relay",
+            Self::AddressBook => "address_book",
+            Self::BroadcastNearby => "broadcast_nearby",
+            Self::ListenNearby => "listen_nearby",
+            Self::RelayFallback => "relay_fallback"
+        }
+    }
+}
+
+/// What to look for in the message search. Dates are unix timestamps, both ends inclusive.
+#[derive(Clone, Debug, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub conversation: Option<NodeId>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// Only match messages carrying a link, the one kind of attachment a text message can hold.
+    pub has_attachment: bool
+}
+
+/// One message matching a search. Matched terms in the snippet sit between SNIPPET_START and SNIPPET_END.
+#[derive(Clone, Debug)]
+pub struct SearchHit {
+    pub conversation: NodeId,
+    pub author: NodeId,
+    pub timestamp: u64,
+    pub message_id: MessageId,
+    pub snippet: String
+}
+
+/// How long a one-time invite can be redeemed for, in seconds.
+pub const INVITE_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// First line of an exported conversation, naming the tab separated columns.
+pub const EXPORT_HEADER: &str = "packet_type\tmessage_id\ttimestamp\tauthor\trecipient\treply_to\tcontent_hex\tsignature_hex";
+
+pub const SNIPPET_START: char = '\u{2}';
+pub const SNIPPET_END: char = '\u{3}';
+
 impl DatabaseInterface {
 
-    pub fn make_tables_nonblocking(db: DataLink) {
-        let _ = db.execute(CREATE_NODEID_TABLE, DatabaseParams::empty());
-        let _ = db.execute(CREATE_CONTACTS_TABLE, DatabaseParams::empty());
-        let _ = db.execute(CREATE_USERNAME_TABLE, DatabaseParams::empty());
+    /// Create whatever tables, triggers and indexes are missing, and tidy rows older versions left behind.
+    /// Changes to existing tables are migrations, which run as the database is opened.
+    pub fn make_tables(db: DataLink) -> Res<()> {
+        for query in [
+            CREATE_IDENTITY_TABLE,
+            CREATE_ROTATIONS_TABLE,
+            CREATE_PENDING_ROTATIONS_TABLE,
+            CREATE_CONTACTS_TABLE,
+            CREATE_MESSAGES_TABLE,
+            CREATE_SEARCH_TABLE,
+            SEARCH_SECURE_DELETE,
+            CREATE_SEARCH_INSERT_TRIGGER,
+            CREATE_SEARCH_EDIT_TRIGGER,
+            CREATE_SEARCH_DELETE_TRIGGER,
+            CREATE_ERASE_TRIGGER,
+            CREATE_SEARCH_EXPIRE_TRIGGER,
+            BACKFILL_SEARCH,
+            ERASE_DELETED_MESSAGES,
+            CREATE_REACTIONS_TABLE,
+            CREATE_REACTIONS_DELETE_TRIGGER,
+            DELETE_REACTIONS_ON_DELETED,
+            CREATE_READ_CURSORS_TABLE,
+            CREATE_TIMERS_TABLE,
+            CREATE_MUTES_TABLE,
+            CREATE_INVITES_TABLE,
+            CREATE_USERNAME_TABLE,
+            CREATE_SETTINGS_TABLE,
+        ] {
+            db.execute_blocking(query, DatabaseParams::empty())?;
+        }
+        Ok(())
+    }
+
+    /// Read the stored identity key. A missing or malformed identity is reported rather than replaced,
+    /// so the user can choose between restoring from a recovery phrase and creating a new one.
+    pub async fn select_identity(db: DataLink) -> Res<SecretKey> {
+        Self::parse_identity(db.query_map(SELECT_IDENTITY, DatabaseParams::empty()).await?)
+    }
+
+    /// The same as select_identity, for the UI thread, which waits on the database instead.
+    pub fn select_identity_blocking(db: DataLink) -> Res<SecretKey> {
+        Self::parse_identity(db.query_blocking(SELECT_IDENTITY, DatabaseParams::empty())?)
     }
 
-    pub async fn get_node_id_blocking(db: DataLink) -> (SecretKey, SecretKey) {
+    fn parse_identity(rows: Vec<Vec<DatabaseParam>>) -> Res<SecretKey> {
+        let row = rows.first().ok_or(Error::NoIdentity)?;
+
+        if let Some(DatabaseParam::String(secret)) = row.first()
+            && let Ok(secret_key) = hex::decode(secret)
+            && let Ok(bytecode) = &secret_key.try_into() {
+            return Ok(SecretKey::from_bytes(bytecode));
+        }
 
-        let mut rng = OsRng;
-        
-        if let Ok(results) = db.query_map(SELECT_NODEID, DatabaseParams::empty()).await {
-            if let Some(row) = results.first() {
-                if let (Some(a), Some(b)) = (row.first(), row.get(1)) {
-                    if let (Ok(secret_key), Ok(node_id)) = (hex::decode(a.string()), hex::decode(b.string())) {
-                        if let (Ok(bytecode_a), Ok(bytecode_b)) = (&secret_key.try_into(), &node_id.try_into()) {
-                            return (SecretKey::from_bytes(bytecode_a), SecretKey::from_bytes(bytecode_b));
-                        }
-                    }
+        Err(Error::MalformedIdentity)
+    }
+
+    /// Replace the stored identity. Only called once the user has explicitly chosen to create or restore one.
+    pub async fn store_node_id(db: DataLink, secret_key: SecretKey) -> Res<()> {
+        db.execute_and_wait(DELETE_IDENTITY, DatabaseParams::empty()).await?;
+        db.execute_and_wait(INSERT_IDENTITY, DatabaseParams::single(
+            DatabaseParam::String(hex::encode(secret_key.to_bytes()))
+        )).await
+    }
+
+    /// Record a verified identity rotation, ours or a contact's, and move the contact onto the new id.
+    pub fn record_rotation(db: DataLink, old: NodeId, new: NodeId, statement: &[u8]) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as usize).unwrap_or(0);
+
+        let _ = db.execute(UPDATE_CONTACT_NODE_ID, DatabaseParams::new(vec![
+            DatabaseParam::String(new.to_string()),
+            DatabaseParam::String(old.to_string())
+        ]));
+        let _ = db.execute(UPDATE_MESSAGES_CONVERSATION, DatabaseParams::new(vec![
+            DatabaseParam::String(new.to_string()),
+            DatabaseParam::String(old.to_string())
+        ]));
+        let _ = db.execute(UPDATE_TIMER_CONVERSATION, DatabaseParams::new(vec![
+            DatabaseParam::String(new.to_string()),
+            DatabaseParam::String(old.to_string())
+        ]));
+        let _ = db.execute(UPDATE_REACTIONS_CONVERSATION, DatabaseParams::new(vec![
+            DatabaseParam::String(new.to_string()),
+            DatabaseParam::String(old.to_string())
+        ]));
+        let _ = db.execute(UPDATE_READ_CURSOR_CONVERSATION, DatabaseParams::new(vec![
+            DatabaseParam::String(new.to_string()),
+            DatabaseParam::String(old.to_string())
+        ]));
+        let _ = db.execute(UPDATE_MUTE_CONVERSATION, DatabaseParams::new(vec![
+            DatabaseParam::String(new.to_string()),
+            DatabaseParam::String(old.to_string())
+        ]));
+        let _ = db.execute(UPDATE_PENDING_ROTATIONS_CONVERSATION, DatabaseParams::new(vec![
+            DatabaseParam::String(new.to_string()),
+            DatabaseParam::String(old.to_string())
+        ]));
+        let _ = db.execute(INSERT_ROTATION, DatabaseParams::new(vec![
+            DatabaseParam::String(old.to_string()),
+            DatabaseParam::String(new.to_string()),
+            DatabaseParam::Usize(timestamp),
+            DatabaseParam::String(hex::encode(statement))
+        ]));
+    }
+
+    /// Keep a statement of our own rotation for every saved contact and every connected conversation, until
+    /// it has been delivered to each of them.
+    pub async fn queue_rotation(db: DataLink, statement: &[u8], connected: Vec<NodeId>) -> Res<()> {
+        let statement = hex::encode(statement);
+        db.execute_and_wait(QUEUE_ROTATION_FOR_CONTACTS, DatabaseParams::single(DatabaseParam::String(statement.clone()))).await?;
+        for conversation in connected {
+            db.execute_and_wait(QUEUE_ROTATION, DatabaseParams::new(vec![
+                DatabaseParam::String(conversation.to_string()),
+                DatabaseParam::String(statement.clone())
+            ])).await?;
+        }
+        Ok(())
+    }
+
+    /// Rotation statements a contact has not received yet, oldest first, with the id to clear each one by.
+    pub async fn select_pending_rotations(db: DataLink, conversation: NodeId) -> Res<Vec<(usize, Vec<u8>)>> {
+        let rows = db.query_map(SELECT_PENDING_ROTATIONS, DatabaseParams::single(DatabaseParam::String(conversation.to_string()))).await?;
+
+        Ok(rows.iter().filter_map(|row| match row.as_slice() {
+            [DatabaseParam::Usize(id), DatabaseParam::String(statement)] => hex::decode(statement).ok().map(|statement| (*id, statement)),
+            _ => None
+        }).collect())
+    }
+
+    pub fn delete_pending_rotation(db: DataLink, id: usize) {
+        let _ = db.execute(DELETE_PENDING_ROTATION, DatabaseParams::single(DatabaseParam::Usize(id)));
+    }
+
+    /// Persist a packet in the conversation with a contact, along with the signature that proves its author.
+    /// Text is stored as-is so it stays searchable; other payloads are hex encoded.
+    pub fn insert_message(db: DataLink, conversation: NodeId, recipient: NodeId, packet: &Packet) {
+        let content = match &packet.content {
+            Ok(content) if packet.packet_type.is_signed() => match String::from_utf8(content.clone()) {
+                Ok(text) => text,
+                Err(_) => return
+            },
+            Ok(content) => hex::encode(content),
+            Err(_) => return
+        };
+
+        let _ = db.execute(INSERT_MESSAGE, DatabaseParams::new(vec![
+            DatabaseParam::String(conversation.to_string()),
+            DatabaseParam::String(packet.author.to_string()),
+            DatabaseParam::String(recipient.to_string()),
+            DatabaseParam::Usize(packet.packet_type.to_u8() as usize),
+            DatabaseParam::Usize(packet.timestamp as usize),
+            DatabaseParam::String(content),
+            packet.signature.map(|s| DatabaseParam::String(hex::encode(s.to_bytes()))).unwrap_or(DatabaseParam::Null),
+            packet.id.map(|id| DatabaseParam::String(hex::encode(id))).unwrap_or(DatabaseParam::Null),
+            packet.reply_to.map(|id| DatabaseParam::String(hex::encode(id))).unwrap_or(DatabaseParam::Null)
+        ]));
+    }
+
+    /// Load up to `limit` messages sent before the `before` cursor (or the newest ones), verifying each
+    /// against its author and applying any edits and deletes. Returns the packets oldest first, how many rows
+    /// were rejected, and the cursor for the page before this one if there may be more.
+    pub async fn select_conversation_page(db: DataLink, conversation: NodeId, before: Option<usize>, limit: usize) -> Res<(Vec<Packet>, usize, Option<usize>)> {
+        let before = before.unwrap_or(i64::MAX as usize);
+        let mut rows = db.query_map(SELECT_CONVERSATION_PAGE, DatabaseParams::new(vec![
+            DatabaseParam::String(conversation.to_string()),
+            DatabaseParam::Usize(before),
+            DatabaseParam::Usize(limit)
+        ])).await?;
+        rows.reverse();
+
+        let oldest = match rows.first().and_then(|row| row.first()) {
+            Some(DatabaseParam::Usize(id)) => *id,
+            _ => return Ok((Vec::new(), 0, None))
+        };
+
+        let amendments = db.query_map(SELECT_AMENDMENTS, DatabaseParams::new(vec![
+            DatabaseParam::String(conversation.to_string()),
+            DatabaseParam::String(conversation.to_string()),
+            DatabaseParam::Usize(oldest),
+            DatabaseParam::Usize(before)
+        ])).await?;
+
+        let changes = Self::select_key_changes(db.clone()).await?;
+        let mut packets = Vec::new();
+        let mut rejected = 0;
+
+        for row in rows.iter().map(|row| &row[1..]).chain(amendments.iter().map(|row| row.as_slice())) {
+            if let Some(erased) = Self::erased_message(row) {
+                packets.extend(erased);
+                continue;
+            }
+
+            match Self::verify_message(row) {
+                Ok(packet) if matches!(packet.packet_type, PacketType::Edit | PacketType::Delete) => {
+                    // The target may have expired already, which is fine. Amending someone else's message is not.
+                    let by_author = |original| Self::later_keys(&changes, original).contains(&packet.author);
+                    if let Err(Error::NotAuthor) = Packet::amend(&mut packets, &packet, by_author) { rejected += 1; }
                 }
+                Ok(packet) => packets.push(packet),
+                Err(_) => rejected += 1
             }
         }
 
-        // Clean up malformed node_id prior to creating one.
-        let _ = db.execute_and_wait(DELETE_NODEID, DatabaseParams::empty()).await;
+        Ok((packets, rejected, (rows.len() == limit).then_some(oldest)))
+    }
+
+    pub async fn is_message_deleted(db: DataLink, id: MessageId) -> Res<bool> {
+        let rows = db.query_map(SELECT_MESSAGE_DELETED, DatabaseParams::single(DatabaseParam::String(hex::encode(id)))).await?;
+        Ok(!rows.is_empty())
+    }
+
+    /// A row whose text was erased by a delete. Text messages stay in place as deleted, so the conversation
+    /// still shows where they were. Erased edits are dropped.
+    fn erased_message(row: &[DatabaseParam]) -> Option<Option<Packet>> {
+        let [DatabaseParam::String(author), _, DatabaseParam::Usize(packet_type), DatabaseParam::Usize(timestamp), DatabaseParam::Null, _, DatabaseParam::String(id), reply_to] = row else {
+            return None
+        };
+
+        let packet_type = PacketType::from_u8(*packet_type as u8);
+        if packet_type != PacketType::String { return Some(None) }
+
+        let mut packet = Packet::new(NodeId::from_str(author).ok()?, Ok(Vec::new()), packet_type);
+        packet.timestamp = *timestamp as u64;
+        packet.id = hex::decode(id).ok().and_then(|id| id.try_into().ok());
+        packet.reply_to = match reply_to {
+            DatabaseParam::String(id) => hex::decode(id).ok().and_then(|id| id.try_into().ok()),
+            _ => None
+        };
+        packet.revision = Revision::Deleted;
+        Some(Some(packet))
+    }
 
-        let secret_key_a: SecretKey = SecretKey::generate(&mut rng);
-        let secret_key_b: SecretKey = SecretKey::generate(&mut rng);
+    /// Author of the text message with the given id, if it is stored.
+    pub async fn select_message_author(db: DataLink, id: MessageId) -> Res<Option<NodeId>> {
+        let rows = db.query_map(SELECT_MESSAGE_AUTHOR, DatabaseParams::single(DatabaseParam::String(hex::encode(id)))).await?;
 
-        let key_string_a = hex::encode(secret_key_a.to_bytes());
-        let key_string_b = hex::encode(secret_key_b.to_bytes());
+        Ok(rows.first().and_then(|row| match row.first() {
+            Some(DatabaseParam::String(author)) => NodeId::from_str(author).ok(),
+            _ => None
+        }))
+    }
 
-        let _ = db.execute_and_wait(INSERT_NODEID, DatabaseParams::new(vec![
-            DatabaseParam::String(key_string_a),
-            DatabaseParam::String(key_string_b)
-        ])).await;
+    /// Every key the author of a text message has held since sending it, starting with the one it was sent
+    /// under. Rows keep the key that signed them, so an author that rotated since is only found this way.
+    /// Empty if the message is not stored.
+    pub async fn select_author_keys(db: DataLink, id: MessageId) -> Res<Vec<NodeId>> {
+        let Some(author) = Self::select_message_author(db.clone(), id).await? else { return Ok(Vec::new()) };
+        Ok(Self::later_keys(&Self::select_key_changes(db).await?, author))
+    }
 
-        (secret_key_a, secret_key_b)
+    /// Every recorded rotation, ours and our contacts', oldest first.
+    pub async fn select_key_changes(db: DataLink) -> Res<Vec<(NodeId, NodeId)>> {
+        let rows = db.query_map(SELECT_KEY_CHANGES, DatabaseParams::empty()).await?;
+        Ok(rows.iter().filter_map(|row| match row.as_slice() {
+            [DatabaseParam::String(old), DatabaseParam::String(new)] => Some((NodeId::from_str(old).ok()?, NodeId::from_str(new).ok()?)),
+            _ => None
+        }).collect())
+    }
+
+    /// The key and each key it was rotated to after it, in order.
+    pub fn later_keys(changes: &[(NodeId, NodeId)], key: NodeId) -> Vec<NodeId> {
+        let mut keys = vec![key];
+        for (old, new) in changes {
+            if keys.last() == Some(old) && !keys.contains(new) { keys.push(*new); }
+        }
+        keys
+    }
+
+    /// Write the verified history with a contact to a tab separated file. Each line holds everything a third
+    /// party needs to check the signature against the author's node id, and key rotations on either side are
+    /// included so messages sent under an earlier key can still be tied to the same person.
+    pub async fn export_conversation(db: DataLink, directory: PathBuf, conversation: NodeId) -> Res<PathBuf> {
+        let rows = db.query_map(SELECT_CONVERSATION, DatabaseParams::single(DatabaseParam::String(conversation.to_string()))).await?;
+        let rotations = db.query_map(SELECT_ROTATIONS, DatabaseParams::empty()).await?;
+        let identity = Self::select_identity(db.clone()).await?.public();
+
+        // Our own rotations are not stored in the conversation. Follow them back from the current identity.
+        let mut current = identity;
+        let mut own = Vec::new();
+        for row in rotations.iter().rev() {
+            if let [DatabaseParam::String(old), DatabaseParam::String(new), DatabaseParam::Usize(timestamp), DatabaseParam::String(statement)] = row.as_slice()
+                && *new == current.to_string()
+                && let Ok(old) = NodeId::from_str(old) {
+                own.push(format!("{}\t\t{timestamp}\t{new}\t\t\t{statement}\t", PacketType::Rotation.to_u8()));
+                current = old;
+            }
+        }
+
+        let mut lines = vec![String::from(EXPORT_HEADER)];
+        lines.extend(own.into_iter().rev());
+        for row in rows {
+            let (Ok(packet), [DatabaseParam::String(author), DatabaseParam::String(recipient), ..]) = (Self::verify_message(&row), row.as_slice()) else { continue };
+            let Ok(content) = packet.content else { continue };
+
+            match (packet.signature, packet.id) {
+                (Some(signature), Some(id)) => lines.push(format!(
+                    "{}\t{}\t{}\t{author}\t{recipient}\t{}\t{}\t{}",
+                    packet.packet_type.to_u8(), hex::encode(id), packet.timestamp,
+                    packet.reply_to.map(hex::encode).unwrap_or_default(), hex::encode(content), hex::encode(signature.to_bytes())
+                )),
+                _ if packet.packet_type == PacketType::Rotation => lines.push(format!(
+                    "{}\t\t{}\t{author}\t{recipient}\t\t{}\t",
+                    packet.packet_type.to_u8(), packet.timestamp, hex::encode(content)
+                )),
+                _ => {}
+            }
+        }
+
+        let _ = create_dir_all(&directory);
+        let path = directory.join(format!("{conversation}.tsv"));
+        write(&path, lines.join("\n")).map_err(|e| Error::ExportFailed(Arc::new(e)))?;
+        Ok(path)
+    }
+
+    /// Check an exported conversation file. Returns how many messages were verified.
+    pub async fn verify_export_file(path: PathBuf) -> Res<usize> {
+        let export = read_to_string(&path).map_err(|e| Error::ExportUnreadable(Arc::new(e)))?;
+        Self::verify_export(&export)
+    }
+
+    /// Every message must carry a valid signature, and every author and recipient must be one of the two
+    /// parties, following each of them through the rotations in the export.
+    pub fn verify_export(export: &str) -> Res<usize> {
+        let mut lines = export.lines().enumerate().map(|(index, line)| (index + 1, line));
+        if lines.next().map(|(_, header)| header) != Some(EXPORT_HEADER) { return Err(Error::InvalidExport(1)) }
+
+        fn hex_array<const N: usize>(field: &str) -> Option<[u8; N]> {
+            hex::decode(field).ok().and_then(|bytes| bytes.try_into().ok())
+        }
+
+        // Keys a party rotated to, mapped to the first key they were known by.
+        let mut owners: HashMap<NodeId, NodeId> = HashMap::new();
+        let mut messages = Vec::new();
+
+        for (number, line) in lines {
+            let invalid = || Error::InvalidExport(number);
+            let [packet_type, id, timestamp, author, recipient, reply_to, content, signature] = line.split('\t').collect::<Vec<_>>()[..] else {
+                return Err(invalid());
+            };
+
+            let packet_type = PacketType::from_u8(packet_type.parse().map_err(|_| invalid())?);
+            let author = NodeId::from_str(author).map_err(|_| invalid())?;
+            let content = hex::decode(content).map_err(|_| invalid())?;
+
+            if packet_type == PacketType::Rotation {
+                let statement = RotationStatement::from_bytes(&content).map_err(|_| invalid())?;
+                statement.verify(author).map_err(|_| invalid())?;
+                let owner = owners.get(&statement.old).copied().unwrap_or(statement.old);
+                owners.insert(statement.new, owner);
+                continue;
+            }
+            if !packet_type.is_signed() { return Err(invalid()) }
+
+            let message = SignedMessage {
+                id: hex_array(id).ok_or_else(invalid)?,
+                timestamp: timestamp.parse().map_err(|_| invalid())?,
+                signature: Signature::from_bytes(&hex_array(signature).ok_or_else(invalid)?),
+                reply_to: match reply_to {
+                    "" => None,
+                    reply_to => Some(hex_array(reply_to).ok_or_else(invalid)?)
+                },
+                content
+            };
+
+            let recipient = NodeId::from_str(recipient).map_err(|_| invalid())?;
+            message.verify(packet_type, author, recipient).map_err(|_| invalid())?;
+            messages.push((number, author, recipient));
+        }
+
+        let owner = |key: NodeId| owners.get(&key).copied().unwrap_or(key);
+        let mut parties = HashSet::new();
+        for (number, author, recipient) in &messages {
+            parties.extend([owner(*author), owner(*recipient)]);
+            if parties.len() > 2 { return Err(Error::InvalidExport(*number)) }
+        }
+        Ok(messages.len())
+    }
+
+    fn verify_message(row: &[DatabaseParam]) -> Res<Packet> {
+        let [
+            DatabaseParam::String(author),
+            DatabaseParam::String(recipient),
+            DatabaseParam::Usize(packet_type),
+            DatabaseParam::Usize(timestamp),
+            DatabaseParam::String(content),
+            signature,
+            message_id,
+            reply_to
+        ] = row else { return Err(Error::InvalidSignature) };
+
+        let author = NodeId::from_str(author).map_err(|_| Error::InvalidSignature)?;
+        let recipient = NodeId::from_str(recipient).map_err(|_| Error::InvalidSignature)?;
+
+        match PacketType::from_u8(*packet_type as u8) {
+            packet_type if packet_type.is_signed() => {
+                let signature: [u8; 64] = match signature {
+                    DatabaseParam::String(signature) => hex::decode(signature).ok().and_then(|s| s.try_into().ok()),
+                    _ => None
+                }.ok_or(Error::InvalidSignature)?;
+
+                let id: MessageId = match message_id {
+                    DatabaseParam::String(id) => hex::decode(id).ok().and_then(|id| id.try_into().ok()),
+                    _ => None
+                }.ok_or(Error::InvalidSignature)?;
+
+                let reply_to: Option<MessageId> = match reply_to {
+                    DatabaseParam::String(id) => Some(hex::decode(id).ok().and_then(|id| id.try_into().ok()).ok_or(Error::InvalidSignature)?),
+                    _ => None
+                };
+
+                let message = SignedMessage {
+                    id,
+                    timestamp: *timestamp as u64,
+                    signature: Signature::from_bytes(&signature),
+                    reply_to,
+                    content: content.as_bytes().to_vec()
+                };
+
+                message.verify(packet_type, author, recipient)?;
+                Ok(message.into_packet(author, packet_type))
+            }
+
+            PacketType::Rotation => {
+                let statement = hex::decode(content).map_err(|_| Error::InvalidRotation)?;
+                let parsed = RotationStatement::from_bytes(&statement)?;
+                parsed.verify(parsed.old)?;
+
+                let mut packet = Packet::new(author, Ok(statement), PacketType::Rotation);
+                packet.timestamp = *timestamp as u64;
+                Ok(packet)
+            }
+
+            _ => Err(Error::InvalidSignature)
+        }
+    }
+
+    /// Mark everything stored in a conversation so far as read. The cursor never moves backwards.
+    pub fn mark_read(db: DataLink, conversation: NodeId) {
+        let _ = db.execute(UPSERT_READ_CURSOR, DatabaseParams::single(DatabaseParam::String(conversation.to_string())));
+    }
+
+    /// Number of unread incoming messages in every conversation that has any.
+    pub async fn select_unread_counts(db: DataLink) -> Res<HashMap<NodeId, usize>> {
+        let rows = db.query_map(SELECT_UNREAD_COUNTS, DatabaseParams::empty()).await?;
+
+        Ok(rows.iter().filter_map(|row| match row.as_slice() {
+            [DatabaseParam::String(conversation), DatabaseParam::Usize(count)] =>
+                NodeId::from_str(conversation).ok().map(|node_id| (node_id, *count)),
+            _ => None
+        }).collect())
+    }
+
+    /// Persist the disappearing message timer for a conversation, None turning it off.
+    pub fn set_timer(db: DataLink, conversation: NodeId, seconds: Option<u64>) {
+        let _ = match seconds {
+            Some(seconds) => db.execute(INSERT_TIMER, DatabaseParams::new(vec![
+                DatabaseParam::String(conversation.to_string()),
+                DatabaseParam::Usize(seconds as usize)
+            ])),
+            None => db.execute(DELETE_TIMER, DatabaseParams::single(DatabaseParam::String(conversation.to_string())))
+        };
+    }
+
+    pub async fn select_timers(db: DataLink) -> HashMap<NodeId, u64> {
+        let rows = db.query_map(SELECT_ALL_TIMERS, DatabaseParams::empty()).await.unwrap_or_default();
+
+        rows.iter().filter_map(|row| match row.as_slice() {
+            [DatabaseParam::String(conversation), DatabaseParam::Usize(seconds)] =>
+                NodeId::from_str(conversation).ok().map(|node_id| (node_id, *seconds as u64)),
+            _ => None
+        }).collect()
+    }
+
+    /// Silence or restore notifications from a conversation.
+    pub fn set_muted(db: DataLink, conversation: NodeId, muted: bool) {
+        let param = DatabaseParams::single(DatabaseParam::String(conversation.to_string()));
+        let _ = match muted {
+            true => db.execute(INSERT_MUTE, param),
+            false => db.execute(DELETE_MUTE, param)
+        };
+    }
+
+    pub fn select_muted(db: DataLink) -> HashSet<NodeId> {
+        let rows = db.query_blocking(SELECT_ALL_MUTES, DatabaseParams::empty()).unwrap_or_default();
+
+        rows.iter().filter_map(|row| match row.as_slice() {
+            [DatabaseParam::String(conversation)] => NodeId::from_str(conversation).ok(),
+            _ => None
+        }).collect()
+    }
+
+    pub fn insert_invite(db: DataLink, secret: InviteSecret) {
+        let _ = db.execute(INSERT_INVITE, DatabaseParams::new(vec![
+            DatabaseParam::String(hex::encode(secret)),
+            DatabaseParam::Usize(timestamp() as usize)
+        ]));
+    }
+
+    /// Use up a one-time invite, returning whether it was valid. Runs on the network thread, which handles
+    /// packets one at a time, so a secret cannot be redeemed twice between the check and the delete.
+    /// Invites older than INVITE_TTL are invalid, and are cleared out on the way.
+    pub async fn consume_invite(db: DataLink, secret: InviteSecret) -> Res<bool> {
+        let oldest = DatabaseParam::Usize(timestamp().saturating_sub(INVITE_TTL) as usize);
+        db.execute_and_wait(DELETE_EXPIRED_INVITES, DatabaseParams::single(oldest.clone())).await?;
+
+        let secret = DatabaseParam::String(hex::encode(secret));
+        if db.query_map(SELECT_INVITE, DatabaseParams::new(vec![secret.clone(), oldest])).await?.is_empty() { return Ok(false) }
+
+        db.execute_and_wait(DELETE_INVITE, DatabaseParams::single(secret)).await?;
+        Ok(true)
+    }
+
+    /// When the oldest message still stored in a conversation was sent, if there is one.
+    pub async fn select_oldest_message(db: DataLink, conversation: NodeId) -> Res<Option<u64>> {
+        let rows = db.query_map(SELECT_OLDEST_MESSAGE, DatabaseParams::single(DatabaseParam::String(conversation.to_string()))).await?;
+
+        Ok(match rows.first().map(|row| row.as_slice()) {
+            Some([DatabaseParam::Usize(oldest)]) => Some(*oldest as u64),
+            _ => None
+        })
+    }
+
+    /// Delete every stored message in a conversation sent at or before the cutoff.
+    pub fn delete_expired(db: DataLink, conversation: NodeId, cutoff: u64) {
+        let _ = db.execute(DELETE_EXPIRED_MESSAGES, DatabaseParams::new(vec![
+            DatabaseParam::String(conversation.to_string()),
+            DatabaseParam::Usize(cutoff as usize)
+        ]));
+        let _ = db.execute(DELETE_ORPHANED_REACTIONS, DatabaseParams::single(DatabaseParam::String(conversation.to_string())));
+    }
+
+    /// Search the text of every conversation. Each word of the query must appear, the last one as a prefix
+    /// so results show up while typing.
+    pub async fn search_messages(db: DataLink, query: SearchQuery) -> Res<Vec<SearchHit>> {
+        let words: Vec<String> = query.text.split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect();
+        if words.is_empty() { return Ok(Vec::new()) }
+
+        let conversation = query.conversation.map(|c| c.to_string()).unwrap_or_default();
+        let rows = db.query_map(SEARCH_MESSAGES, DatabaseParams::new(vec![
+            DatabaseParam::String(format!("{}*", words.join(" "))),
+            DatabaseParam::String(conversation.clone()),
+            DatabaseParam::String(conversation),
+            DatabaseParam::Usize(query.from.unwrap_or(0) as usize),
+            DatabaseParam::Usize(query.to.map(|to| to as usize).unwrap_or(i64::MAX as usize)),
+            DatabaseParam::Usize(query.has_attachment as usize)
+        ])).await?;
+
+        Ok(rows.iter().filter_map(|row| match row.as_slice() {
+            [
+                DatabaseParam::String(conversation),
+                DatabaseParam::String(author),
+                DatabaseParam::Usize(timestamp),
+                DatabaseParam::String(message_id),
+                DatabaseParam::String(snippet)
+            ] => Some(SearchHit {
+                conversation: NodeId::from_str(conversation).ok()?,
+                author: NodeId::from_str(author).ok()?,
+                timestamp: *timestamp as u64,
+                message_id: hex::decode(message_id).ok()?.try_into().ok()?,
+                snippet: snippet.clone()
+            }),
+            _ => None
+        }).collect())
+    }
+
+    /// Add or remove one person's reaction to a message.
+    pub fn set_reaction(db: DataLink, conversation: NodeId, author: NodeId, reaction: &Reaction) {
+        let _ = if reaction.added {
+            db.execute(INSERT_REACTION, DatabaseParams::new(vec![
+                DatabaseParam::String(conversation.to_string()),
+                DatabaseParam::String(hex::encode(reaction.id)),
+                DatabaseParam::String(author.to_string()),
+                DatabaseParam::String(reaction.emoji.clone())
+            ]))
+        } else {
+            db.execute(DELETE_REACTION, DatabaseParams::new(vec![
+                DatabaseParam::String(hex::encode(reaction.id)),
+                DatabaseParam::String(author.to_string()),
+                DatabaseParam::String(reaction.emoji.clone())
+            ]))
+        };
+    }
+
+    /// Every reaction currently standing in a conversation, as (author, reaction) pairs.
+    pub async fn select_reactions(db: DataLink, conversation: NodeId) -> Res<Vec<(NodeId, Reaction)>> {
+        let rows = db.query_map(SELECT_REACTIONS, DatabaseParams::single(DatabaseParam::String(conversation.to_string()))).await?;
+
+        Ok(rows.iter().filter_map(|row| match row.as_slice() {
+            [DatabaseParam::String(id), DatabaseParam::String(author), DatabaseParam::String(emoji)] => Some((
+                NodeId::from_str(author).ok()?,
+                Reaction { id: hex::decode(id).ok()?.try_into().ok()?, emoji: emoji.clone(), added: true }
+            )),
+            _ => None
+        }).collect())
     }
 
     pub fn select_all_contacts(db: DataLink) -> Receiver<ItemStream> {
@@ -67,7 +758,7 @@ impl DatabaseInterface {
 
     pub fn select_username(db: DataLink) -> Option<String> {
         match db.query_blocking(SELECT_USERNAME, DatabaseParams::empty()) {
-            Ok(rows) => if let Some(first) = rows.first() { first.first().map(|p| p.string()) } else { None },
+            Ok(rows) => rows.first().and_then(|first| first.first()).and_then(DatabaseParam::string),
             Err(_) => None
         }
     }
@@ -75,4 +766,371 @@ impl DatabaseInterface {
     pub fn insert_username(db: DataLink, username: String) {
         let _ = db.execute(INSERT_USERNAME, DatabaseParams::single(DatabaseParam::String(username)));
     }
+
+    pub fn select_setting(db: DataLink, setting: Setting) -> Option<String> {
+        match db.query_blocking(SELECT_SETTING, DatabaseParams::single(DatabaseParam::String(setting.key().to_string()))) {
+            Ok(rows) => rows.first().and_then(|first| first.first()).and_then(DatabaseParam::string),
+            Err(_) => None
+        }
+    }
+
+    pub fn insert_setting(db: DataLink, setting: Setting, value: String) {
+        let _ = db.execute(INSERT_SETTING, DatabaseParams::new(vec![
+            DatabaseParam::String(setting.key().to_string()),
+            DatabaseParam::String(value)
+        ]));
+    }
+
+    pub fn delete_setting(db: DataLink, setting: Setting) {
+        let _ = db.execute(DELETE_SETTING, DatabaseParams::single(DatabaseParam::String(setting.key().to_string())));
+    }
+
+    /// Encrypt the database under a new passphrase, or decrypt it if None.
+    pub async fn set_passphrase(db: DataLink, passphrase: Option<String>) -> Res<()> {
+        db.rekey(passphrase).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::database::{Database, TemporaryDatabase};
+    use rand::rngs::OsRng;
+
+    /// A database with every table, and the keys of both sides of a conversation.
+    struct Fixture {
+        _database: TemporaryDatabase,
+        db: DataLink,
+        us: SecretKey,
+        them: SecretKey
+    }
+
+    fn fixture() -> Fixture {
+        let database = Database::temporary();
+        let db = database.derive();
+        DatabaseInterface::make_tables(db.clone()).unwrap();
+        Fixture { _database: database, db, us: key(), them: key() }
+    }
+
+    fn key() -> SecretKey {
+        SecretKey::generate(&mut OsRng)
+    }
+
+    fn node_id() -> NodeId {
+        key().public()
+    }
+
+    fn signed(author: &SecretKey, recipient: NodeId, text: &str) -> Packet {
+        SignedMessage::new(author, recipient, PacketType::String, rand::random(), None, text.as_bytes().to_vec())
+            .into_packet(author.public(), PacketType::String)
+    }
+
+    /// A conversation with a contact that rotated its key, where we also rotated ours, exported to a file.
+    async fn exported_conversation() -> String {
+        let Fixture { _database: database, db, us, them } = fixture();
+
+        let (us_later, them_later) = (key(), key());
+        DatabaseInterface::store_node_id(db.clone(), us.clone()).await.unwrap();
+
+        let conversation = them.public();
+        DatabaseInterface::insert_message(db.clone(), conversation, us.public(), &signed(&them, us.public(), "hello"));
+        DatabaseInterface::insert_message(db.clone(), conversation, conversation, &signed(&us, conversation, "hi"));
+
+        // We rotate first, so the contact's next message is addressed to our new key.
+        let ours = RotationStatement::new(&us, &us_later).to_bytes();
+        DatabaseInterface::store_node_id(db.clone(), us_later.clone()).await.unwrap();
+        DatabaseInterface::record_rotation(db.clone(), us.public(), us_later.public(), &ours);
+        DatabaseInterface::insert_message(db.clone(), conversation, us_later.public(), &signed(&them, us_later.public(), "new key?"));
+
+        let theirs = RotationStatement::new(&them, &them_later).to_bytes();
+        DatabaseInterface::record_rotation(db.clone(), them.public(), them_later.public(), &theirs);
+        let conversation = them_later.public();
+        DatabaseInterface::insert_message(db.clone(), conversation, us_later.public(), &Packet::new(conversation, Ok(theirs), PacketType::Rotation));
+        DatabaseInterface::insert_message(db.clone(), conversation, us_later.public(), &signed(&them_later, us_later.public(), "me too"));
+
+        let path = DatabaseInterface::export_conversation(db.clone(), database.path().join("exports"), conversation).await.unwrap();
+        read_to_string(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tables_can_be_made_on_every_start() {
+        let Fixture { _database, db, us, them } = fixture();
+        DatabaseInterface::make_tables(db.clone()).unwrap();
+
+        DatabaseInterface::record_rotation(db.clone(), us.public(), them.public(), b"statement");
+        let rotations = db.query_map(SELECT_ROTATIONS, DatabaseParams::empty()).await.unwrap();
+        assert!(matches!(rotations[0].get(3), Some(DatabaseParam::String(statement)) if *statement == hex::encode(b"statement")));
+    }
+
+    #[test]
+    fn later_keys_follow_only_the_chain() {
+        let (a, b, c, other) = (node_id(), node_id(), node_id(), node_id());
+        let changes = [(a, b), (other, a), (b, c)];
+        assert_eq!(DatabaseInterface::later_keys(&changes, a), [a, b, c]);
+        assert_eq!(DatabaseInterface::later_keys(&changes, b), [b, c]);
+        assert_eq!(DatabaseInterface::later_keys(&changes, c), [c]);
+    }
+
+    #[tokio::test]
+    async fn messages_stay_editable_after_their_author_rotates() {
+        let Fixture { _database, db, us, them } = fixture();
+        let them_later = key();
+
+        let original = signed(&them, us.public(), "before");
+        let id = original.id.unwrap();
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &original);
+
+        let statement = RotationStatement::new(&them, &them_later).to_bytes();
+        DatabaseInterface::record_rotation(db.clone(), them.public(), them_later.public(), &statement);
+        assert_eq!(DatabaseInterface::select_author_keys(db.clone(), id).await.unwrap(), [them.public(), them_later.public()]);
+
+        let edit = SignedMessage::new(&them_later, us.public(), PacketType::Edit, id, None, b"after".to_vec())
+            .into_packet(them_later.public(), PacketType::Edit);
+        DatabaseInterface::insert_message(db.clone(), them_later.public(), us.public(), &edit);
+
+        let (packets, rejected, _) = DatabaseInterface::select_conversation_page(db.clone(), them_later.public(), None, 10).await.unwrap();
+        assert_eq!(rejected, 0);
+        assert_eq!(packets[0].content.as_deref().unwrap(), b"after");
+        assert_eq!(packets[0].revision, Revision::Edited);
+    }
+
+    #[tokio::test]
+    async fn identity_is_reported_missing_until_stored() {
+        let Fixture { _database, db, us, .. } = fixture();
+        assert!(matches!(DatabaseInterface::select_identity(db.clone()).await, Err(Error::NoIdentity)));
+        assert!(matches!(DatabaseInterface::select_identity_blocking(db.clone()), Err(Error::NoIdentity)));
+
+        DatabaseInterface::store_node_id(db.clone(), us.clone()).await.unwrap();
+        assert_eq!(DatabaseInterface::select_identity(db.clone()).await.unwrap().public(), us.public());
+        assert_eq!(DatabaseInterface::select_identity_blocking(db.clone()).unwrap().public(), us.public());
+    }
+
+    #[tokio::test]
+    async fn expired_messages_leave_the_next_oldest() {
+        let Fixture { _database, db, us, them } = fixture();
+        assert_eq!(DatabaseInterface::select_oldest_message(db.clone(), them.public()).await.unwrap(), None);
+
+        for (timestamp, text) in [(100, "first"), (200, "second")] {
+            let mut packet = signed(&them, us.public(), text);
+            packet.timestamp = timestamp;
+            DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &packet);
+        }
+        assert_eq!(DatabaseInterface::select_oldest_message(db.clone(), them.public()).await.unwrap(), Some(100));
+
+        DatabaseInterface::delete_expired(db.clone(), them.public(), 150);
+        assert_eq!(DatabaseInterface::select_oldest_message(db.clone(), them.public()).await.unwrap(), Some(200));
+    }
+
+    #[tokio::test]
+    async fn rotation_notices_never_expire() {
+        let Fixture { _database, db, us, them } = fixture();
+        let later = key();
+
+        let mut notice = Packet::new(later.public(), Ok(RotationStatement::new(&them, &later).to_bytes()), PacketType::Rotation);
+        notice.timestamp = 50;
+        DatabaseInterface::insert_message(db.clone(), later.public(), us.public(), &notice);
+        assert_eq!(DatabaseInterface::select_oldest_message(db.clone(), later.public()).await.unwrap(), None);
+
+        DatabaseInterface::delete_expired(db.clone(), later.public(), 150);
+        let (packets, rejected, _) = DatabaseInterface::select_conversation_page(db.clone(), later.public(), None, 10).await.unwrap();
+        assert_eq!((packets.len(), rejected), (1, 0));
+        assert_eq!(packets[0].packet_type, PacketType::Rotation);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_message_erases_its_text() {
+        let Fixture { _database, db, us, them } = fixture();
+        let original = signed(&them, us.public(), "secret plans");
+        let id = original.id.unwrap();
+        let amend = |packet_type, text: &str| SignedMessage::new(&them, us.public(), packet_type, id, None, text.as_bytes().to_vec())
+            .into_packet(them.public(), packet_type);
+
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &original);
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &amend(PacketType::Edit, "secret plans, revised"));
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &signed(&them, us.public(), "kept"));
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &amend(PacketType::Delete, ""));
+
+        let search = |text: &str| DatabaseInterface::search_messages(db.clone(), SearchQuery { text: String::from(text), ..SearchQuery::default() });
+        assert!(search("secret").await.unwrap().is_empty());
+        assert_eq!(search("kept").await.unwrap().len(), 1);
+
+        // Only the signed delete still mentions the message, and it carries no text.
+        let rows = db.query_map(SELECT_CONVERSATION, DatabaseParams::single(DatabaseParam::String(them.public().to_string()))).await.unwrap();
+        assert!(rows.iter().all(|row| !matches!(&row[4], DatabaseParam::String(content) if content.contains("secret"))));
+
+        assert!(DatabaseInterface::is_message_deleted(db.clone(), id).await.unwrap());
+        let (packets, rejected, _) = DatabaseInterface::select_conversation_page(db.clone(), them.public(), None, 10).await.unwrap();
+        assert_eq!(rejected, 0);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].revision, Revision::Deleted);
+        assert_eq!(packets[0].author, them.public());
+    }
+
+    #[tokio::test]
+    async fn edits_after_a_delete_stay_hidden() {
+        let Fixture { _database, db, us, them } = fixture();
+        let original = signed(&them, us.public(), "secret plans");
+        let amend = |packet_type, text: &str| SignedMessage::new(&them, us.public(), packet_type, original.id.unwrap(), None, text.as_bytes().to_vec())
+            .into_packet(them.public(), packet_type);
+
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &original);
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &amend(PacketType::Delete, ""));
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &amend(PacketType::Edit, "secret plans, again"));
+
+        let (packets, _, _) = DatabaseInterface::select_conversation_page(db.clone(), them.public(), None, 10).await.unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].revision, Revision::Deleted);
+        assert_eq!(packets[0].content.as_deref().unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn only_the_author_changes_what_search_finds() {
+        let Fixture { _database, db, us, them } = fixture();
+        let original = signed(&them, us.public(), "secret plans");
+        let forge = |packet_type, text: &str| SignedMessage::new(&us, them.public(), packet_type, original.id.unwrap(), None, text.as_bytes().to_vec())
+            .into_packet(us.public(), packet_type);
+
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &original);
+        DatabaseInterface::insert_message(db.clone(), them.public(), them.public(), &forge(PacketType::Edit, "nothing to see"));
+        DatabaseInterface::insert_message(db.clone(), them.public(), them.public(), &forge(PacketType::Delete, ""));
+
+        let search = |text: &str| DatabaseInterface::search_messages(db.clone(), SearchQuery { text: String::from(text), ..SearchQuery::default() });
+        assert_eq!(search("secret").await.unwrap().len(), 1);
+        assert!(search("nothing").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleting_a_message_clears_its_reactions() {
+        let Fixture { _database, db, us, them } = fixture();
+        let (kept, deleted) = (signed(&them, us.public(), "kept"), signed(&them, us.public(), "deleted"));
+        for packet in [&kept, &deleted] {
+            DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), packet);
+            let reaction = Reaction { id: packet.id.unwrap(), emoji: String::from("👍"), added: true };
+            DatabaseInterface::set_reaction(db.clone(), them.public(), us.public(), &reaction);
+        }
+
+        let delete = SignedMessage::new(&them, us.public(), PacketType::Delete, deleted.id.unwrap(), None, Vec::new())
+            .into_packet(them.public(), PacketType::Delete);
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &delete);
+
+        let reactions = DatabaseInterface::select_reactions(db.clone(), them.public()).await.unwrap();
+        assert_eq!(reactions.iter().map(|(_, r)| r.id).collect::<Vec<_>>(), [kept.id.unwrap()]);
+
+        // A delete from anyone but the author leaves the reactions alone.
+        let forged = SignedMessage::new(&us, them.public(), PacketType::Delete, kept.id.unwrap(), None, Vec::new())
+            .into_packet(us.public(), PacketType::Delete);
+        DatabaseInterface::insert_message(db.clone(), them.public(), them.public(), &forged);
+        DatabaseInterface::make_tables(db.clone()).unwrap();
+        assert_eq!(DatabaseInterface::select_reactions(db.clone(), them.public()).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn export_verifies_across_rotations_on_both_sides() {
+        let export = exported_conversation().await;
+        assert_eq!(DatabaseInterface::verify_export(&export).unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn export_rejects_altered_lines() {
+        let export = exported_conversation().await;
+        let mut lines: Vec<String> = export.lines().map(String::from).collect();
+
+        // Change the text of the last message without re-signing it.
+        let last = lines.len() - 1;
+        let mut fields: Vec<String> = lines[last].split('\t').map(String::from).collect();
+        fields[6] = hex::encode("me three");
+        lines[last] = fields.join("\t");
+        assert!(matches!(DatabaseInterface::verify_export(&lines.join("\n")), Err(Error::InvalidExport(line)) if line == last + 1));
+
+        // Without our rotation the messages to our new key belong to a third person.
+        let rotation = lines.iter().position(|line| line.starts_with(&format!("{}\t", PacketType::Rotation.to_u8()))).unwrap();
+        lines.remove(rotation);
+        lines.truncate(last - 1);
+        assert!(matches!(DatabaseInterface::verify_export(&lines.join("\n")), Err(Error::InvalidExport(_))));
+
+        assert!(matches!(DatabaseInterface::verify_export("not an export"), Err(Error::InvalidExport(1))));
+    }
+
+    #[tokio::test]
+    async fn rotations_wait_for_each_contact_until_delivered() {
+        let Fixture { _database, db, .. } = fixture();
+
+        let (saved, connected, moved) = (node_id(), node_id(), node_id());
+        DatabaseInterface::insert_contact(db.clone(), Contact { server_address: saved, username: None });
+
+        DatabaseInterface::queue_rotation(db.clone(), b"first", vec![connected, saved]).await.unwrap();
+        DatabaseInterface::queue_rotation(db.clone(), b"second", vec![]).await.unwrap();
+
+        // Saved contacts get every statement once, in order. Connected strangers only the ones made while connected.
+        let pending = DatabaseInterface::select_pending_rotations(db.clone(), saved).await.unwrap();
+        assert_eq!(pending.iter().map(|(_, s)| s.as_slice()).collect::<Vec<_>>(), [b"first".as_slice(), b"second"]);
+        assert_eq!(DatabaseInterface::select_pending_rotations(db.clone(), connected).await.unwrap().len(), 1);
+
+        DatabaseInterface::delete_pending_rotation(db.clone(), pending[0].0);
+        DatabaseInterface::record_rotation(db.clone(), saved, moved, b"statement");
+
+        // Whatever is left follows the contact onto its new identity.
+        let pending = DatabaseInterface::select_pending_rotations(db.clone(), moved).await.unwrap();
+        assert_eq!(pending.iter().map(|(_, s)| s.as_slice()).collect::<Vec<_>>(), [b"second".as_slice()]);
+        assert!(DatabaseInterface::select_pending_rotations(db.clone(), saved).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_can_require_a_link() {
+        let Fixture { _database, db, us, them } = fixture();
+        let original = signed(&them, us.public(), "meeting notes");
+        let edit = SignedMessage::new(&them, us.public(), PacketType::Edit, original.id.unwrap(), None, b"meeting notes https://example.com".to_vec())
+            .into_packet(them.public(), PacketType::Edit);
+
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &original);
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &signed(&them, us.public(), "meeting moved"));
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &signed(&them, us.public(), "meeting at http://example.org"));
+
+        let search = |has_attachment| DatabaseInterface::search_messages(db.clone(), SearchQuery { text: String::from("meeting"), has_attachment, ..SearchQuery::default() });
+        assert_eq!(search(false).await.unwrap().len(), 3);
+        assert_eq!(search(true).await.unwrap().len(), 1);
+
+        // The filter follows the edited text, not the text first sent.
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &edit);
+        assert_eq!(search(true).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unread_counts_ignore_sender_clocks() {
+        let Fixture { _database, db, us, them } = fixture();
+        let at = |text: &str, time: u64| {
+            let mut packet = signed(&them, us.public(), text);
+            packet.timestamp = time;
+            packet
+        };
+
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &at("now", timestamp()));
+        DatabaseInterface::mark_read(db.clone(), them.public());
+        assert!(DatabaseInterface::select_unread_counts(db.clone()).await.unwrap().is_empty());
+
+        // A sender whose clock runs behind still produces unread messages.
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &at("late", 1));
+        DatabaseInterface::insert_message(db.clone(), them.public(), us.public(), &at("later", 2));
+        assert_eq!(DatabaseInterface::select_unread_counts(db.clone()).await.unwrap().get(&them.public()), Some(&2));
+
+        DatabaseInterface::mark_read(db.clone(), them.public());
+        assert!(DatabaseInterface::select_unread_counts(db.clone()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn invites_are_used_once_within_their_lifetime() {
+        let Fixture { _database, db, .. } = fixture();
+
+        DatabaseInterface::insert_invite(db.clone(), [1; 16]);
+        assert!(DatabaseInterface::consume_invite(db.clone(), [1; 16]).await.unwrap());
+        assert!(!DatabaseInterface::consume_invite(db.clone(), [1; 16]).await.unwrap());
+        assert!(!DatabaseInterface::consume_invite(db.clone(), [2; 16]).await.unwrap());
+
+        let created = timestamp() - INVITE_TTL - 1;
+        db.execute_and_wait(INSERT_INVITE, DatabaseParams::new(vec![
+            DatabaseParam::String(hex::encode([3; 16])),
+            DatabaseParam::Usize(created as usize)
+        ])).await.unwrap();
+        assert!(!DatabaseInterface::consume_invite(db.clone(), [3; 16]).await.unwrap());
+    }
 }
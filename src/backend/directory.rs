@@ -1,4 +1,4 @@
-use std::{fs::create_dir_all, path::{Path, PathBuf}};
+use std::{ffi::OsString, fs::create_dir_all, path::{Path, PathBuf}};
 
 use directories::ProjectDirs;
 
@@ -9,17 +9,17 @@ pub struct Directory {
     root: PathBuf
 }
 
+/// Keeps the data somewhere other than the usual per-user location, so that several instances can run
+/// side by side on one machine, each as its own identity.
+const DATA_DIR_VARIABLE: &str = "PINGPONG_DATA_DIR";
+
 impl Directory {
     pub fn create_or_load() -> Res<Self> {
-        let root = match ProjectDirs::from("com", "hchap1", "pingpong") {
-            Some(dir_builder) => dir_builder.data_dir().to_path_buf(),
-            None => return Err(Error::FailedToFindLocation)
-        };
-
-        let error = Error::FailedToFindLocation;
-        let _ = create_dir_all(&root);
-        if !root.exists() { return Err(error); }
+        Self::create(location(std::env::var_os(DATA_DIR_VARIABLE))?)
+    }
 
+    fn create(root: PathBuf) -> Res<Self> {
+        create_dir_all(&root).map_err(|_| Error::FailedToCreateFolders)?;
         Ok(Self { root })
     }
 
@@ -31,3 +31,38 @@ impl Directory {
         self.root.as_path()
     }
 }
+
+/// The data directory: the override if one is set, otherwise the platform's data directory for the user.
+fn location(data_dir: Option<OsString>) -> Res<PathBuf> {
+    match data_dir.filter(|dir| !dir.is_empty()) {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => ProjectDirs::from("com", "hchap1", "pingpong")
+            .map(|dirs| dirs.data_dir().to_path_buf())
+            .ok_or(Error::FailedToFindLocation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_directory_can_be_overridden() {
+        let temporary = tempfile::tempdir().unwrap();
+        let root = temporary.path().join("pingpong");
+        assert_eq!(location(Some(root.clone().into_os_string())).unwrap(), root);
+
+        let nested = root.join("second").join("instance");
+        assert_eq!(Directory::create(nested.clone()).unwrap().get(), nested);
+        assert!(nested.is_dir());
+
+        // An empty override is treated as unset.
+        assert_ne!(location(Some(OsString::new())).ok(), Some(PathBuf::new()));
+    }
+
+    #[test]
+    fn unusable_directories_are_reported() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(matches!(Directory::create(file.path().join("data")), Err(Error::FailedToCreateFolders)));
+    }
+}
@@ -0,0 +1,18 @@
+use bip39::Mnemonic;
+use iroh::SecretKey;
+
+use crate::error::{Error, Res};
+
+/// Encode a secret key as a 24 word BIP39 recovery phrase.
+pub fn to_mnemonic(secret_key: &SecretKey) -> String {
+    Mnemonic::from_entropy(&secret_key.to_bytes())
+        .expect("32 bytes is always valid BIP39 entropy")
+        .to_string()
+}
+
+/// Decode a recovery phrase back into the secret key it was exported from.
+pub fn from_mnemonic(phrase: &str) -> Res<SecretKey> {
+    let mnemonic = Mnemonic::parse(phrase.trim()).map_err(|_| Error::InvalidMnemonic)?;
+    let bytes: [u8; 32] = mnemonic.to_entropy().try_into().map_err(|_| Error::InvalidMnemonic)?;
+    Ok(SecretKey::from_bytes(&bytes))
+}
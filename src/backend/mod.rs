@@ -3,3 +3,4 @@ pub mod database;
 pub mod directory;
 pub mod database_interface;
 pub mod sql;
+pub mod identity;
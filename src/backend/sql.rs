@@ -1,25 +1,118 @@
 
-// NODE ID //
+// IDENTITY //
 
-pub const CREATE_NODEID_TABLE: &str = "
-    CREATE TABLE IF NOT EXISTS NodeID (
+pub const CREATE_IDENTITY_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Identity (
         id INTEGER PRIMARY KEY AUTOINCREMENT,
-        secret TEXT NOT NULL,
-        public TEXT NOT NULL
+        secret TEXT NOT NULL
     );
 ";
 
-pub const INSERT_NODEID: &str = "
-    INSERT INTO NodeID
-    VALUES(null, ?, ?)
+pub const INSERT_IDENTITY: &str = "
+    INSERT INTO Identity
+    VALUES(null, ?)
+";
+
+pub const SELECT_IDENTITY: &str = "
+    SELECT secret FROM Identity;
+";
+
+pub const DELETE_IDENTITY: &str = "
+    DELETE FROM Identity;
+";
+
+// MIGRATIONS //
+
+// Counts the migrations a database has been through.
+pub const SELECT_SCHEMA_VERSION: &str = "
+    PRAGMA user_version;
+";
+
+pub const SELECT_NODEID_TABLE: &str = "
+    SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'NodeID';
+";
+
+// Older databases stored an unused second key alongside the identity in NodeID.
+pub const MIGRATE_NODEID: &str = "
+    INSERT INTO Identity (secret)
+    SELECT secret FROM NodeID
+    WHERE NOT EXISTS (SELECT 1 FROM Identity)
+    LIMIT 1;
 ";
 
 pub const SELECT_NODEID: &str = "
-    SELECT secret, public FROM NodeID;
+    SELECT secret FROM NodeID LIMIT 1;
+";
+
+pub const DROP_NODEID: &str = "
+    DROP TABLE NodeID;
+";
+
+// Triggers written before they checked who amended a message. Tables are made on every start, which creates
+// them again as they are now.
+pub const DROP_UNCHECKED_TRIGGERS: &str = "
+    DROP TRIGGER IF EXISTS MessageSearchEdit;
+    DROP TRIGGER IF EXISTS MessageSearchDelete;
+    DROP TRIGGER IF EXISTS ReactionsDelete;
+";
+
+// KEY ROTATIONS //
+
+pub const CREATE_ROTATIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS KeyRotations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        old_node_id TEXT NOT NULL,
+        new_node_id TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        statement TEXT
+    );
 ";
 
-pub const DELETE_NODEID: &str = "
-    DELETE FROM NodeID;
+pub const INSERT_ROTATION: &str = "
+    INSERT INTO KeyRotations (old_node_id, new_node_id, timestamp, statement)
+    VALUES(?, ?, ?, ?)
+";
+
+pub const SELECT_ROTATIONS: &str = "
+    SELECT old_node_id, new_node_id, timestamp, statement FROM KeyRotations
+    WHERE statement IS NOT NULL
+    ORDER BY id;
+";
+
+pub const SELECT_KEY_CHANGES: &str = "
+    SELECT old_node_id, new_node_id FROM KeyRotations ORDER BY id;
+";
+
+// Statements announcing our own rotations, kept per contact until they have been delivered.
+pub const CREATE_PENDING_ROTATIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS PendingRotations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        conversation TEXT NOT NULL,
+        statement TEXT NOT NULL
+    );
+";
+
+pub const QUEUE_ROTATION_FOR_CONTACTS: &str = "
+    INSERT INTO PendingRotations (conversation, statement)
+    SELECT DISTINCT node_id, ? FROM Contacts;
+";
+
+pub const QUEUE_ROTATION: &str = "
+    INSERT INTO PendingRotations (conversation, statement)
+    SELECT ?1, ?2
+    WHERE NOT EXISTS (SELECT 1 FROM PendingRotations WHERE conversation = ?1 AND statement = ?2);
+";
+
+pub const SELECT_PENDING_ROTATIONS: &str = "
+    SELECT id, statement FROM PendingRotations WHERE conversation = ? ORDER BY id;
+";
+
+pub const DELETE_PENDING_ROTATION: &str = "
+    DELETE FROM PendingRotations WHERE id = ?;
+";
+
+pub const UPDATE_PENDING_ROTATIONS_CONVERSATION: &str = "
+    UPDATE PendingRotations SET conversation = ? WHERE conversation = ?;
 ";
 
 // CONTACTS //
@@ -41,10 +134,323 @@ pub const DELETE_CONTACT: &str = "
     DELETE FROM Contacts WHERE node_id = ?;
 ";
 
+pub const UPDATE_CONTACT_NODE_ID: &str = "
+    UPDATE Contacts SET node_id = ? WHERE node_id = ?;
+";
+
 pub const SELECT_ALL_CONTACTS: &str = "
     SELECT node_id, username FROM Contacts;
 ";
 
+// MESSAGES //
+
+pub const CREATE_MESSAGES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        conversation TEXT NOT NULL,
+        author TEXT NOT NULL,
+        recipient TEXT NOT NULL,
+        packet_type INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        signature TEXT,
+        message_id TEXT,
+        reply_to TEXT
+    );
+";
+
+pub const INSERT_MESSAGE: &str = "
+    INSERT INTO Messages
+    VALUES(null, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+";
+
+pub const SELECT_CONVERSATION: &str = "
+    SELECT author, recipient, packet_type, timestamp, content, signature, message_id, reply_to FROM Messages
+    WHERE conversation = ?
+    ORDER BY id;
+";
+
+// Text and rotation rows before a cursor, newest first. Edits and deletes are fetched separately for the
+// messages on the page, as they can be far newer than what they amend.
+pub const SELECT_CONVERSATION_PAGE: &str = "
+    SELECT id, author, recipient, packet_type, timestamp, content, signature, message_id, reply_to FROM Messages
+    WHERE conversation = ? AND id < ? AND packet_type NOT IN (6, 7)
+    ORDER BY id DESC
+    LIMIT ?;
+";
+
+pub const SELECT_AMENDMENTS: &str = "
+    SELECT author, recipient, packet_type, timestamp, content, signature, message_id, reply_to FROM Messages
+    WHERE conversation = ? AND packet_type IN (6, 7) AND message_id IN (
+        SELECT message_id FROM Messages WHERE conversation = ? AND packet_type = 1 AND id >= ? AND id < ?
+    )
+    ORDER BY id;
+";
+
+pub const SELECT_MESSAGE_AUTHOR: &str = "
+    SELECT author FROM Messages WHERE message_id = ? AND packet_type = 1;
+";
+
+pub const SELECT_MESSAGE_DELETED: &str = "
+    SELECT 1 FROM Messages WHERE message_id = ? AND packet_type = 7 LIMIT 1;
+";
+
+pub const UPDATE_MESSAGES_CONVERSATION: &str = "
+    UPDATE Messages SET conversation = ? WHERE conversation = ?;
+";
+
+// Only messages and their amendments expire. Rotation notices record who the contact is and stay.
+pub const SELECT_OLDEST_MESSAGE: &str = "
+    SELECT MIN(timestamp) FROM Messages WHERE conversation = ? AND packet_type IN (1, 6, 7);
+";
+
+pub const DELETE_EXPIRED_MESSAGES: &str = "
+    DELETE FROM Messages WHERE conversation = ? AND timestamp <= ? AND packet_type IN (1, 6, 7);
+";
+
+// SEARCH //
+
+// Full-text index over the current text of every message, keyed by the Messages row id. The triggers keep it
+// in step as the database thread inserts and expires messages, so no caller has to maintain it.
+pub const CREATE_SEARCH_TABLE: &str = "
+    CREATE VIRTUAL TABLE IF NOT EXISTS MessageSearch USING fts5(content, tokenize = 'unicode61 remove_diacritics 2');
+";
+
+// Without this, FTS5 only marks deleted rows and their terms stay readable in the index until it is merged.
+pub const SEARCH_SECURE_DELETE: &str = "
+    INSERT INTO MessageSearch(MessageSearch, rank) VALUES('secure-delete', 1);
+";
+
+pub const CREATE_SEARCH_INSERT_TRIGGER: &str = "
+    CREATE TRIGGER IF NOT EXISTS MessageSearchInsert AFTER INSERT ON Messages WHEN new.packet_type = 1 BEGIN
+        INSERT INTO MessageSearch(rowid, content) VALUES (new.id, new.content);
+    END;
+";
+
+pub const CREATE_SEARCH_EDIT_TRIGGER: &str = "
+    CREATE TRIGGER IF NOT EXISTS MessageSearchEdit AFTER INSERT ON Messages WHEN new.packet_type = 6 BEGIN
+        UPDATE MessageSearch SET content = new.content WHERE rowid IN (
+            SELECT id FROM Messages
+            WHERE conversation = new.conversation AND message_id = new.message_id AND author = new.author AND packet_type = 1
+        );
+    END;
+";
+
+// Deleting a message erases its text and every edit of it, leaving only the signed delete. Erased rows hold an
+// empty blob, which no signature covers, so they are recognised when history loads.
+pub const CREATE_ERASE_TRIGGER: &str = "
+    CREATE TRIGGER IF NOT EXISTS MessageErase AFTER INSERT ON Messages WHEN new.packet_type = 7 BEGIN
+        UPDATE Messages SET content = X'', signature = NULL
+        WHERE conversation = new.conversation AND message_id = new.message_id AND author = new.author AND packet_type IN (1, 6);
+    END;
+";
+
+// Erase messages deleted before deleting erased anything.
+pub const ERASE_DELETED_MESSAGES: &str = "
+    UPDATE Messages SET content = X'', signature = NULL
+    WHERE packet_type IN (1, 6) AND EXISTS (
+        SELECT 1 FROM Messages d
+        WHERE d.conversation = Messages.conversation AND d.message_id = Messages.message_id
+            AND d.author = Messages.author AND d.packet_type = 7
+    );
+";
+
+pub const CREATE_SEARCH_DELETE_TRIGGER: &str = "
+    CREATE TRIGGER IF NOT EXISTS MessageSearchDelete AFTER INSERT ON Messages WHEN new.packet_type = 7 BEGIN
+        DELETE FROM MessageSearch WHERE rowid IN (
+            SELECT id FROM Messages
+            WHERE conversation = new.conversation AND message_id = new.message_id AND author = new.author AND packet_type = 1
+        );
+    END;
+";
+
+pub const CREATE_SEARCH_EXPIRE_TRIGGER: &str = "
+    CREATE TRIGGER IF NOT EXISTS MessageSearchExpire AFTER DELETE ON Messages WHEN old.packet_type = 1 BEGIN
+        DELETE FROM MessageSearch WHERE rowid = old.id;
+    END;
+";
+
+// Index messages stored before the index existed, at their latest edit and leaving out deleted ones.
+pub const BACKFILL_SEARCH: &str = "
+    INSERT INTO MessageSearch(rowid, content)
+    SELECT m.id, COALESCE((
+        SELECT e.content FROM Messages e
+        WHERE e.conversation = m.conversation AND e.message_id = m.message_id AND e.packet_type = 6
+        ORDER BY e.id DESC LIMIT 1
+    ), m.content) FROM Messages m
+    WHERE m.packet_type = 1
+        AND m.id NOT IN (SELECT rowid FROM MessageSearch)
+        AND NOT EXISTS (
+            SELECT 1 FROM Messages d WHERE d.conversation = m.conversation AND d.message_id = m.message_id AND d.packet_type = 7
+        );
+";
+
+// Matches are marked with the control characters 0x02 and 0x03 so they can be highlighted.
+pub const SEARCH_MESSAGES: &str = "
+    SELECT m.conversation, m.author, m.timestamp, m.message_id, snippet(MessageSearch, 0, char(2), char(3), '...', 12)
+    FROM MessageSearch JOIN Messages m ON m.id = MessageSearch.rowid
+    WHERE MessageSearch MATCH ?
+        AND (? = '' OR m.conversation = ?)
+        AND m.timestamp >= ? AND m.timestamp <= ?
+        AND (? = 0 OR MessageSearch.content LIKE '%http://%' OR MessageSearch.content LIKE '%https://%')
+    ORDER BY m.conversation, m.id DESC
+    LIMIT 500;
+";
+
+// REACTIONS //
+
+pub const CREATE_REACTIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Reactions (
+        conversation TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        author TEXT NOT NULL,
+        emoji TEXT NOT NULL,
+        PRIMARY KEY (message_id, author, emoji)
+    );
+";
+
+pub const INSERT_REACTION: &str = "
+    INSERT OR IGNORE INTO Reactions VALUES(?, ?, ?, ?);
+";
+
+pub const DELETE_REACTION: &str = "
+    DELETE FROM Reactions WHERE message_id = ? AND author = ? AND emoji = ?;
+";
+
+pub const SELECT_REACTIONS: &str = "
+    SELECT message_id, author, emoji FROM Reactions WHERE conversation = ?;
+";
+
+pub const UPDATE_REACTIONS_CONVERSATION: &str = "
+    UPDATE Reactions SET conversation = ? WHERE conversation = ?;
+";
+
+// Reactions go with the message they were on.
+pub const CREATE_REACTIONS_DELETE_TRIGGER: &str = "
+    CREATE TRIGGER IF NOT EXISTS ReactionsDelete AFTER INSERT ON Messages WHEN new.packet_type = 7 BEGIN
+        DELETE FROM Reactions WHERE conversation = new.conversation AND message_id = new.message_id AND EXISTS (
+            SELECT 1 FROM Messages
+            WHERE conversation = new.conversation AND message_id = new.message_id AND author = new.author AND packet_type = 1
+        );
+    END;
+";
+
+pub const DELETE_REACTIONS_ON_DELETED: &str = "
+    DELETE FROM Reactions WHERE EXISTS (
+        SELECT 1 FROM Messages m JOIN Messages d ON d.conversation = m.conversation AND d.message_id = m.message_id
+        WHERE m.conversation = Reactions.conversation AND m.message_id = Reactions.message_id
+            AND m.packet_type = 1 AND d.packet_type = 7 AND d.author = m.author
+    );
+";
+
+pub const DELETE_ORPHANED_REACTIONS: &str = "
+    DELETE FROM Reactions WHERE conversation = ? AND message_id NOT IN (
+        SELECT message_id FROM Messages WHERE message_id IS NOT NULL
+    );
+";
+
+// READ CURSORS //
+
+pub const CREATE_READ_CURSORS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS ReadCursors (
+        conversation TEXT PRIMARY KEY,
+        last_read INTEGER NOT NULL
+    );
+";
+
+// The cursor is the row id of the newest stored message, as timestamps come from the sender's clock.
+pub const UPSERT_READ_CURSOR: &str = "
+    INSERT INTO ReadCursors SELECT ?1, COALESCE(MAX(id), 0) FROM Messages WHERE conversation = ?1
+    ON CONFLICT(conversation) DO UPDATE SET last_read = MAX(last_read, excluded.last_read);
+";
+
+pub const UPDATE_READ_CURSOR_CONVERSATION: &str = "
+    UPDATE ReadCursors SET conversation = ? WHERE conversation = ?;
+";
+
+// Incoming text messages stored after the read cursor. Rows we sent are stored with the contact as recipient.
+pub const SELECT_UNREAD_COUNTS: &str = "
+    SELECT m.conversation, COUNT(*) FROM Messages m
+    LEFT JOIN ReadCursors r ON r.conversation = m.conversation
+    WHERE m.packet_type = 1 AND m.recipient != m.conversation AND m.id > COALESCE(r.last_read, 0)
+    GROUP BY m.conversation;
+";
+
+// TIMERS //
+
+pub const CREATE_TIMERS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Timers (
+        conversation TEXT PRIMARY KEY,
+        seconds INTEGER NOT NULL
+    );
+";
+
+pub const INSERT_TIMER: &str = "
+    INSERT OR REPLACE INTO Timers
+    VALUES(?, ?)
+";
+
+pub const DELETE_TIMER: &str = "
+    DELETE FROM Timers WHERE conversation = ?;
+";
+
+pub const SELECT_ALL_TIMERS: &str = "
+    SELECT conversation, seconds FROM Timers;
+";
+
+pub const UPDATE_TIMER_CONVERSATION: &str = "
+    UPDATE Timers SET conversation = ? WHERE conversation = ?;
+";
+
+// MUTES //
+
+pub const CREATE_MUTES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Mutes (
+        conversation TEXT PRIMARY KEY
+    );
+";
+
+pub const INSERT_MUTE: &str = "
+    INSERT OR IGNORE INTO Mutes VALUES(?);
+";
+
+pub const DELETE_MUTE: &str = "
+    DELETE FROM Mutes WHERE conversation = ?;
+";
+
+pub const SELECT_ALL_MUTES: &str = "
+    SELECT conversation FROM Mutes;
+";
+
+pub const UPDATE_MUTE_CONVERSATION: &str = "
+    UPDATE Mutes SET conversation = ? WHERE conversation = ?;
+";
+
+// INVITES //
+
+pub const CREATE_INVITES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Invites (
+        secret TEXT PRIMARY KEY,
+        created INTEGER NOT NULL
+    );
+";
+
+pub const INSERT_INVITE: &str = "
+    INSERT OR IGNORE INTO Invites VALUES(?, ?);
+";
+
+pub const SELECT_INVITE: &str = "
+    SELECT secret FROM Invites WHERE secret = ? AND created >= ?;
+";
+
+pub const DELETE_EXPIRED_INVITES: &str = "
+    DELETE FROM Invites WHERE created < ?;
+";
+
+pub const DELETE_INVITE: &str = "
+    DELETE FROM Invites WHERE secret = ?;
+";
+
 // USERNAME //
 pub const CREATE_USERNAME_TABLE: &str = "
     CREATE TABLE IF NOT EXISTS Username (
@@ -61,3 +467,43 @@ pub const INSERT_USERNAME: &str = "
 pub const SELECT_USERNAME: &str = "
     SELECT username FROM Username;
 ";
+
+// SETTINGS //
+
+pub const CREATE_SETTINGS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+pub const INSERT_SETTING: &str = "
+    INSERT OR REPLACE INTO Settings
+    VALUES(?, ?)
+";
+
+pub const SELECT_SETTING: &str = "
+    SELECT value FROM Settings WHERE key = ?;
+";
+
+pub const DELETE_SETTING: &str = "
+    DELETE FROM Settings WHERE key = ?;
+";
+
+// ENCRYPTION //
+
+pub const VERIFY_KEY: &str = "
+    SELECT count(*) FROM sqlite_master;
+";
+
+pub const ATTACH_EXPORT: &str = "
+    ATTACH DATABASE ? AS export KEY ?;
+";
+
+pub const EXPORT_DATABASE: &str = "
+    SELECT sqlcipher_export('export');
+";
+
+pub const DETACH_EXPORT: &str = "
+    DETACH DATABASE export;
+";
@@ -1,64 +1,205 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
 use async_channel::RecvError;
-use iroh::endpoint::{BindError, ConnectError, ConnectionError, RemoteNodeIdError};
+use iroh::endpoint::{BindError, ConnectError, ConnectionError, ReadError, RemoteNodeIdError};
+use iroh::RelayUrl;
 
 pub type Res<T> = Result<T, Error>;
 
 #[derive(Clone, Debug)]
 pub enum Error {
-    MagicSpawn,
-    Discovery,
-    Connect,
-    Connection(Option<ConnectionError>),
-    Unknown,
+    // Sources that are not Clone are shared, so errors can still travel inside UI messages.
+    Bind(Arc<BindError>),
+    Connect(Arc<ConnectError>),
+    Connection(ConnectionError),
 
     StreamClosed,
     StreamCrashed,
-    StreamReadFailed,
+    StreamReadFailed(Arc<ReadError>),
     TooLong,
+    MalformedPacket,
 
-    RemoteIDFailed,
+    RemoteIDFailed(Arc<RemoteNodeIdError>),
+    InvalidNodeId,
+    HandshakeFailed,
+    InvalidSignature,
 
     MPMCRecvError,
 
     NoSuchClient,
+    NoSuchMessage,
+    NotAuthor,
+    InvalidReaction,
 
     // DATABASE //
     ChannelDead,
+    Open(Arc<rusqlite::Error>),
+    Migrate(Arc<rusqlite::Error>),
+    Prepare { query: &'static str, source: Arc<rusqlite::Error> },
+    Query { query: &'static str, source: Arc<rusqlite::Error> },
+    Execute { query: &'static str, source: Arc<rusqlite::Error> },
+    ExportFailed(Arc<std::io::Error>),
+    ExportUnreadable(Arc<std::io::Error>),
+    // The line of an exported conversation that failed verification, counting the header as line 1.
+    InvalidExport(usize),
+    IncorrectPassphrase,
+    RekeyFailed,
+
+    // IDENTITY //
+    NoIdentity,
+    MalformedIdentity,
+    InvalidMnemonic,
+    InvalidRotation,
+
+    // LOCK //
+    IncorrectPin,
+    PinBackoff(u64),
+
+    // LINKS //
+    FailedToOpenLink,
+
+    // SEARCH //
+    InvalidDate,
+
+    // NOTIFICATIONS //
+    FailedToNotify,
+    InvalidQuietHours,
+
+    // INVITES //
+    InvalidTicket,
+    InvalidInvite,
+
+    // TRANSPORT //
+    InvalidRelayUrl,
+    RelayUnreachable(RelayUrl),
+    // What happens instead, in words fit to show the user.
+    NoRelayReachable(&'static str),
 
     // DIRECTORIES //
     FailedToFindLocation,
     FailedToCreateFolders,
 }
 
+impl Error {
+    /// What went wrong, in words fit to show the user.
+    pub fn message(&self) -> String {
+        match self {
+            Self::Bind(_) => String::from("Could not start networking."),
+            Self::Connect(_) => String::from("Could not connect to the contact. Check the ID and that they are online."),
+            Self::Connection(_) => String::from("The connection failed."),
+
+            Self::StreamClosed => String::from("The contact closed the connection."),
+            Self::StreamCrashed => String::from("The connection to the contact broke."),
+            Self::StreamReadFailed(_) => String::from("Could not read from the connection."),
+            Self::TooLong => String::from("The message is too long to send."),
+            Self::MalformedPacket => String::from("Received a damaged message."),
+
+            Self::RemoteIDFailed(_) => String::from("Could not identify the other side of a connection."),
+            Self::InvalidNodeId => String::from("That is not a valid contact ID."),
+            Self::HandshakeFailed => String::from("Could not establish a connection with the contact."),
+            Self::InvalidSignature => String::from("Received a message with an invalid signature. It was discarded."),
+
+            Self::MPMCRecvError => String::from("An internal channel closed unexpectedly."),
+
+            Self::NoSuchClient => String::from("No contact with that ID is connected. Check the ID and try again."),
+            Self::NoSuchMessage => String::from("That message no longer exists."),
+            Self::NotAuthor => String::from("Only the author of a message can change it."),
+            Self::InvalidReaction => String::from("Reactions must be a single emoji."),
+
+            Self::ChannelDead => String::from("The database stopped responding."),
+            Self::Open(_) => String::from("The database file could not be opened."),
+            Self::Migrate(_) => String::from("The database could not be upgraded to this version."),
+            Self::Prepare { .. } | Self::Query { .. } | Self::Execute { .. } => String::from("A database operation failed."),
+            Self::ExportFailed(_) => String::from("Could not export the conversation."),
+            Self::ExportUnreadable(_) => String::from("Could not read the exported conversation."),
+            Self::InvalidExport(line) => format!("Line {line} of the exported conversation does not verify."),
+            Self::IncorrectPassphrase => String::from("Incorrect passphrase."),
+            Self::RekeyFailed => String::from("Could not change the database encryption."),
+
+            Self::NoIdentity => String::from("No identity has been set up."),
+            Self::MalformedIdentity => String::from("The stored identity is damaged."),
+            Self::InvalidMnemonic => String::from("That recovery phrase is not valid."),
+            Self::InvalidRotation => String::from("Received an invalid key change. It was ignored."),
+
+            Self::IncorrectPin => String::from("Incorrect PIN."),
+            Self::PinBackoff(seconds) => format!("Too many incorrect PINs. Try again in {seconds} seconds."),
+
+            Self::FailedToOpenLink => String::from("Could not open the link."),
+
+            Self::InvalidDate => String::from("Dates must be written as YYYY-MM-DD."),
+
+            Self::FailedToNotify => String::from("Could not show a desktop notification."),
+            Self::InvalidQuietHours => String::from("Do not disturb hours must be written as START-END, for example 22-7."),
+
+            Self::InvalidTicket => String::from("That is not a valid invite ticket or public key."),
+            Self::InvalidInvite => String::from("Someone presented an invite that is unknown or already used. They were not added."),
+
+            Self::InvalidRelayUrl => String::from("Enter relay URLs, \"default\" or \"disabled\"."),
+            Self::RelayUnreachable(url) => format!("Relay {url} could not be reached and is not used."),
+            Self::NoRelayReachable(fallback) => format!("None of your relays could be reached. {fallback}"),
+
+            Self::FailedToFindLocation => String::from("Could not find a location to store data."),
+            Self::FailedToCreateFolders => String::from("Could not create the data folders.")
+        }
+    }
+}
+
+impl Display for Error {
+    /// The user facing message, except for database failures, which name the query and what went wrong with it
+    /// so reports can be traced.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Prepare { query, source } => write!(f, "Could not prepare query: {} ({source})", compact(query)),
+            Self::Query { query, source } => write!(f, "Query failed: {} ({source})", compact(query)),
+            Self::Execute { query, source } => write!(f, "Statement failed: {} ({source})", compact(query)),
+            other => write!(f, "{}", other.message())
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Bind(e) => Some(e.as_ref()),
+            Self::Connect(e) => Some(e.as_ref()),
+            Self::Connection(e) => Some(e),
+            Self::StreamReadFailed(e) => Some(e.as_ref()),
+            Self::RemoteIDFailed(e) => Some(e.as_ref()),
+            // Failed queries already describe their cause.
+            Self::Open(source) | Self::Migrate(source) => Some(source.as_ref()),
+            Self::ExportFailed(e) | Self::ExportUnreadable(e) => Some(e.as_ref()),
+            _ => None
+        }
+    }
+}
+
+/// Queries are written over several indented lines, which reads badly inside a single line of text.
+fn compact(query: &str) -> String {
+    query.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
 impl From<BindError> for Error {
     fn from(error: BindError) -> Self {
-        match error {
-            BindError::MagicSpawn { source: _, backtrace: _, span_trace: _ } => Error::MagicSpawn,
-            BindError::Discovery { source: _, backtrace: _, span_trace: _ } => Error::Discovery,
-            _ => Error::Unknown
-        }
+        Error::Bind(Arc::new(error))
     }
 }
 
 impl From<ConnectError> for Error {
     fn from(error: ConnectError) -> Self {
-        match error {
-            ConnectError::Connect { source: _, backtrace: _, span_trace: _ } => Error::Connect,
-            ConnectError::Connection { source: _, backtrace: _, span_trace: _ } => Error::Connection(None),
-            _ => Error::Unknown
-        }
+        Error::Connect(Arc::new(error))
     }
 }
 
 impl From<ConnectionError> for Error {
     fn from(error: ConnectionError) -> Self {
-        Error::Connection(Some(error))
+        Error::Connection(error)
     }
 }
 
 impl From<RemoteNodeIdError> for Error {
-    fn from(_error: RemoteNodeIdError) -> Self {
-        Error::RemoteIDFailed
+    fn from(error: RemoteNodeIdError) -> Self {
+        Error::RemoteIDFailed(Arc::new(error))
     }
 }
 
@@ -1,45 +1,343 @@
-use crate::backend::database::{Database, ItemStream};
-use crate::backend::database_interface::DatabaseInterface;
+use crate::backend::database::{Database, DatabaseParam, ItemStream};
+use crate::backend::database_interface::{DatabaseInterface, Setting};
 use crate::backend::directory::Directory;
+use crate::backend::identity;
 use crate::backend::relay::Relay;
 use crate::error::Error;
+use crate::logging;
 use crate::networking::abstraction::{NetworkOutput, NetworkTask};
 use crate::networking::contact::Contact;
+use crate::networking::network::{Nearby, NearbyPeer};
+use crate::networking::packet::{MessageId, PacketType};
+use crate::networking::transport::{Discovery, RelayFallback, RelaySetting, Transport};
 use crate::{error::Res, frontend::message::Message, networking::abstraction::run_network};
 use crate::frontend::message::Global;
 
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
 use async_channel::{unbounded, Receiver, Sender};
-use iced::widget::{button, text, text_input, Column, Row, Scrollable};
-use iced::{Element, Length, Task};
+use iced::widget::{button, container, stack, text, text_input, Column, Container, Row, Scrollable};
+use iced::{event, time, window, Element, Event, Length, Subscription, Task};
+use iroh::{NodeId, SecretKey};
+use rand::rngs::OsRng;
 use tokio::{spawn, task::JoinHandle};
+use tracing::{debug, warn};
+use url::Url;
 
-use super::message::{Chat, PageType};
+use super::conversation::ConversationStore;
+use super::error_log::ErrorLog;
+use super::lock::{hash_pin, AppLock, DEFAULT_IDLE_TIMEOUT};
+use super::notifications::{DesktopBackend, NotificationService, Preview, QuietHours};
+use super::toast::Toasts;
+use super::message::{Add, Chat, Diagnostics, PageType, Search, Settings};
 use super::pages::add::AddPage;
 use super::pages::chat::ChatPage;
+use super::pages::diagnostics::DiagnosticsPage;
+use super::pages::search::SearchPage;
+use super::pages::settings::SettingsPage;
 
+/// Pages render from the application's conversations rather than holding their own copies, so packets are
+/// kept whichever page happens to be open.
 pub trait Page {
-    fn view(&self) -> Element<Message>;
-    fn update(&mut self, message: Message) -> Task<Message>;
+    fn view<'a>(&'a self, conversations: &'a ConversationStore) -> Element<'a, Message>;
+    fn update(&mut self, message: Message, conversations: &ConversationStore) -> Task<Message>;
+}
+
+/// Shown when the network cannot start because there is no usable identity.
+enum IdentityPrompt {
+    FirstLaunch,
+    Damaged,
+    // The identity could not be read at all. Offering to create one here could overwrite a good identity.
+    Unreadable(Error)
+}
+
+impl IdentityPrompt {
+    fn check(database: &Database) -> Option<Self> {
+        match DatabaseInterface::select_identity_blocking(database.derive()) {
+            Ok(_) => None,
+            Err(Error::NoIdentity) => Some(Self::FirstLaunch),
+            Err(Error::MalformedIdentity) => Some(Self::Damaged),
+            Err(e) => Some(Self::Unreadable(e))
+        }
+    }
+
+    /// Creating or restoring replaces whatever identity is stored, so it is only offered when there is none
+    /// or it is known to be damaged.
+    fn allows_replacing(&self) -> bool {
+        !matches!(self, Self::Unreadable(_))
+    }
 }
 
 pub struct Application {
     root: Directory,
-    database: Database,
+    database: Option<Database>,
     networking_task_sender: Sender<NetworkTask>,
     networking_output_receiver: Receiver<NetworkOutput>,
-    _networker: JoinHandle<Res<()>>,
+
+    // Held until the database is unlocked, then handed to the network thread.
+    networking_channels: Option<(Receiver<NetworkTask>, Sender<NetworkOutput>)>,
+    _networker: Option<JoinHandle<Res<()>>>,
     page: Box<dyn Page>,
 
     active_chats: Vec<Contact>,
     possible_chats: Vec<Contact>,
+
+    // Conversation shown on the current page, and unread incoming messages in every other one.
+    visible_chat: Option<NodeId>,
+    diagnostics_visible: bool,
+    unread: HashMap<NodeId, usize>,
+    conversations: ConversationStore,
+
+    notifications: NotificationService,
+    toasts: Toasts,
+    error_log: ErrorLog,
+
+    // Read when the network starts. Changes are saved straight away but only apply on the next start.
+    transport: Transport,
+
+    // Pingpong users currently seen on the local network.
+    nearby: Vec<NearbyPeer>,
+
     username: Option<String>,
-    username_input: String
+    username_input: String,
+    passphrase_input: String,
+    lock: AppLock,
+    identity_prompt: Option<IdentityPrompt>,
+    recovery_input: String,
+
+    // A link from a message, shown in full and only opened once the user confirms it.
+    pending_link: Option<Url>
+}
+
+/// Links come from other people's messages, so only web pages are opened. Anything else could start a local
+/// program or read a file.
+pub fn parse_link(link: &str) -> Res<Url> {
+    match Url::parse(link) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.host().is_some() => Ok(url),
+        _ => Err(Error::FailedToOpenLink)
+    }
 }
 
 impl Application {
-    pub fn view(&self) -> Element<Message> {
+
+    /// Open the database and start the network thread. Called at startup, or on unlock if the database is encrypted.
+    fn open(&mut self, passphrase: Option<String>) -> Res<()> {
+        let database = Database::new(self.root.get(), passphrase)?;
+
+        DatabaseInterface::make_tables(database.derive())?;
+        self.username = DatabaseInterface::select_username(database.derive());
+
+        let idle_timeout = DatabaseInterface::select_setting(database.derive(), Setting::IdleTimeout)
+            .and_then(|seconds| seconds.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        self.lock = AppLock::new(DatabaseInterface::select_setting(database.derive(), Setting::LockPin), idle_timeout);
+
+        self.notifications.configure(
+            DatabaseInterface::select_muted(database.derive()),
+            DatabaseInterface::select_setting(database.derive(), Setting::QuietHours)
+                .and_then(|hours| QuietHours::parse(&hours).ok().flatten()),
+            DatabaseInterface::select_setting(database.derive(), Setting::NotificationPreview)
+                .map(|key| Preview::from_key(&key))
+                .unwrap_or_default()
+        );
+
+        self.transport = Transport {
+            discovery: DatabaseInterface::select_setting(database.derive(), Setting::Discovery)
+                .map(|key| Discovery::from_key(&key))
+                .unwrap_or_default(),
+            relay: DatabaseInterface::select_setting(database.derive(), Setting::Relay)
+                .and_then(|relay| RelaySetting::parse(&relay).ok())
+                .unwrap_or_default(),
+            address_book: DatabaseInterface::select_setting(database.derive(), Setting::AddressBook)
+                .map(|book| Transport::parse_address_book(&book))
+                .unwrap_or_default(),
+            broadcast: DatabaseInterface::select_setting(database.derive(), Setting::BroadcastNearby).is_some(),
+            listen: DatabaseInterface::select_setting(database.derive(), Setting::ListenNearby).is_some(),
+            fallback: DatabaseInterface::select_setting(database.derive(), Setting::RelayFallback)
+                .map(|key| RelayFallback::from_key(&key))
+                .unwrap_or_default()
+        };
+
+        self.identity_prompt = IdentityPrompt::check(&database);
+
+        self.database = Some(database);
+        if self.identity_prompt.is_none() { self.start_network(); }
+        Ok(())
+    }
+
+    /// Open the database at startup, unless it is encrypted and waits for its passphrase. A file that cannot be
+    /// opened at all is reported instead of asking for a passphrase that would never open it.
+    fn open_at_start(&mut self) {
+        let opened = Database::is_encrypted(self.root.get_ref())
+            .and_then(|encrypted| if encrypted { Ok(()) } else { self.open(None) });
+
+        if let Err(e) = opened {
+            warn!(error = %e, "could not open the database");
+            self.identity_prompt = Some(IdentityPrompt::Unreadable(e));
+        }
+    }
+
+    /// Spawn the network thread. Requires an open database holding a valid identity.
+    fn start_network(&mut self) {
+        if let (Some(database), Some((task_receiver, output_sender))) = (self.database.as_ref(), self.networking_channels.take()) {
+            self._networker = Some(spawn(run_network(task_receiver, output_sender, database.derive(), self.username.clone(), self.transport.clone())));
+        }
+    }
+
+    fn store_identity(&self, secret_key: SecretKey) -> Task<Message> {
+        let database = match self.database.as_ref() {
+            Some(database) => database.derive(),
+            None => return Message::None.task()
+        };
+
+        Task::perform(
+            DatabaseInterface::store_node_id(database, secret_key),
+            |result| match result {
+                Ok(_) => Message::Global(Global::IdentityReady),
+                Err(e) => Message::Global(Global::Warn(e))
+            }
+        )
+    }
+
+    fn mark_read(&mut self, node_id: NodeId) {
+        self.unread.remove(&node_id);
+        if let Some(database) = self.database.as_ref() {
+            DatabaseInterface::mark_read(database.derive(), node_id);
+        }
+    }
+
+    fn chat_label(&self, contact: &Contact) -> String {
+        let name = contact.username.clone().unwrap_or_else(|| contact.server_address.to_string());
+        match self.unread.get(&contact.server_address) {
+            Some(count) if *count > 0 => format!("{name} ({count})"),
+            _ => name
+        }
+    }
+
+    fn view_link_prompt(&self) -> Option<Element<'_, Message>> {
+        let url = self.pending_link.as_ref()?;
+        Some(
+            Container::new(
+                Container::new(
+                    Column::new()
+                        .spacing(8)
+                        .push(text("OPEN THIS LINK IN YOUR BROWSER?"))
+                        .push(text(url.as_str()).size(14))
+                        .push(
+                            Row::new()
+                                .spacing(8)
+                                .push(button(text("OPEN")).on_press(Message::Global(Global::ConfirmLink)))
+                                .push(button(text("CANCEL")).on_press(Message::Global(Global::CancelLink)))
+                        )
+                )
+                    .padding(16)
+                    .style(container::rounded_box)
+            )
+                .center(Length::Fill)
+                .into()
+        )
+    }
+
+    /// Every known contact once, whether chatting this session or only saved.
+    fn all_contacts(&self) -> Vec<Contact> {
+        let mut contacts = self.active_chats.clone();
+        for contact in &self.possible_chats {
+            if !contacts.iter().any(|c| c.server_address == contact.server_address) {
+                contacts.push(contact.clone());
+            }
+        }
+        contacts
+    }
+
+    fn contact_name(&self, node_id: NodeId) -> Option<String> {
+        self.active_chats.iter().chain(self.possible_chats.iter())
+            .find(|c| c.server_address == node_id)
+            .and_then(|c| c.username.clone())
+    }
+
+    /// Show the conversation with a contact, scrolled to the given message once its history is loaded.
+    fn open_chat(&mut self, node_id: NodeId, focus: Option<MessageId>) -> Task<Message> {
+        let mut page = ChatPage::new(node_id, self.contact_name(node_id))
+            .focus(focus)
+            .muted(self.notifications.is_muted(node_id));
+        let task = page.opened(&mut self.conversations);
+        self.page = Box::new(page);
+        self.visible_chat = Some(node_id);
+        self.diagnostics_visible = false;
+        self.mark_read(node_id);
+        task
+    }
+
+    pub fn view(&self) -> Element<'_, Message> {
+        if self.database.is_none() {
+            if let Some(IdentityPrompt::Unreadable(e)) = self.identity_prompt.as_ref() {
+                return Column::new()
+                    .push(text(format!("The database could not be opened: {}", e.message())))
+                    .push(
+                        button(text("RETRY"))
+                            .on_press(Message::Global(Global::RetryIdentity))
+                    ).into();
+            }
+
+            return text_input("Enter passphrase to unlock", &self.passphrase_input)
+                .secure(true)
+                .on_input(|v| Message::Global(Global::PassphraseInput(v)))
+                .on_submit(Message::Global(Global::Unlock))
+                .into();
+        }
+
+        // Message content stays hidden while locked, only the number of new messages is shown.
+        if self.lock.is_locked() {
+            return Column::new()
+                .push(text(format!("LOCKED - {} NEW MESSAGES", self.lock.unread())))
+                .push(
+                    text_input("Enter PIN to unlock", &self.passphrase_input)
+                        .secure(true)
+                        .on_input(|v| Message::Global(Global::PassphraseInput(v)))
+                        .on_submit(Message::Global(Global::UnlockApp))
+                ).into();
+        }
+
+        if let Some(prompt) = self.identity_prompt.as_ref() {
+            let (explanation, create) = match prompt {
+                IdentityPrompt::FirstLaunch => (
+                    "No identity found. Create a new one, or restore an existing one from its recovery phrase.",
+                    "CREATE NEW IDENTITY"
+                ),
+                IdentityPrompt::Damaged => (
+                    "The stored identity is damaged. Restore it from its recovery phrase, or replace it with a new identity.",
+                    "REPLACE WITH NEW IDENTITY"
+                ),
+                IdentityPrompt::Unreadable(e) => return Column::new()
+                    .push(text(format!("The stored identity could not be read: {}", e.message())))
+                    .push(
+                        button(text("RETRY"))
+                            .on_press(Message::Global(Global::RetryIdentity))
+                    ).into()
+            };
+
+            return Column::new()
+                .push(text(explanation))
+                .push(
+                    button(text(create))
+                        .on_press(Message::Global(Global::CreateIdentity))
+                )
+                .push(
+                    text_input("Recovery phrase", &self.recovery_input)
+                        .secure(true)
+                        .on_input(|v| Message::Global(Global::RecoveryInput(v)))
+                        .on_submit(Message::Global(Global::RestoreIdentity))
+                )
+                .push(
+                    button(text("RESTORE"))
+                        .on_press(Message::Global(Global::RestoreIdentity))
+                ).into();
+        }
+
         match self.username.as_ref() {
-            Some(_) => Row::new()
+            Some(_) => stack![Row::new()
                 .push(
                     Column::new()
                         .push(
@@ -49,7 +347,7 @@ impl Application {
                                         .map(|c|
                                             Row::new()
                                                 .push(
-                                                    button(text(c.username.as_ref().unwrap_or(&c.server_address.to_string()).to_string()))
+                                                    button(text(self.chat_label(c)))
                                                         .on_press(Message::Global(Global::Load(PageType::Chat(c.server_address))))
                                                 )
                                                 .push(
@@ -70,16 +368,40 @@ impl Application {
                                 Column::from_iter(
                                     self.possible_chats.iter()
                                         .map(|c|
-                                            button(text(c.username.as_ref().unwrap_or(&c.server_address.to_string()).to_string()))
+                                            button(text(self.chat_label(c)))
                                                 .on_press(Message::Global(Global::AddChat(c.clone())))
                                                 .into()
                                         )
                                 ).width(Length::FillPortion(1))
                             ).height(Length::FillPortion(1))
+                        ).push(
+                            Row::new()
+                                .push(
+                                    button(text("ADD CHAT"))
+                                        .on_press(Message::Global(Global::Load(PageType::AddChat)))
+                                )
+                                .push(
+                                    button(text("SEARCH"))
+                                        .on_press(Message::Global(Global::Load(PageType::Search)))
+                                )
+                                .push(
+                                    button(text("SETTINGS"))
+                                        .on_press(Message::Global(Global::Load(PageType::Settings)))
+                                )
+                                .push(
+                                    button(text("DIAGNOSTICS"))
+                                        .on_press(Message::Global(Global::Load(PageType::Diagnostics)))
+                                )
+                                .push(
+                                    button(text(format!("ERRORS ({})", self.error_log.count())))
+                                        .on_press(Message::Global(Global::ToggleErrorLog))
+                                )
                         )
                 ).push(
-                    self.page.view()
-                ).into(),
+                    self.page.view(&self.conversations)
+                ).push_maybe(
+                    self.error_log.is_open().then(|| self.error_log.view())
+                ), self.toasts.view()].push_maybe(self.view_link_prompt()).into(),
             None => text_input("Enter username!", &self.username_input)
                 .on_input(|v| Message::Global(Global::UsernameInput(v)))
                 .on_submit(Message::Global(Global::UpdateUsername))
@@ -87,6 +409,18 @@ impl Application {
         }
     }
 
+    pub fn subscription(&self) -> Subscription<Message> {
+        Subscription::batch(vec![
+            event::listen_with(|event, _, _| match event {
+                Event::Keyboard(_) | Event::Mouse(_) | Event::Touch(_) => Some(Message::Global(Global::Activity)),
+                Event::Window(window::Event::Focused) => Some(Message::Global(Global::Focus(true))),
+                Event::Window(window::Event::Unfocused) => Some(Message::Global(Global::Focus(false))),
+                _ => None
+            }),
+            time::every(Duration::from_secs(1)).map(|_| Message::Global(Global::Tick))
+        ])
+    }
+
     pub fn update(&mut self, message: Message) -> Task<Message> {
         match message {
             Message::Global(global) => match global {
@@ -94,31 +428,71 @@ impl Application {
                     Relay::consume_receiver(
                         self.networking_output_receiver.clone(),
                         |o| match o {
-                            NetworkOutput::AddPacket(packet) => Some(Message::Chat(Chat::AddPacketToCache(packet))),
-                            NetworkOutput::ConversationRecord(packets) => Some(Message::Chat(Chat::SetConversation(packets))),
+                            NetworkOutput::AddPacket(node_id, packet) => Some(Message::Chat(Chat::AddPacketToCache(node_id, packet))),
+                            NetworkOutput::ConversationRecord(node_id, packets, cursor) => Some(Message::Chat(Chat::History(node_id, packets, cursor))),
                             NetworkOutput::NonFatalError(e) => Some(Message::Global(Global::Warn(e))),
                             NetworkOutput::AddChat(c) => Some(Message::Global(Global::AddChat(c))),
-                            NetworkOutput::ContactName(node_id, username) => Some(Message::Global(Global::ContactName(node_id, username)))
+                            NetworkOutput::ContactName(node_id, username) => Some(Message::Global(Global::ContactName(node_id, username))),
+                            NetworkOutput::IdentityRotated(old, new) => Some(Message::Global(Global::IdentityRotated(old, new))),
+                            NetworkOutput::OwnIdentityRotated(new) => Some(Message::Settings(Settings::IdentityRotated(new))),
+                            NetworkOutput::TimerChanged(node_id, seconds) => Some(Message::Chat(Chat::TimerChanged(node_id, seconds))),
+                            NetworkOutput::MessagesExpired(node_id, cutoff) => Some(Message::Chat(Chat::Expire(node_id, cutoff))),
+                            NetworkOutput::Reactions(node_id, reactions) => Some(Message::Chat(Chat::Reactions(node_id, reactions))),
+                            NetworkOutput::Diagnostics(report) => Some(Message::Diagnostics(Diagnostics::Report(report))),
+                            NetworkOutput::Invite(ticket) => Some(Message::Add(Add::Invite(ticket))),
+                            NetworkOutput::InviteRedeemed(contact) => Some(Message::Global(Global::SaveChat(contact))),
+                            NetworkOutput::ContactConnected(contact) => Some(Message::Global(Global::SaveChat(contact))),
+                            NetworkOutput::Nearby(nearby) => Some(Message::Global(Global::Nearby(nearby)))
                         }
                     )
                 ),
 
                 Global::Warn(e) => {
-                    eprintln!("ERROR: {e:?}");
+                    match std::error::Error::source(&e) {
+                        Some(source) => warn!(error = %e, %source, "reported to user"),
+                        None => warn!(error = %e, "reported to user")
+                    }
+                    self.toasts.push(String::from("ERROR"), e.message(), None);
+                    self.error_log.record(e);
                     Message::None.task()
                 },
 
+                Global::ToggleErrorLog => {
+                    self.error_log.toggle();
+                    Message::None.task()
+                }
+
+                Global::ClearErrorLog => {
+                    self.error_log.clear();
+                    Message::None.task()
+                }
+
                 Global::Load(page_type) => {
+                    debug!(page = ?page_type, "load page");
+                    self.visible_chat = None;
+                    self.diagnostics_visible = false;
                     match page_type {
-                        PageType::Chat(node_id) => {
-                            self.page = Box::new(ChatPage::new(node_id));
-                            Message::Global(Global::NetworkTask(NetworkTask::RequestConversation(node_id))).task()
-                        },
+                        PageType::Chat(node_id) => self.open_chat(node_id, None),
 
                         PageType::AddChat => {
-                            self.page = Box::new(AddPage::default());
+                            self.page = Box::new(AddPage::new(self.nearby.clone(), self.transport.listen));
                             Message::None.task()
                         }
+
+                        PageType::Settings => {
+                            self.page = Box::new(SettingsPage::new(self.notifications.preview(), self.notifications.quiet_hours(), self.transport.clone(), self.lock.has_pin()));
+                            Message::None.task()
+                        }
+
+                        PageType::Search => {
+                            self.page = Box::new(SearchPage::new(self.all_contacts()));
+                            Message::None.task()
+                        }
+                        PageType::Diagnostics => {
+                            self.page = Box::new(DiagnosticsPage::new(self.all_contacts()));
+                            self.diagnostics_visible = true;
+                            Message::Diagnostics(Diagnostics::Refresh).task()
+                        }
                     }
                 },
 
@@ -135,26 +509,72 @@ impl Application {
                 }
 
                 Global::LoadContacts => {
-                    Task::stream(Relay::consume_receiver(
-                        DatabaseInterface::select_all_contacts(self.database.derive()),
+                    let database = match self.database.as_ref() {
+                        Some(database) => database,
+                        None => return Message::None.task()
+                    };
+
+                    let unread = Task::perform(
+                        DatabaseInterface::select_unread_counts(database.derive()),
+                        |result| match result {
+                            Ok(counts) => Message::Global(Global::UnreadCounts(counts)),
+                            Err(e) => Message::Global(Global::Warn(e))
+                        }
+                    );
+
+                    let contacts = Task::stream(Relay::consume_receiver(
+                        DatabaseInterface::select_all_contacts(database.derive()),
                         |emmision| match emmision {
-                            ItemStream::Value(row) => if let (Some(address), Some(username)) = (row.first(), row.get(1)) {
-                                if let Ok(contact) = Contact::new(address.string(), username.string()) {
+                            ItemStream::Value(row) => if let (Some(address), Some(username)) = (row.first().and_then(DatabaseParam::string), row.get(1).and_then(DatabaseParam::string)) {
+                                if let Ok(contact) = Contact::new(address, username) {
                                     Some(Message::Global(Global::DatabaseContactEmmision(contact)))
                                 } else { Some(Message::None) }
                             } else { Some(Message::None) },
-                            ItemStream::Error => None,
+                            ItemStream::Error(e) => Some(Message::Global(Global::Warn(e))),
                             ItemStream::End => None
                         }
-                    ))
+                    ));
+
+                    Task::batch([unread, contacts])
+                }
+
+                Global::UnreadCounts(counts) => {
+                    self.unread = counts;
+                    if let Some(node_id) = self.visible_chat {
+                        self.unread.remove(&node_id);
+                    }
+                    Message::None.task()
                 }
 
                 Global::AddContactToDatabase(contact) => {
+                    if self.possible_chats.iter().any(|c| c.server_address == contact.server_address) {
+                        return Message::None.task();
+                    }
                     self.possible_chats.push(contact.clone());
-                    DatabaseInterface::insert_contact(self.database.derive(), contact);
+                    if let Some(database) = self.database.as_ref() {
+                        DatabaseInterface::insert_contact(database.derive(), contact);
+                    }
                     Message::None.task()
                 }
 
+                Global::SaveChat(contact) => {
+                    if !self.active_chats.iter().any(|c| c.server_address == contact.server_address) {
+                        self.active_chats.push(contact.clone());
+                    }
+                    Message::Global(Global::AddContactToDatabase(contact)).task()
+                }
+
+                Global::Nearby(nearby) => {
+                    match nearby {
+                        Nearby::Found(peer) => match self.nearby.iter_mut().find(|p| p.addr.node_id == peer.addr.node_id) {
+                            Some(known) => *known = peer,
+                            None => self.nearby.push(peer)
+                        },
+                        Nearby::Lost(node_id) => self.nearby.retain(|p| p.addr.node_id != node_id)
+                    }
+                    self.page.update(Message::Add(Add::Nearby(self.nearby.clone())), &self.conversations)
+                }
+
                 Global::DatabaseContactEmmision(contact) => {
                     self.possible_chats.push(contact);
                     Message::None.task()
@@ -163,10 +583,102 @@ impl Application {
                 Global::ContactName(addr, username) => {
                     for chat in &mut self.active_chats {
                         if chat.server_address == addr {
-                            chat.username = Some(username);
+                            chat.username = Some(username.clone());
                             break;
                         }
                     }
+                    self.page.update(Message::Chat(Chat::ContactName(addr, username)), &self.conversations)
+                }
+
+                Global::IdentityRotated(old, new) => {
+                    for chat in self.active_chats.iter_mut().chain(self.possible_chats.iter_mut()) {
+                        if chat.server_address == old {
+                            chat.server_address = new;
+                        }
+                    }
+
+                    // A contact we could not reach when it rotated may already be listed under its new key.
+                    let mut seen = HashSet::new();
+                    self.active_chats.retain(|chat| seen.insert(chat.server_address));
+                    let mut seen = HashSet::new();
+                    self.possible_chats.retain(|chat| seen.insert(chat.server_address));
+
+                    if let Some(count) = self.unread.remove(&old) {
+                        *self.unread.entry(new).or_default() += count;
+                    }
+                    if self.visible_chat == Some(old) {
+                        self.visible_chat = Some(new);
+                    }
+                    self.conversations.migrate(old, new);
+                    self.notifications.migrate(old, new);
+                    self.page.update(Message::Chat(Chat::IdentityRotated(old, new)), &self.conversations)
+                }
+
+                Global::ExportConversation(node_id) => {
+                    let database = match self.database.as_ref() {
+                        Some(database) => database.derive(),
+                        None => return Message::None.task()
+                    };
+
+                    Task::perform(
+                        DatabaseInterface::export_conversation(database, self.root.get().join("exports"), node_id),
+                        |result| match result {
+                            Ok(path) => Message::Global(Global::Exported(path)),
+                            Err(e) => Message::Global(Global::Warn(e))
+                        }
+                    )
+                }
+
+                Global::Exported(path) => {
+                    self.toasts.push(String::from("EXPORT"), format!("Saved to {}", path.display()), None);
+                    Message::None.task()
+                }
+
+                Global::VerifyExport(path) => Task::perform(
+                    DatabaseInterface::verify_export_file(path),
+                    |result| match result {
+                        Ok(count) => Message::Global(Global::ExportVerified(count)),
+                        Err(e) => Message::Global(Global::Warn(e))
+                    }
+                ),
+
+                Global::ExportVerified(count) => {
+                    self.toasts.push(String::from("EXPORT"), format!("All {count} messages verified."), None);
+                    Message::None.task()
+                }
+
+                Global::Search(sequence, query) => {
+                    let database = match self.database.as_ref() {
+                        Some(database) => database.derive(),
+                        None => return Message::None.task()
+                    };
+
+                    Task::perform(
+                        DatabaseInterface::search_messages(database, query),
+                        move |result| match result {
+                            Ok(hits) => Message::Search(Search::Results(sequence, hits)),
+                            Err(e) => Message::Global(Global::Warn(e))
+                        }
+                    )
+                }
+
+                Global::OpenMessage(node_id, message_id) => self.open_chat(node_id, Some(message_id)),
+
+                Global::OpenLink(link) => match parse_link(&link) {
+                    Ok(url) => {
+                        self.pending_link = Some(url);
+                        Message::None.task()
+                    }
+                    Err(e) => Message::Global(Global::Warn(e)).task()
+                },
+
+                Global::ConfirmLink => match self.pending_link.take().map(|url| open::that_detached(url.as_str())) {
+                    Some(Err(_)) => Message::Global(Global::Warn(Error::FailedToOpenLink)).task(),
+                    _ => Message::None.task()
+                },
+
+                Global::CancelLink => {
+                    self.pending_link = None;
                     Message::None.task()
                 }
 
@@ -177,40 +689,329 @@ impl Application {
 
                 Global::UpdateUsername => {
                     self.username = Some(std::mem::take(&mut self.username_input));
-                    DatabaseInterface::insert_username(self.database.derive(), self.username.as_ref().unwrap().clone());
+                    if let Some(database) = self.database.as_ref() {
+                        DatabaseInterface::insert_username(database.derive(), self.username.as_ref().unwrap().clone());
+                    }
                     Message::Global(Global::NetworkTask(NetworkTask::SetUsername(self.username.as_ref().unwrap().clone()))).task()
                 }
+
+                Global::PassphraseInput(new_value) => {
+                    self.passphrase_input = new_value;
+                    Message::None.task()
+                }
+
+                Global::Unlock => {
+                    let passphrase = std::mem::take(&mut self.passphrase_input);
+                    match self.open(Some(passphrase)) {
+                        Ok(_) => Message::Global(Global::LoadContacts).task(),
+                        Err(e) => Message::Global(Global::Warn(e)).task()
+                    }
+                }
+
+                Global::SetPassphrase(passphrase) => {
+                    let database = match self.database.as_ref() {
+                        Some(database) => database.derive(),
+                        None => return Message::None.task()
+                    };
+
+                    Task::perform(
+                        DatabaseInterface::set_passphrase(database, passphrase),
+                        |result| match result {
+                            Ok(_) => Message::None,
+                            Err(e) => Message::Global(Global::Warn(e))
+                        }
+                    )
+                }
+
+                Global::Activity => {
+                    self.lock.touch();
+                    Message::None.task()
+                }
+
+                Global::Tick => {
+                    self.lock.tick();
+                    self.toasts.tick();
+                    // Keep the diagnostics figures live, without asking the network for them on every other page.
+                    match self.diagnostics_visible {
+                        true => self.page.update(Message::Diagnostics(Diagnostics::Refresh), &self.conversations),
+                        false => Message::None.task()
+                    }
+                }
+
+                Global::Focus(focused) => {
+                    self.notifications.set_focused(focused);
+                    Message::None.task()
+                }
+
+                Global::SetMuted(node_id, muted) => {
+                    self.notifications.set_muted(node_id, muted);
+                    if let Some(database) = self.database.as_ref() {
+                        DatabaseInterface::set_muted(database.derive(), node_id, muted);
+                    }
+                    Message::None.task()
+                }
+
+                Global::SetPreview(preview) => {
+                    self.notifications.set_preview(preview);
+                    if let Some(database) = self.database.as_ref() {
+                        DatabaseInterface::insert_setting(database.derive(), Setting::NotificationPreview, preview.key().to_string());
+                    }
+                    Message::None.task()
+                }
+
+                Global::SetQuietHours(input) => {
+                    let quiet_hours = match QuietHours::parse(&input) {
+                        Ok(quiet_hours) => quiet_hours,
+                        Err(e) => return Message::Global(Global::Warn(e)).task()
+                    };
+                    self.notifications.set_quiet_hours(quiet_hours);
+
+                    if let Some(database) = self.database.as_ref() {
+                        match quiet_hours {
+                            Some(hours) => DatabaseInterface::insert_setting(database.derive(), Setting::QuietHours, hours.to_string()),
+                            None => DatabaseInterface::delete_setting(database.derive(), Setting::QuietHours)
+                        }
+                    }
+                    Message::None.task()
+                }
+
+                Global::SetTransport(transport) => {
+                    if let Some(database) = self.database.as_ref() {
+                        DatabaseInterface::insert_setting(database.derive(), Setting::Discovery, transport.discovery.key().to_string());
+                        DatabaseInterface::insert_setting(database.derive(), Setting::Relay, transport.relay.to_string());
+                        DatabaseInterface::insert_setting(database.derive(), Setting::RelayFallback, transport.fallback.key().to_string());
+                        DatabaseInterface::insert_setting(database.derive(), Setting::AddressBook, transport.address_book_key());
+                        match transport.broadcast {
+                            true => DatabaseInterface::insert_setting(database.derive(), Setting::BroadcastNearby, String::from("1")),
+                            false => DatabaseInterface::delete_setting(database.derive(), Setting::BroadcastNearby)
+                        }
+                        match transport.listen {
+                            true => DatabaseInterface::insert_setting(database.derive(), Setting::ListenNearby, String::from("1")),
+                            false => DatabaseInterface::delete_setting(database.derive(), Setting::ListenNearby)
+                        }
+                    }
+                    self.transport = transport;
+                    self.toasts.push(String::from("NETWORK"), String::from("Saved. Restart pingpong to apply the new network settings."), None);
+                    Message::None.task()
+                }
+
+                Global::UnlockApp => {
+                    let pin = std::mem::take(&mut self.passphrase_input);
+                    match self.lock.unlock(&pin) {
+                        Ok(()) => {
+                            // Whatever arrived in the open chat while locked has now been seen.
+                            if let Some(node_id) = self.visible_chat {
+                                self.mark_read(node_id);
+                            }
+                            Message::None.task()
+                        }
+                        Err(e) => Message::Global(Global::Warn(e)).task()
+                    }
+                }
+
+                Global::SetLock(current, pin, minutes) => {
+                    // Otherwise anyone at the unlocked app could turn the lock off.
+                    if let Err(e) = self.lock.verify(&current) {
+                        return Message::Global(Global::Warn(e)).task();
+                    }
+
+                    let pin_hash = pin.as_deref().and_then(hash_pin);
+                    let idle_timeout = minutes.map(|m| Duration::from_secs(m * 60)).unwrap_or(DEFAULT_IDLE_TIMEOUT);
+                    self.lock.configure(pin_hash.clone(), idle_timeout);
+                    let idle_timeout = self.lock.idle_timeout();
+
+                    if let Some(database) = self.database.as_ref() {
+                        match pin_hash {
+                            Some(hash) => DatabaseInterface::insert_setting(database.derive(), Setting::LockPin, hash),
+                            None => DatabaseInterface::delete_setting(database.derive(), Setting::LockPin)
+                        }
+                        DatabaseInterface::insert_setting(database.derive(), Setting::IdleTimeout, idle_timeout.as_secs().to_string());
+                    }
+                    Message::Settings(Settings::LockChanged(self.lock.has_pin())).task()
+                }
+
+                Global::RecoveryInput(new_value) => {
+                    self.recovery_input = new_value;
+                    Message::None.task()
+                }
+
+                Global::CreateIdentity | Global::RestoreIdentity if !self.identity_prompt.as_ref().is_some_and(IdentityPrompt::allows_replacing) => {
+                    Message::None.task()
+                }
+
+                Global::CreateIdentity => self.store_identity(SecretKey::generate(&mut OsRng)),
+
+                Global::RestoreIdentity => {
+                    match identity::from_mnemonic(&std::mem::take(&mut self.recovery_input)) {
+                        Ok(secret_key) => self.store_identity(secret_key),
+                        Err(e) => Message::Global(Global::Warn(e)).task()
+                    }
+                }
+
+                Global::RetryIdentity => {
+                    let Some(database) = self.database.as_ref() else {
+                        self.identity_prompt = None;
+                        self.open_at_start();
+                        return Message::Global(Global::LoadContacts).task()
+                    };
+                    self.identity_prompt = IdentityPrompt::check(database);
+                    if self.identity_prompt.is_none() { self.start_network(); }
+                    Message::None.task()
+                }
+
+                Global::IdentityReady => {
+                    self.identity_prompt = None;
+                    self.start_network();
+                    Message::None.task()
+                }
+
+                Global::RotateIdentity(current) => match self.lock.verify(&current) {
+                    Ok(()) => Message::Global(Global::NetworkTask(NetworkTask::RotateIdentity)).task(),
+                    Err(e) => Message::Global(Global::Warn(e)).task()
+                },
+
+                Global::ExportIdentity(current) => {
+                    if let Err(e) = self.lock.verify(&current) {
+                        return Message::Global(Global::Warn(e)).task();
+                    }
+
+                    let database = match self.database.as_ref() {
+                        Some(database) => database.derive(),
+                        None => return Message::None.task()
+                    };
+
+                    match DatabaseInterface::select_identity_blocking(database) {
+                        Ok(secret_key) => Message::Settings(Settings::RecoveryPhrase(identity::to_mnemonic(&secret_key))).task(),
+                        Err(e) => Message::Global(Global::Warn(e)).task()
+                    }
+                }
             },
 
             Message::None => Message::None.task(),
-            page_specific => self.page.update(page_specific)
+            Message::Chat(Chat::AddPacketToCache(node_id, packet)) => {
+                let visible = self.visible_chat == Some(node_id);
+
+                let mut notified = Message::None.task();
+                if packet.packet_type == PacketType::String && packet.author == node_id {
+                    self.lock.record_incoming();
+                    if visible && !self.lock.is_locked() {
+                        self.mark_read(node_id);
+                    } else {
+                        *self.unread.entry(node_id).or_default() += 1;
+                    }
+
+                    // Messages in the open chat only need announcing while the window is in the background.
+                    if !visible || !self.notifications.is_focused() || self.lock.is_locked() {
+                        let sender = self.contact_name(node_id).unwrap_or_else(|| node_id.fmt_short().to_string());
+                        if let Err(e) = self.notifications.notify(node_id, sender, &packet, self.lock.is_locked()) {
+                            notified = Message::Global(Global::Warn(e)).task();
+                        }
+                    }
+                }
+
+                self.conversations.push(node_id, packet.clone());
+                Task::batch(vec![notified, match visible {
+                    true => self.page.update(Message::Chat(Chat::AddPacketToCache(node_id, packet)), &self.conversations),
+                    false => Message::None.task()
+                }])
+            }
+            Message::Chat(Chat::History(node_id, packets, cursor)) => {
+                self.conversations.history(node_id, packets.clone(), cursor);
+                self.page.update(Message::Chat(Chat::History(node_id, packets, cursor)), &self.conversations)
+            }
+            Message::Chat(Chat::Reactions(node_id, reactions)) => {
+                self.conversations.react(node_id, reactions);
+                Message::None.task()
+            }
+            Message::Chat(Chat::TimerChanged(node_id, seconds)) => {
+                self.conversations.set_timer(node_id, seconds);
+                Message::None.task()
+            }
+            Message::Chat(Chat::Expire(node_id, cutoff)) => {
+                self.conversations.expire(node_id, cutoff);
+                Message::None.task()
+            }
+            page_specific => self.page.update(page_specific, &self.conversations)
         }
     }
 }
 
-impl Default for Application {
-    fn default() -> Self {
-
-        let root = Directory::create_or_load().expect("[FATAL] Cannot proceed without creating directory, which failed.");
+impl Application {
+    /// Start up in the data directory, which main has already created for the logs.
+    pub fn new(root: Directory) -> Self {
         let (task_sender, task_receiver) = unbounded();
         let (output_sender, output_receiver) = unbounded();
-        let database = Database::new(root.get());
-        let db = database.derive();
+        let toasts = Toasts::default();
 
-        let username = DatabaseInterface::select_username(database.derive());
-        DatabaseInterface::make_tables_nonblocking(database.derive());
+        if logging::logs_content() {
+            toasts.push(
+                String::from("LOGGING MESSAGES"),
+                String::from("Message text is being written to unencrypted log files. Unset PINGPONG_LOG_CONTENT to stop."),
+                None
+            );
+        }
 
-        Self {
+        let mut application = Self {
             root: root.clone(),
-            database,
+            database: None,
             networking_task_sender: task_sender,
             networking_output_receiver: output_receiver,
-            _networker: spawn(run_network(task_receiver, output_sender, db, username.clone())),
-            page: Box::new(AddPage::default()),
+            networking_channels: Some((task_receiver, output_sender)),
+            _networker: None,
+            page: Box::new(AddPage::new(Vec::new(), false)),
             active_chats: Vec::new(),
             possible_chats: Vec::new(),
-            username,
-            username_input: String::default()
+            visible_chat: None,
+            diagnostics_visible: false,
+            unread: HashMap::new(),
+            conversations: ConversationStore::default(),
+            notifications: NotificationService::new(Box::new(DesktopBackend), Box::new(toasts.clone())),
+            toasts,
+            error_log: ErrorLog::default(),
+            transport: Transport::default(),
+            nearby: Vec::new(),
+            username: None,
+            username_input: String::default(),
+            passphrase_input: String::default(),
+            lock: AppLock::new(None, DEFAULT_IDLE_TIMEOUT),
+            identity_prompt: None,
+            pending_link: None,
+            recovery_input: String::default()
+        };
+
+        application.open_at_start();
+        application
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::database::DatabaseParams;
+    use crate::backend::sql::INSERT_IDENTITY;
+
+    #[test]
+    fn only_web_links_are_opened() {
+        assert_eq!(parse_link("https://example.com/a?b=c").unwrap().as_str(), "https://example.com/a?b=c");
+        assert!(parse_link("http://example.com").is_ok());
+
+        for link in ["file:///etc/passwd", "javascript:alert(1)", "mailto:a@example.com", "ssh://example.com", "smb://host/share", "example.com", "https://", ""] {
+            assert!(matches!(parse_link(link), Err(Error::FailedToOpenLink)), "{link}");
         }
     }
+
+    #[test]
+    fn identity_prompt_only_offers_creation_when_safe() {
+        let database = Database::temporary();
+
+        // Without its table the identity cannot be read, which must not look like a first launch.
+        assert!(matches!(IdentityPrompt::check(&database), Some(IdentityPrompt::Unreadable(_))));
+        assert!(!IdentityPrompt::Unreadable(Error::ChannelDead).allows_replacing());
+
+        DatabaseInterface::make_tables(database.derive()).unwrap();
+        assert!(matches!(IdentityPrompt::check(&database), Some(IdentityPrompt::FirstLaunch)));
+
+        database.derive().execute(INSERT_IDENTITY, DatabaseParams::single(DatabaseParam::String(String::from("not hex")))).unwrap();
+        assert!(matches!(IdentityPrompt::check(&database), Some(IdentityPrompt::Damaged)));
+    }
 }
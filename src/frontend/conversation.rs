@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, HashMap};
+
+use iced::widget::markdown;
+use iroh::NodeId;
+
+use crate::networking::packet::{MessageId, Packet, PacketType, Reaction, Revision, RotationStatement};
+
+/// Everything loaded for the conversation with one contact.
+pub struct Conversation {
+    pub remote_id: NodeId,
+    pub packets: Vec<Packet>,
+
+    // Where older history continues in the database.
+    pub history_cursor: Option<usize>,
+
+    // Set once the newest page of history has arrived.
+    pub loaded: bool,
+
+    // Live packets that arrived before that page. The page may or may not include them, depending on whether
+    // the database wrote them before or after reading it, so they are merged in once it arrives.
+    pending: Vec<Packet>,
+
+    pub timer: Option<u64>,
+
+    // Parsed markup of every text message, kept so it is not parsed again on every frame.
+    pub rendered: HashMap<MessageId, Vec<markdown::Item>>,
+
+    // Who reacted to each message with each emoji.
+    pub reactions: HashMap<MessageId, BTreeMap<String, Vec<NodeId>>>,
+
+    // Keys the contact has rotated away from, so their older messages still read as theirs.
+    pub aliases: Vec<NodeId>
+}
+
+impl Conversation {
+    fn new(remote_id: NodeId) -> Self {
+        Self {
+            remote_id,
+            packets: Vec::default(),
+            history_cursor: None,
+            loaded: false,
+            pending: Vec::default(),
+            timer: None,
+            rendered: HashMap::default(),
+            reactions: HashMap::default(),
+            aliases: Vec::default()
+        }
+    }
+
+    pub fn is_remote(&self, author: NodeId) -> bool {
+        author == self.remote_id || self.aliases.contains(&author)
+    }
+
+    pub fn find(&self, id: MessageId) -> Option<&Packet> {
+        self.packets.iter().find(|p| p.id == Some(id) && p.packet_type == PacketType::String)
+    }
+
+    pub fn position(&self, id: MessageId) -> Option<usize> {
+        self.packets.iter().position(|p| p.id == Some(id))
+    }
+
+    /// Text of a message as it should be shown.
+    pub fn body(p: &Packet) -> String {
+        if p.revision == Revision::Deleted { String::from("MESSAGE DELETED") }
+        else if p.packet_type == PacketType::String {
+            if let Ok(content) = p.content.clone() {
+                match String::from_utf8(content) {
+                    Ok(message) => message,
+                    Err(_) => String::from("INVALID UTF-8")
+                }
+            } else { String::from("EMPTY") }
+        } else { String::from("NOT A STRING") }
+    }
+
+    fn push(&mut self, packet: Packet) {
+        if matches!(packet.packet_type, PacketType::Edit | PacketType::Delete) {
+            // Both keys belong to the same person when both or neither are the contact's.
+            let remote = self.is_remote(packet.author);
+            let remote_keys: Vec<NodeId> = self.aliases.iter().copied().chain([self.remote_id]).collect();
+            if let Some(id) = packet.id && Packet::amend(&mut self.packets, &packet, |original| remote_keys.contains(&original) == remote).is_ok() {
+                if packet.packet_type == PacketType::Delete { self.reactions.remove(&id); }
+                self.render(id)
+            }
+            return;
+        }
+
+        let id = packet.id.filter(|_| packet.packet_type == PacketType::String);
+
+        if packet.packet_type == PacketType::Rotation
+            && let Ok(Ok(statement)) = packet.content.as_ref().map(|c| RotationStatement::from_bytes(c))
+        {
+            self.aliases.push(statement.old);
+        }
+        self.packets.push(packet);
+        if let Some(id) = id { self.render(id) }
+    }
+
+    /// Whether a live packet is already part of the loaded history. Amendments always apply again, as
+    /// doing so changes nothing.
+    fn contains(&self, packet: &Packet) -> bool {
+        match packet.packet_type {
+            PacketType::String => packet.id.is_some_and(|id| self.find(id).is_some()),
+            PacketType::Edit | PacketType::Delete => false,
+            _ => self.packets.iter().any(|p| p.packet_type == packet.packet_type
+                && p.author == packet.author
+                && p.timestamp == packet.timestamp
+                && p.content.as_ref().ok() == packet.content.as_ref().ok())
+        }
+    }
+
+    /// Insert an older page of history in front of what is loaded.
+    fn prepend(&mut self, packets: Vec<Packet>) {
+        let newer = std::mem::take(&mut self.packets);
+        for packet in packets { self.push(packet) }
+        self.packets.extend(newer);
+    }
+
+    /// Parse a text message's markup. Headings are flattened to plain paragraphs, as chat only supports
+    /// emphasis, code, links and lists.
+    fn render(&mut self, id: MessageId) {
+        fn flatten(item: markdown::Item) -> markdown::Item {
+            match item {
+                markdown::Item::Heading(_, text) => markdown::Item::Paragraph(text),
+                markdown::Item::List { start, items } => markdown::Item::List {
+                    start,
+                    items: items.into_iter().map(|item| item.into_iter().map(flatten).collect()).collect()
+                },
+                item => item
+            }
+        }
+
+        let items = self.find(id)
+            .filter(|p| p.revision != Revision::Deleted)
+            .map(|p| markdown::parse(&Self::body(p)).map(flatten).collect());
+
+        match items {
+            Some(items) => { self.rendered.insert(id, items); }
+            None => { self.rendered.remove(&id); }
+        }
+    }
+
+    fn react(&mut self, author: NodeId, reaction: Reaction) {
+        let emojis = self.reactions.entry(reaction.id).or_default();
+        let authors = emojis.entry(reaction.emoji.clone()).or_default();
+
+        if !reaction.added {
+            authors.retain(|a| *a != author);
+        } else if !authors.contains(&author) {
+            authors.push(author);
+        }
+
+        if authors.is_empty() { emojis.remove(&reaction.emoji); }
+        if emojis.is_empty() { self.reactions.remove(&reaction.id); }
+    }
+
+    fn expire(&mut self, cutoff: u64) {
+        self.packets.retain(|packet| packet.timestamp > cutoff || !packet.packet_type.is_signed());
+        let packets = &self.packets;
+        self.reactions.retain(|id, _| packets.iter().any(|p| p.id == Some(*id)));
+        self.rendered.retain(|id, _| packets.iter().any(|p| p.id == Some(*id)));
+    }
+}
+
+/// Every conversation loaded this session, keyed by the contact's node id. Owned by the application, so
+/// packets reach the right conversation whichever page is open, and nothing is lost when switching chats.
+#[derive(Default)]
+pub struct ConversationStore {
+    conversations: HashMap<NodeId, Conversation>
+}
+
+impl ConversationStore {
+    pub fn get(&self, node_id: &NodeId) -> Option<&Conversation> {
+        self.conversations.get(node_id)
+    }
+
+    /// Start tracking a conversation. Returns false if it was already tracked, in which case its history
+    /// has been requested before and must not be requested again.
+    pub fn open(&mut self, node_id: NodeId) -> bool {
+        if self.conversations.contains_key(&node_id) { return false }
+        self.conversations.insert(node_id, Conversation::new(node_id));
+        true
+    }
+
+    pub fn push(&mut self, node_id: NodeId, packet: Packet) {
+        match self.conversations.get_mut(&node_id) {
+            Some(conversation) if conversation.loaded => conversation.push(packet),
+            Some(conversation) => conversation.pending.push(packet),
+            None => ()
+        }
+    }
+
+    pub fn history(&mut self, node_id: NodeId, packets: Vec<Packet>, cursor: Option<usize>) {
+        let conversation = self.conversations.entry(node_id).or_insert_with(|| Conversation::new(node_id));
+        conversation.prepend(packets);
+        conversation.history_cursor = cursor;
+
+        if !conversation.loaded {
+            conversation.loaded = true;
+            for packet in std::mem::take(&mut conversation.pending) {
+                if !conversation.contains(&packet) { conversation.push(packet) }
+            }
+        }
+    }
+
+    pub fn react(&mut self, node_id: NodeId, reactions: Vec<(NodeId, Reaction)>) {
+        if let Some(conversation) = self.conversations.get_mut(&node_id).filter(|c| c.loaded) {
+            for (author, reaction) in reactions { conversation.react(author, reaction) }
+        }
+    }
+
+    pub fn set_timer(&mut self, node_id: NodeId, seconds: Option<u64>) {
+        if let Some(conversation) = self.conversations.get_mut(&node_id) {
+            conversation.timer = seconds;
+        }
+    }
+
+    pub fn expire(&mut self, node_id: NodeId, cutoff: u64) {
+        if let Some(conversation) = self.conversations.get_mut(&node_id) {
+            conversation.expire(cutoff);
+        }
+    }
+
+    /// Follow a contact onto their new key.
+    pub fn migrate(&mut self, old: NodeId, new: NodeId) {
+        if let Some(mut conversation) = self.conversations.remove(&old) {
+            conversation.remote_id = new;
+            conversation.aliases.push(old);
+
+            // A contact we could not reach when it rotated already wrote to us under its new key.
+            if let Some(recent) = self.conversations.remove(&new) {
+                conversation.packets.extend(recent.packets);
+                conversation.rendered.extend(recent.rendered);
+                conversation.reactions.extend(recent.reactions);
+            }
+            self.conversations.insert(new, conversation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+    use rand::rngs::OsRng;
+
+    use crate::networking::packet::SignedMessage;
+
+    use super::*;
+
+    fn packet(author: &SecretKey, packet_type: PacketType, id: MessageId, text: &str) -> Packet {
+        SignedMessage::new(author, author.public(), packet_type, id, None, text.as_bytes().to_vec())
+            .into_packet(author.public(), packet_type)
+    }
+
+    fn texts(store: &ConversationStore, node_id: NodeId) -> Vec<String> {
+        store.get(&node_id).unwrap().packets.iter().map(Conversation::body).collect()
+    }
+
+    #[test]
+    fn packets_before_history_are_merged_once() {
+        let them = SecretKey::generate(&mut OsRng);
+        let mut store = ConversationStore::default();
+        let (stored, missed) = (packet(&them, PacketType::String, [1; 16], "stored"), packet(&them, PacketType::String, [2; 16], "missed"));
+
+        assert!(store.open(them.public()));
+        store.push(them.public(), stored.clone());
+        store.push(them.public(), missed);
+        assert!(texts(&store, them.public()).is_empty());
+
+        // The database wrote the first packet before reading the page, but not the second.
+        store.history(them.public(), vec![stored], None);
+        assert_eq!(texts(&store, them.public()), ["stored", "missed"]);
+
+        store.push(them.public(), packet(&them, PacketType::String, [3; 16], "live"));
+        assert_eq!(texts(&store, them.public()), ["stored", "missed", "live"]);
+    }
+
+    #[test]
+    fn amendments_before_history_apply_in_order() {
+        let them = SecretKey::generate(&mut OsRng);
+        let mut store = ConversationStore::default();
+        let original = packet(&them, PacketType::String, [1; 16], "original");
+
+        store.open(them.public());
+        store.push(them.public(), packet(&them, PacketType::Delete, [1; 16], ""));
+        store.push(them.public(), packet(&them, PacketType::Edit, [1; 16], "edited"));
+        store.history(them.public(), vec![original], None);
+
+        let conversation = store.get(&them.public()).unwrap();
+        assert_eq!(conversation.packets[0].revision, Revision::Deleted);
+        assert!(!conversation.rendered.contains_key(&[1; 16]));
+    }
+
+    #[test]
+    fn messages_stay_editable_across_rotations() {
+        let (them, them_later, us, us_later) = (SecretKey::generate(&mut OsRng), SecretKey::generate(&mut OsRng), SecretKey::generate(&mut OsRng), SecretKey::generate(&mut OsRng));
+        let mut store = ConversationStore::default();
+
+        store.open(them.public());
+        store.history(them.public(), vec![
+            packet(&them, PacketType::String, [1; 16], "theirs"),
+            packet(&us, PacketType::String, [2; 16], "ours")
+        ], None);
+        store.migrate(them.public(), them_later.public());
+
+        // Either side may edit under its new key, but never the other side's messages.
+        store.push(them_later.public(), packet(&them_later, PacketType::Edit, [1; 16], "theirs, edited"));
+        store.push(them_later.public(), packet(&us_later, PacketType::Edit, [2; 16], "ours, edited"));
+        store.push(them_later.public(), packet(&us_later, PacketType::Edit, [1; 16], "forged"));
+        store.push(them_later.public(), packet(&them_later, PacketType::Delete, [2; 16], ""));
+        assert_eq!(texts(&store, them_later.public()), ["theirs, edited", "ours, edited"]);
+    }
+
+    #[test]
+    fn expiry_keeps_rotation_notices() {
+        let (them, later) = (SecretKey::generate(&mut OsRng), SecretKey::generate(&mut OsRng));
+        let mut store = ConversationStore::default();
+        let mut notice = Packet::new(later.public(), Ok(RotationStatement::new(&them, &later).to_bytes()), PacketType::Rotation);
+        notice.timestamp = 0;
+
+        store.open(them.public());
+        store.history(them.public(), vec![packet(&them, PacketType::String, [1; 16], "old"), notice], None);
+        store.expire(them.public(), u64::MAX);
+
+        let conversation = store.get(&them.public()).unwrap();
+        assert_eq!(conversation.packets.len(), 1);
+        assert_eq!(conversation.packets[0].packet_type, PacketType::Rotation);
+    }
+
+    #[test]
+    fn untracked_conversations_ignore_packets() {
+        let them = SecretKey::generate(&mut OsRng);
+        let mut store = ConversationStore::default();
+
+        store.push(them.public(), packet(&them, PacketType::String, [1; 16], "stray"));
+        assert!(store.get(&them.public()).is_none());
+    }
+}
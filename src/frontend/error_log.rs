@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Local};
+use iced::widget::{button, container, text, Column, Container, Row, Scrollable};
+use iced::{Element, Length};
+
+use crate::{error::Error, frontend::message::{Global, Message}};
+
+/// Only the most recent errors are kept.
+const MAX_ENTRIES: usize = 200;
+
+const PANEL_WIDTH: f32 = 360.0;
+
+/// Every error reported this session, newest first, for when a toast went by too quickly.
+#[derive(Default)]
+pub struct ErrorLog {
+    entries: VecDeque<(DateTime<Local>, Error)>,
+    open: bool
+}
+
+impl ErrorLog {
+    pub fn record(&mut self, error: Error) {
+        if self.entries.len() >= MAX_ENTRIES { self.entries.pop_back(); }
+        self.entries.push_front((Local::now(), error));
+    }
+
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn view(&self) -> Element<'_, Message> {
+        let entries = Column::from_iter(self.entries.iter().map(|(when, error)|
+            Column::new()
+                .push(text(when.format("%Y-%m-%d %H:%M:%S").to_string()).size(11))
+                .push(text(error.message()))
+                .push_maybe(details(error).map(|details| text(details).size(11)))
+                .into()
+        )).spacing(8);
+
+        Container::new(
+            Column::new()
+                .spacing(8)
+                .push(
+                    Row::new()
+                        .spacing(4)
+                        .push(text("ERRORS").width(Length::Fill))
+                        .push(button(text("CLEAR")).on_press(Message::Global(Global::ClearErrorLog)))
+                        .push(button(text("CLOSE")).on_press(Message::Global(Global::ToggleErrorLog)))
+                )
+                .push_maybe(self.entries.is_empty().then(|| text("NO ERRORS")))
+                .push(Scrollable::new(entries).height(Length::Fill))
+        )
+            .padding(8)
+            .width(PANEL_WIDTH)
+            .height(Length::Fill)
+            .style(container::rounded_box)
+            .into()
+    }
+}
+
+/// The technical side of an error for bug reports: the failing query, if any, then each underlying cause.
+fn details(error: &Error) -> Option<String> {
+    let mut lines = Vec::new();
+    let display = error.to_string();
+    if display != error.message() { lines.push(display) }
+
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        lines.push(format!("Caused by: {cause}"));
+        source = cause.source();
+    }
+
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_entries() {
+        let mut log = ErrorLog::default();
+        log.record(Error::InvalidDate);
+        for _ in 0..MAX_ENTRIES { log.record(Error::InvalidQuietHours) }
+
+        assert_eq!(log.count(), MAX_ENTRIES);
+        assert!(log.entries.iter().all(|(_, e)| matches!(e, Error::InvalidQuietHours)));
+
+        log.record(Error::InvalidDate);
+        assert!(matches!(log.entries.front(), Some((_, Error::InvalidDate))));
+
+        log.clear();
+        assert_eq!(log.count(), 0);
+    }
+
+    #[test]
+    fn details_name_the_query_and_its_cause() {
+        assert_eq!(details(&Error::InvalidDate), None);
+
+        let error = Error::Query { query: "SELECT 1;", source: Arc::new(rusqlite::Error::InvalidQuery) };
+        assert_eq!(details(&error).unwrap(), format!("Query failed: SELECT 1; ({})", rusqlite::Error::InvalidQuery));
+
+        let error = Error::Migrate(Arc::new(rusqlite::Error::InvalidQuery));
+        assert_eq!(details(&error).unwrap(), format!("Caused by: {}", rusqlite::Error::InvalidQuery));
+    }
+}
@@ -0,0 +1,195 @@
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::rngs::OsRng;
+
+use crate::error::{Error, Res};
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Shorter timeouts would lock the user out again as soon as they unlock.
+pub const MIN_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Wrong PINs allowed before each further attempt has to wait.
+const FREE_ATTEMPTS: u32 = 3;
+const FIRST_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Optional PIN lock that hides the UI after a period without input.
+/// Only the UI is locked, the network thread keeps running underneath.
+pub struct AppLock {
+    pin_hash: Option<String>,
+    idle_timeout: Duration,
+    last_activity: Instant,
+    locked: bool,
+    unread: usize,
+
+    // Wrong PINs entered in a row, and when the next attempt is allowed.
+    failed_attempts: u32,
+    retry_at: Option<Instant>
+}
+
+impl AppLock {
+    /// Start locked if a PIN has been configured.
+    pub fn new(pin_hash: Option<String>, idle_timeout: Duration) -> Self {
+        Self {
+            locked: pin_hash.is_some(),
+            pin_hash,
+            idle_timeout: idle_timeout.max(MIN_IDLE_TIMEOUT),
+            last_activity: Instant::now(),
+            unread: 0,
+            failed_attempts: 0,
+            retry_at: None
+        }
+    }
+
+    pub fn configure(&mut self, pin_hash: Option<String>, idle_timeout: Duration) {
+        self.pin_hash = pin_hash;
+        self.idle_timeout = idle_timeout.max(MIN_IDLE_TIMEOUT);
+        self.last_activity = Instant::now();
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn has_pin(&self) -> bool {
+        self.pin_hash.is_some()
+    }
+
+    /// Number of messages that arrived since the UI locked.
+    pub fn unread(&self) -> usize {
+        self.unread
+    }
+
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Lock the UI if a PIN is set and there has been no input for the idle timeout.
+    pub fn tick(&mut self) {
+        if self.pin_hash.is_some() && !self.locked && self.last_activity.elapsed() >= self.idle_timeout {
+            self.locked = true;
+            self.unread = 0;
+        }
+    }
+
+    pub fn record_incoming(&mut self) {
+        if self.locked { self.unread += 1; }
+    }
+
+    /// Attempt to unlock with a PIN.
+    pub fn unlock(&mut self, pin: &str) -> Res<()> {
+        self.verify(pin)?;
+        self.locked = false;
+        self.touch();
+        Ok(())
+    }
+
+    /// Check the PIN, which always passes when none is set. After a few wrong PINs each attempt has to wait
+    /// longer than the last, and attempts made before then are refused without checking the PIN. Unlocking and
+    /// confirming changes in the settings share the count, so neither can be used to guess faster.
+    pub fn verify(&mut self, pin: &str) -> Res<()> {
+        if let Some(wait) = self.retry_at.and_then(|at| at.checked_duration_since(Instant::now())) {
+            return Err(Error::PinBackoff(wait.as_secs() + 1));
+        }
+
+        let correct = match self.pin_hash.as_ref() {
+            Some(hash) => verify_pin(hash, pin),
+            None => true
+        };
+
+        if !correct {
+            self.failed_attempts += 1;
+            self.retry_at = (self.failed_attempts >= FREE_ATTEMPTS).then(|| Instant::now() + backoff(self.failed_attempts));
+            return Err(Error::IncorrectPin);
+        }
+
+        self.failed_attempts = 0;
+        self.retry_at = None;
+        Ok(())
+    }
+}
+
+/// Wait imposed after the given number of wrong PINs in a row, doubling from the last free attempt.
+fn backoff(failed_attempts: u32) -> Duration {
+    match failed_attempts.checked_sub(FREE_ATTEMPTS) {
+        Some(extra) => FIRST_BACKOFF.saturating_mul(2u32.saturating_pow(extra)).min(MAX_BACKOFF),
+        None => Duration::ZERO
+    }
+}
+
+/// Hash a PIN into a PHC string suitable for storing in the database.
+pub fn hash_pin(pin: &str) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(pin.as_bytes(), &salt).ok().map(|hash| hash.to_string())
+}
+
+fn verify_pin(hash: &str, pin: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(pin.as_bytes(), &hash).is_ok(),
+        Err(_) => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_timeout_is_clamped() {
+        let mut lock = AppLock::new(None, Duration::ZERO);
+        assert_eq!(lock.idle_timeout(), MIN_IDLE_TIMEOUT);
+
+        lock.configure(None, Duration::from_secs(1));
+        assert_eq!(lock.idle_timeout(), MIN_IDLE_TIMEOUT);
+    }
+
+    #[test]
+    fn backoff_grows_after_free_attempts() {
+        assert_eq!(backoff(1), Duration::ZERO);
+        assert_eq!(backoff(FREE_ATTEMPTS - 1), Duration::ZERO);
+        assert_eq!(backoff(FREE_ATTEMPTS), FIRST_BACKOFF);
+        assert_eq!(backoff(FREE_ATTEMPTS + 1), FIRST_BACKOFF * 2);
+        assert_eq!(backoff(FREE_ATTEMPTS + 40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn wrong_pins_lock_out_further_attempts() {
+        let mut lock = AppLock::new(hash_pin("1234"), DEFAULT_IDLE_TIMEOUT);
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(matches!(lock.unlock("0000"), Err(Error::IncorrectPin)));
+        }
+
+        // Even the right PIN is refused until the wait is over.
+        assert!(matches!(lock.unlock("1234"), Err(Error::PinBackoff(_))));
+        assert!(lock.is_locked());
+
+        lock.retry_at = None;
+        assert!(lock.unlock("1234").is_ok());
+        assert!(!lock.is_locked());
+        assert_eq!(lock.failed_attempts, 0);
+    }
+
+    #[test]
+    fn changing_settings_needs_the_current_pin() {
+        assert!(AppLock::new(None, DEFAULT_IDLE_TIMEOUT).verify("").is_ok());
+
+        let mut lock = AppLock::new(hash_pin("1234"), DEFAULT_IDLE_TIMEOUT);
+        lock.unlock("1234").unwrap();
+        assert!(matches!(lock.verify(""), Err(Error::IncorrectPin)));
+        assert!(lock.verify("1234").is_ok());
+
+        // Guessing through the settings counts towards the same backoff as the lock screen.
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(lock.verify("0000").is_err());
+        }
+        assert!(matches!(lock.verify("1234"), Err(Error::PinBackoff(_))));
+        assert!(!lock.is_locked());
+    }
+}
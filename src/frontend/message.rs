@@ -1,14 +1,20 @@
-use iced::Task;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use iced::{widget::scrollable::Viewport, Task};
 use iroh::NodeId;
 
-use crate::{error::Error, networking::{abstraction::NetworkTask, contact::Contact, packet::Packet}};
+use crate::{backend::database_interface::{SearchHit, SearchQuery}, error::Error, frontend::{notifications::Preview, pages::{search::ContactFilter, settings::Sensitive}}, networking::{abstraction::NetworkTask, contact::Contact, network::{Nearby, NearbyPeer, NodeDiagnostics}, packet::{MessageId, Packet, Reaction}, ticket::Ticket, transport::{Discovery, RelayFallback, RelayProbe, Transport}}};
 
 #[derive(Clone, Debug)]
 pub enum Message {
     None,
     Global(Global),
     Chat(Chat),
-    Add(Add)
+    Add(Add),
+    Settings(Settings),
+    Search(Search),
+    Diagnostics(Diagnostics)
 }
 
 impl Message {
@@ -32,25 +38,141 @@ pub enum Global {
     DatabaseContactEmmision(Contact),
     ContactName(NodeId, String),
     UsernameInput(String),
-    UpdateUsername
+    UpdateUsername,
+    PassphraseInput(String),
+    Unlock,
+    SetPassphrase(Option<String>),
+    Activity,
+    Tick,
+    UnlockApp,
+    // The current PIN comes first, followed by the new PIN and idle minutes. No new PIN removes the lock.
+    SetLock(String, Option<String>, Option<u64>),
+    RecoveryInput(String),
+    CreateIdentity,
+    RestoreIdentity,
+    RetryIdentity,
+    IdentityReady,
+    ExportIdentity(String),
+    RotateIdentity(String),
+    IdentityRotated(NodeId, NodeId),
+    ExportConversation(NodeId),
+    Exported(PathBuf),
+    VerifyExport(PathBuf),
+    ExportVerified(usize),
+    OpenLink(String),
+    ConfirmLink,
+    CancelLink,
+    Search(u64, SearchQuery),
+    OpenMessage(NodeId, MessageId),
+    UnreadCounts(HashMap<NodeId, usize>),
+    Focus(bool),
+    SetMuted(NodeId, bool),
+    SetPreview(Preview),
+    SetQuietHours(String),
+    ToggleErrorLog,
+    ClearErrorLog,
+    // Open a chat with someone and save them as a contact.
+    SaveChat(Contact),
+    SetTransport(Transport),
+    Nearby(Nearby)
 }
 
 #[derive(Clone, Debug)]
 pub enum Chat {
     MessageBox(String),
-    AddPacketToCache(Packet),
-    SetConversation(Vec<Packet>),
-    SendMessage
+    AddPacketToCache(NodeId, Packet),
+    History(NodeId, Vec<Packet>, Option<usize>),
+    Scrolled(Viewport),
+    SendMessage,
+    IdentityRotated(NodeId, NodeId),
+    SetTimer(Option<u64>),
+    TimerChanged(NodeId, Option<u64>),
+    Expire(NodeId, u64),
+    StartEdit(MessageId),
+    CancelEdit,
+    Delete(MessageId),
+    StartReply(MessageId),
+    CancelReply,
+    JumpTo(MessageId),
+    OpenThread(MessageId),
+    CloseThread,
+    ContactName(NodeId, String),
+    Copy(MessageId),
+    React(MessageId, String),
+    Reactions(NodeId, Vec<(NodeId, Reaction)>),
+    ToggleMute
 }
 
 #[derive(Clone, Debug)]
 pub enum Add {
     InputBox(String),
-    Submit
+    Submit,
+    CreateInvite(bool),
+    Invite(Ticket),
+    CopyInvite,
+    Nearby(Vec<NearbyPeer>),
+    ConnectNearby(NodeId)
+}
+
+#[derive(Clone, Debug)]
+pub enum Settings {
+    PassphraseInput(String),
+    PassphraseConfirm(String),
+    SetPassphrase,
+    RemovePassphrase,
+    PinInput(String),
+    TimeoutInput(String),
+    CurrentPinInput(String),
+    SetLock,
+    RemoveLock,
+    LockChanged(bool),
+    Ask(Sensitive),
+    Confirm,
+    Cancel,
+    RecoveryPhrase(String),
+    CopyRecoveryPhrase,
+    IdentityRotated(NodeId),
+    Preview(Preview),
+    QuietHoursInput(String),
+    SetQuietHours,
+    ExportPathInput(String),
+    VerifyExport,
+    Discovery(Discovery),
+    RelayInput(String),
+    AddressBookInput(String),
+    AddToAddressBook,
+    RemoveFromAddressBook(usize),
+    Broadcast(bool),
+    Listen(bool),
+    Fallback(RelayFallback),
+    TestRelays,
+    RelayProbes(Vec<RelayProbe>),
+    SaveTransport
+}
+
+#[derive(Clone, Debug)]
+pub enum Search {
+    QueryInput(String),
+    Contact(ContactFilter),
+    FromInput(String),
+    ToInput(String),
+    Attachment(bool),
+    Submit,
+    Results(u64, Vec<SearchHit>)
+}
+
+#[derive(Clone, Debug)]
+pub enum Diagnostics {
+    Refresh,
+    Report(NodeDiagnostics),
+    CopyNodeId
 }
 
 #[derive(Clone, Debug)]
 pub enum PageType {
     AddChat,
-    Chat(NodeId)
+    Chat(NodeId),
+    Settings,
+    Search,
+    Diagnostics
 }
@@ -1,3 +1,8 @@
 pub mod application;
 pub mod message;
+pub mod conversation;
 pub mod pages;
+pub mod lock;
+pub mod error_log;
+pub mod notifications;
+pub mod toast;
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use chrono::{Local, Timelike};
+use iroh::NodeId;
+use tracing::warn;
+
+use crate::{error::{Error, Res}, frontend::conversation::Conversation, networking::packet::Packet};
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// How much of an incoming message a notification reveals.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Preview {
+    #[default]
+    Full,
+    SenderOnly,
+    Hidden
+}
+
+impl Preview {
+    pub const ALL: [Preview; 3] = [Preview::Full, Preview::SenderOnly, Preview::Hidden];
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::SenderOnly => "sender",
+            Self::Hidden => "hidden"
+        }
+    }
+
+    pub fn from_key(key: &str) -> Self {
+        Self::ALL.into_iter().find(|p| p.key() == key).unwrap_or_default()
+    }
+}
+
+impl Display for Preview {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Self::Full => "SENDER AND MESSAGE",
+            Self::SenderOnly => "SENDER ONLY",
+            Self::Hidden => "NOTHING"
+        })
+    }
+}
+
+/// Local hours during which nothing is shown. The range may wrap past midnight, as in 22-7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    start: u32,
+    end: u32
+}
+
+impl QuietHours {
+    /// Parse a START-END pair of hours, with an empty input meaning no quiet hours.
+    pub fn parse(input: &str) -> Res<Option<Self>> {
+        if input.trim().is_empty() { return Ok(None) }
+
+        let (start, end) = input.split_once('-').ok_or(Error::InvalidQuietHours)?;
+        let start = start.trim().parse::<u32>().map_err(|_| Error::InvalidQuietHours)?;
+        let end = end.trim().parse::<u32>().map_err(|_| Error::InvalidQuietHours)?;
+
+        if start > 23 || end > 23 || start == end { return Err(Error::InvalidQuietHours) }
+        Ok(Some(Self { start, end }))
+    }
+
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl Display for QuietHours {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    pub contact: NodeId,
+    pub title: String,
+    pub body: String
+}
+
+/// Somewhere a notification can be shown.
+pub trait NotificationBackend {
+    fn show(&mut self, notification: &Notification) -> Res<()>;
+}
+
+/// Notifications through the desktop's notification daemon. Talking to the daemon can block for a while,
+/// so it happens off the UI thread and failures are only logged.
+pub struct DesktopBackend;
+
+impl NotificationBackend for DesktopBackend {
+    fn show(&mut self, notification: &Notification) -> Res<()> {
+        let notification = notification.clone();
+        tokio::task::spawn_blocking(move || {
+            let shown = notify_rust::Notification::new()
+                .appname("Pingpong")
+                .summary(&notification.title)
+                .body(&notification.body)
+                .show()
+                .map_err(|_| Error::FailedToNotify);
+
+            if let Err(e) = shown { warn!(error = %e, "could not show notification") }
+        });
+        Ok(())
+    }
+}
+
+/// Records notifications instead of showing them, so the service can be exercised without a desktop.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    shown: Arc<Mutex<Vec<Notification>>>
+}
+
+#[cfg(test)]
+impl MockBackend {
+    pub fn shown(&self) -> Vec<Notification> {
+        self.shown.lock().map(|shown| shown.clone()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+impl NotificationBackend for MockBackend {
+    fn show(&mut self, notification: &Notification) -> Res<()> {
+        if let Ok(mut shown) = self.shown.lock() { shown.push(notification.clone()) }
+        Ok(())
+    }
+}
+
+/// Decides whether and how an incoming message is announced. Desktop notifications are used while the
+/// window is unfocused, the in-app backend while it has focus.
+pub struct NotificationService {
+    desktop: Box<dyn NotificationBackend>,
+    in_app: Box<dyn NotificationBackend>,
+    focused: bool,
+
+    muted: HashSet<NodeId>,
+    quiet_hours: Option<QuietHours>,
+    preview: Preview
+}
+
+impl NotificationService {
+    pub fn new(desktop: Box<dyn NotificationBackend>, in_app: Box<dyn NotificationBackend>) -> Self {
+        Self {
+            desktop,
+            in_app,
+            focused: true,
+            muted: HashSet::new(),
+            quiet_hours: None,
+            preview: Preview::default()
+        }
+    }
+
+    pub fn configure(&mut self, muted: HashSet<NodeId>, quiet_hours: Option<QuietHours>, preview: Preview) {
+        self.muted = muted;
+        self.quiet_hours = quiet_hours;
+        self.preview = preview;
+    }
+
+    pub fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+    }
+
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    pub fn set_muted(&mut self, contact: NodeId, muted: bool) {
+        match muted {
+            true => self.muted.insert(contact),
+            false => self.muted.remove(&contact)
+        };
+    }
+
+    pub fn is_muted(&self, contact: NodeId) -> bool {
+        self.muted.contains(&contact)
+    }
+
+    pub fn set_quiet_hours(&mut self, quiet_hours: Option<QuietHours>) {
+        self.quiet_hours = quiet_hours;
+    }
+
+    pub fn quiet_hours(&self) -> Option<QuietHours> {
+        self.quiet_hours
+    }
+
+    pub fn set_preview(&mut self, preview: Preview) {
+        self.preview = preview;
+    }
+
+    pub fn preview(&self) -> Preview {
+        self.preview
+    }
+
+    /// Follow a contact onto their new key.
+    pub fn migrate(&mut self, old: NodeId, new: NodeId) {
+        if self.muted.remove(&old) { self.muted.insert(new); }
+    }
+
+    /// Announce an incoming message. While the app is locked nothing about the message is revealed,
+    /// whatever the preview setting.
+    pub fn notify(&mut self, contact: NodeId, sender: String, packet: &Packet, locked: bool) -> Res<()> {
+        self.notify_at(Local::now().hour(), contact, sender, packet, locked)
+    }
+
+    fn notify_at(&mut self, hour: u32, contact: NodeId, sender: String, packet: &Packet, locked: bool) -> Res<()> {
+        if self.muted.contains(&contact) { return Ok(()) }
+        if self.quiet_hours.is_some_and(|quiet| quiet.contains(hour)) { return Ok(()) }
+
+        let preview = if locked { Preview::Hidden } else { self.preview };
+        let notification = match preview {
+            Preview::Full => Notification { contact, title: sender, body: Conversation::body(packet) },
+            Preview::SenderOnly => Notification { contact, title: sender, body: String::from("New message") },
+            Preview::Hidden => Notification { contact, title: String::from("Pingpong"), body: String::from("New message") }
+        };
+
+        match self.focused {
+            true => self.in_app.show(&notification),
+            false => self.desktop.show(&notification)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+    use rand::rngs::OsRng;
+
+    use crate::networking::packet::{PacketType, SignedMessage};
+
+    use super::*;
+
+    fn service() -> (NotificationService, MockBackend, MockBackend) {
+        let (desktop, in_app) = (MockBackend::default(), MockBackend::default());
+        (NotificationService::new(Box::new(desktop.clone()), Box::new(in_app.clone())), desktop, in_app)
+    }
+
+    fn message(author: &SecretKey, text: &str) -> Packet {
+        SignedMessage::new(author, author.public(), PacketType::String, [0; 16], None, text.as_bytes().to_vec())
+            .into_packet(author.public(), PacketType::String)
+    }
+
+    #[test]
+    fn quiet_hours_wrap_past_midnight() {
+        let night = QuietHours::parse("22-7").unwrap().unwrap();
+        assert!([22, 23, 0, 3, 6].iter().all(|hour| night.contains(*hour)));
+        assert!([7, 12, 21].iter().all(|hour| !night.contains(*hour)));
+
+        let lunch = QuietHours::parse("12-13").unwrap().unwrap();
+        assert!(lunch.contains(12));
+        assert!(!lunch.contains(13) && !lunch.contains(0));
+
+        assert!(QuietHours::parse("5-5").is_err());
+        assert!(QuietHours::parse("22-24").is_err());
+        assert_eq!(QuietHours::parse(" ").unwrap(), None);
+    }
+
+    #[test]
+    fn muted_contacts_and_quiet_hours_stay_silent() {
+        let (mut service, desktop, in_app) = service();
+        let them = SecretKey::generate(&mut OsRng);
+        let packet = message(&them, "hello");
+
+        service.set_muted(them.public(), true);
+        service.notify_at(12, them.public(), String::from("them"), &packet, false).unwrap();
+        service.set_muted(them.public(), false);
+
+        service.set_quiet_hours(QuietHours::parse("22-7").unwrap());
+        service.notify_at(2, them.public(), String::from("them"), &packet, false).unwrap();
+        assert!(in_app.shown().is_empty());
+
+        service.notify_at(12, them.public(), String::from("them"), &packet, false).unwrap();
+        service.set_focused(false);
+        service.notify_at(12, them.public(), String::from("them"), &packet, false).unwrap();
+        assert_eq!(in_app.shown().len(), 1);
+        assert_eq!(desktop.shown().len(), 1);
+    }
+
+    #[test]
+    fn previews_reveal_only_what_is_allowed() {
+        let (mut service, _, in_app) = service();
+        let them = SecretKey::generate(&mut OsRng);
+        let packet = message(&them, "secret plans");
+        let notify = |service: &mut NotificationService, locked| {
+            service.notify_at(12, them.public(), String::from("them"), &packet, locked).unwrap();
+            in_app.shown().pop().unwrap()
+        };
+
+        let full = notify(&mut service, false);
+        assert_eq!((full.title.as_str(), full.body.as_str()), ("them", "secret plans"));
+
+        service.set_preview(Preview::SenderOnly);
+        let sender = notify(&mut service, false);
+        assert_eq!((sender.title.as_str(), sender.body.as_str()), ("them", "New message"));
+
+        service.set_preview(Preview::Hidden);
+        let hidden = notify(&mut service, false);
+        assert_eq!((hidden.title.as_str(), hidden.body.as_str()), ("Pingpong", "New message"));
+
+        // Locking hides everything, whatever the setting.
+        service.set_preview(Preview::Full);
+        let locked = notify(&mut service, true);
+        assert!(!locked.title.contains("them") && !locked.body.contains("secret"));
+    }
+}
@@ -1,24 +1,91 @@
 use std::str::FromStr;
 
-use iced::{widget::text_input, Element, Task};
-use iroh::NodeId;
+use iced::{clipboard, widget::{button, qr_code, text, text_input, Column, Row}, Element, Task};
+use iroh::{NodeAddr, NodeId};
 
-use crate::{error::Error, frontend::{application::Page, message::{Add, Global, Message}}, networking::{abstraction::NetworkTask, packet::PacketType}};
+use crate::{backend::database_interface::INVITE_TTL, error::Error, frontend::{application::Page, conversation::ConversationStore, message::{Add, Global, Message}}, networking::{abstraction::NetworkTask, contact::Contact, network::NearbyPeer, ticket::{InviteSecret, Ticket}}};
+
+const QR_CELL_SIZE: u16 = 4;
 
-#[derive(Default)]
 pub struct AddPage {
-    id_input: String
+    id_input: String,
+
+    // Our latest invite, with its QR code unless it was too long to encode.
+    invite: Option<(String, Option<qr_code::Data>)>,
+
+    nearby: Vec<NearbyPeer>,
+    listening: bool
+}
+
+impl AddPage {
+    pub fn new(nearby: Vec<NearbyPeer>, listening: bool) -> Self {
+        Self { id_input: String::default(), invite: None, nearby, listening }
+    }
+
+    /// Connect to a full address, and once connected save the contact under the name it came with.
+    fn connect_addr(addr: NodeAddr, secret: Option<InviteSecret>, username: Option<String>) -> Message {
+        let contact = Contact { server_address: addr.node_id, username };
+        Message::Global(Global::NetworkTask(NetworkTask::Connect(addr, secret, Some(contact))))
+    }
+
+    /// Accept either an invite ticket or a bare public key.
+    fn connect(input: &str) -> Message {
+        if let Ok(ticket) = Ticket::from_str(input) {
+            return Self::connect_addr(ticket.addr, ticket.secret, ticket.username);
+        }
+
+        match NodeId::from_str(input.trim()) {
+            Ok(id) => Message::Global(Global::NetworkTask(
+                NetworkTask::Connect(NodeAddr::new(id), None, None)
+            )),
+            Err(_) => Message::Global(Global::Warn(Error::InvalidTicket))
+        }
+    }
+
+    fn view_nearby(&self) -> Element<'_, Message> {
+        if self.nearby.is_empty() {
+            return text(match self.listening {
+                true => "NO ONE NEARBY YET",
+                false => "To see people nearby, turn on \"Look for pingpong users on this network\" in settings."
+            }).size(12).into();
+        }
+
+        Column::from_iter(self.nearby.iter().map(|peer|
+            Row::new()
+                .spacing(8)
+                .push(text(peer.username.clone().unwrap_or_else(|| peer.addr.node_id.fmt_short().to_string())))
+                .push(button(text("CONNECT")).on_press(Message::Add(Add::ConnectNearby(peer.addr.node_id))))
+                .into()
+        )).spacing(4).into()
+    }
 }
 
 impl Page for AddPage {
-    fn view(&self) -> Element<Message> {
-        text_input("Enter IROH Public Key", &self.id_input)
-            .on_input(|v| Message::Add(Add::InputBox(v)))
-            .on_submit(Message::Add(Add::Submit))
+    fn view<'a>(&'a self, _: &'a ConversationStore) -> Element<'a, Message> {
+        Column::new()
+            .spacing(8)
+            .push(
+                text_input("Paste an invite ticket or IROH public key", &self.id_input)
+                    .on_input(|v| Message::Add(Add::InputBox(v)))
+                    .on_submit(Message::Add(Add::Submit))
+            )
+            .push(text("PEOPLE NEARBY"))
+            .push(self.view_nearby())
+            .push(text("INVITE SOMEONE"))
+            .push(
+                Row::new()
+                    .spacing(8)
+                    .push(button(text("ONE-TIME INVITE")).on_press(Message::Add(Add::CreateInvite(true))))
+                    .push(button(text("REUSABLE INVITE")).on_press(Message::Add(Add::CreateInvite(false))))
+            )
+            .push(text(format!("ONE-TIME INVITES EXPIRE AFTER {} DAYS", INVITE_TTL / (24 * 60 * 60))).size(11))
+            .push_maybe(self.invite.as_ref().and_then(|(_, qr)| qr.as_ref()).map(|qr| qr_code(qr).cell_size(QR_CELL_SIZE)))
+            .push_maybe(self.invite.as_ref().map(|(ticket, _)| text(ticket).size(12)))
+            .push_maybe(self.invite.as_ref().map(|_| button(text("COPY")).on_press(Message::Add(Add::CopyInvite))))
             .into()
     }
 
-    fn update(&mut self, message: Message) -> Task<Message> {
+    fn update(&mut self, message: Message, _: &ConversationStore) -> Task<Message> {
         if let Message::Add(message) = message {
             match message {
                 Add::InputBox(new_value) => {
@@ -26,14 +93,32 @@ impl Page for AddPage {
                     Message::None.task()
                 }
 
-                Add::Submit => {
-                    let id = std::mem::take(&mut self.id_input);
-                    match NodeId::from_str(&id) {
-                        Ok(id) => Message::Global(Global::NetworkTask(
-                            NetworkTask::Connect(id)
-                        )).task(),
-                        Err(_) => Message::Global(Global::Warn(Error::NoSuchClient)).task()
-                    }
+                Add::Submit => Self::connect(&std::mem::take(&mut self.id_input)).task(),
+
+                Add::CreateInvite(one_time) => Message::Global(Global::NetworkTask(
+                    NetworkTask::CreateInvite(one_time)
+                )).task(),
+
+                Add::Invite(ticket) => {
+                    let ticket = ticket.to_string();
+                    let qr = qr_code::Data::new(&ticket).ok();
+                    self.invite = Some((ticket, qr));
+                    Message::None.task()
+                }
+
+                Add::CopyInvite => match self.invite.as_ref() {
+                    Some((ticket, _)) => clipboard::write(ticket.clone()),
+                    None => Message::None.task()
+                },
+
+                Add::Nearby(nearby) => {
+                    self.nearby = nearby;
+                    Message::None.task()
+                }
+
+                Add::ConnectNearby(node_id) => match self.nearby.iter().find(|peer| peer.addr.node_id == node_id) {
+                    Some(peer) => Self::connect_addr(peer.addr.clone(), None, peer.username.clone()).task(),
+                    None => Message::None.task()
                 }
             }
         } else {
@@ -41,3 +126,27 @@ impl Page for AddPage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contacts_are_only_saved_once_connected() {
+        let identity = iroh::SecretKey::generate(&mut rand::rngs::OsRng);
+        let ticket = Ticket::new(&identity, NodeAddr::new(identity.public()), Some(String::from("alice")), Some([7; 16]));
+
+        // A ticket asks the network to save the contact after connecting, rather than saving it straight away.
+        assert!(matches!(
+            AddPage::connect(&ticket.to_string()),
+            Message::Global(Global::NetworkTask(NetworkTask::Connect(addr, Some(secret), Some(Contact { server_address, username: Some(username) }))))
+                if addr.node_id == identity.public() && secret == [7; 16] && server_address == identity.public() && username == "alice"
+        ));
+
+        assert!(matches!(
+            AddPage::connect(&identity.public().to_string()),
+            Message::Global(Global::NetworkTask(NetworkTask::Connect(_, None, None)))
+        ));
+        assert!(matches!(AddPage::connect("not a ticket"), Message::Global(Global::Warn(Error::InvalidTicket))));
+    }
+}
@@ -1,63 +1,561 @@
-use iced::{widget::{text, text_input, Column, Container, Scrollable}, Element, Length, Task};
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use iced::{border, clipboard, widget::{button, container, markdown, pick_list, scrollable::{self, RelativeOffset}, text, text_input, tooltip, Column, Container, Row, Scrollable, Space}, Element, Length, Task, Theme};
 use iroh::NodeId;
 
-use crate::{frontend::{application::Page, message::{Chat, Global, Message}}, networking::{abstraction::NetworkTask, packet::{Packet, PacketType}}};
+use crate::{frontend::{application::Page, conversation::{Conversation, ConversationStore}, message::{Chat, Global, Message}}, networking::{abstraction::NetworkTask, packet::{MessageId, Packet, PacketType, Reaction, Revision, RotationStatement}}};
+
+/// Choices offered for the disappearing message timer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TimerChoice(Option<u64>);
+
+/// Consecutive messages from one author closer together than this many seconds are grouped under one name.
+const GROUP_WINDOW: u64 = 5 * 60;
+
+const BUBBLE_WIDTH: f32 = 480.0;
+
+/// Only this many rows are built at once. Scrolling near either end slides the window by WINDOW_STEP rows,
+/// and older pages are fetched from the database once the window reaches the oldest loaded message.
+const WINDOW_SIZE: usize = 150;
+const WINDOW_STEP: usize = 50;
+
+/// How close to either end, as a fraction of the scrollable height, counts as reaching it.
+const SCROLL_EDGE: f32 = 0.05;
+
+/// How many characters of the original message a reply quotes.
+const PREVIEW_LENGTH: usize = 48;
+
+/// Emoji offered in the reaction picker under each message.
+const QUICK_REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🎉"];
 
+const TIMER_CHOICES: [TimerChoice; 6] = [
+    TimerChoice(None),
+    TimerChoice(Some(30)),
+    TimerChoice(Some(5 * 60)),
+    TimerChoice(Some(60 * 60)),
+    TimerChoice(Some(24 * 60 * 60)),
+    TimerChoice(Some(7 * 24 * 60 * 60))
+];
+
+impl Display for TimerChoice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let seconds = match self.0 {
+            Some(seconds) => seconds,
+            None => return write!(f, "OFF")
+        };
+
+        let (amount, unit) = [(7 * 24 * 60 * 60, "WEEK"), (24 * 60 * 60, "DAY"), (60 * 60, "HOUR"), (60, "MINUTE")]
+            .into_iter()
+            .find(|(unit, _)| seconds % unit == 0)
+            .map(|(unit, name)| (seconds / unit, name))
+            .unwrap_or((seconds, "SECOND"));
+
+        write!(f, "{amount} {unit}{}", if amount == 1 { "" } else { "S" })
+    }
+}
+
+/// Shows one conversation from the application's ConversationStore. The page itself only holds what the
+/// user is doing with it: the draft, scroll window, thread, and any edit or reply in progress.
 pub struct ChatPage {
     remote_id: NodeId,
+    remote_name: Option<String>,
     message_box: String,
-    conversation: Vec<Packet>
+    muted: bool,
+
+    // How many of the newest rows lie below the rendered window, zero when pinned to the bottom.
+    window_back: usize,
+    loading_history: bool,
+
+    // Message to scroll to once enough history has been loaded to contain it.
+    focus: Option<MessageId>,
+
+    editing: Option<MessageId>,
+    replying: Option<MessageId>,
+
+    // When set, only this message and its replies are shown.
+    thread: Option<MessageId>
 }
 
 impl ChatPage {
-    pub fn new(remote_id: NodeId) -> Self {
+    pub fn new(remote_id: NodeId, remote_name: Option<String>) -> Self {
         Self {
             remote_id,
+            remote_name,
             message_box: String::default(),
-            conversation: Vec::default()
+            muted: false,
+            window_back: 0,
+            loading_history: false,
+            focus: None,
+            editing: None,
+            replying: None,
+            thread: None
+        }
+    }
+
+    pub fn focus(mut self, focus: Option<MessageId>) -> Self {
+        self.focus = focus;
+        self
+    }
+
+    pub fn muted(mut self, muted: bool) -> Self {
+        self.muted = muted;
+        self
+    }
+
+    /// Called once the page is shown. Loads the conversation if this is its first time open this session,
+    /// otherwise goes straight to the focused message.
+    pub fn opened(&mut self, conversations: &mut ConversationStore) -> Task<Message> {
+        if conversations.open(self.remote_id) {
+            return Message::Global(Global::NetworkTask(NetworkTask::RequestConversation(self.remote_id))).task();
+        }
+
+        match conversations.get(&self.remote_id).filter(|c| c.loaded) {
+            Some(conversation) => self.seek_focus(conversation),
+            None => Message::None.task()
+        }
+    }
+
+    /// Keep paging back until the message being opened turns up.
+    fn seek_focus(&mut self, conversation: &Conversation) -> Task<Message> {
+        let Some(id) = self.focus else { return Message::None.task() };
+
+        if conversation.position(id).is_some() {
+            self.focus = None;
+            return self.jump_to(conversation, id);
+        }
+
+        match conversation.history_cursor {
+            Some(cursor) => {
+                self.loading_history = true;
+                Message::Global(Global::NetworkTask(NetworkTask::RequestHistory(self.remote_id, cursor))).task()
+            }
+            None => {
+                self.focus = None;
+                Message::None.task()
+            }
         }
     }
+
+    /// Indices of the rows currently built, always as many as there are up to WINDOW_SIZE.
+    fn window(&self, conversation: &Conversation) -> Range<usize> {
+        let len = conversation.packets.len();
+        let end = len.saturating_sub(self.window_back).max(len.min(WINDOW_SIZE));
+        end.saturating_sub(WINDOW_SIZE)..end
+    }
+
+    /// Slide the window towards older rows, or fetch an older page when there are none left in memory.
+    fn scroll_older(&mut self, conversation: &Conversation) -> Task<Message> {
+        let window = self.window(conversation);
+        if window.start > 0 {
+            let shift = WINDOW_STEP.min(window.start);
+            self.window_back = conversation.packets.len() - window.end + shift;
+            return snap_to_row(shift, window.len());
+        }
+
+        match conversation.history_cursor {
+            Some(cursor) if !self.loading_history => {
+                self.loading_history = true;
+                Message::Global(Global::NetworkTask(NetworkTask::RequestHistory(self.remote_id, cursor))).task()
+            }
+            _ => Message::None.task()
+        }
+    }
+
+    fn scroll_newer(&mut self, conversation: &Conversation) -> Task<Message> {
+        let window = self.window(conversation);
+        let below = conversation.packets.len() - window.end;
+        let shift = WINDOW_STEP.min(below);
+        if shift == 0 { return Message::None.task() }
+
+        self.window_back = below - shift;
+        snap_to_row(window.len().saturating_sub(shift + 1), window.len())
+    }
+
+    fn jump_to(&mut self, conversation: &Conversation, id: MessageId) -> Task<Message> {
+        // Leave any thread so the original is guaranteed to be in the list being scrolled.
+        self.thread = None;
+        let Some(index) = conversation.position(id) else {
+            return Message::None.task();
+        };
+
+        let len = conversation.packets.len();
+        self.window_back = len - (index + WINDOW_STEP).min(len);
+        let window = self.window(conversation);
+        snap_to_row(index - window.start, window.len())
+    }
+
+    fn view_reactions<'a>(&'a self, conversation: &'a Conversation, id: MessageId) -> Row<'a, Message> {
+        let chips = conversation.reactions.get(&id).into_iter().flatten().map(move |(emoji, authors)| {
+            let who = authors.iter()
+                .map(|a| self.author_name(conversation, *a))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            tooltip(
+                button(text(format!("{emoji} {}", authors.len()))).on_press(Message::Chat(Chat::React(id, emoji.clone()))),
+                text(who),
+                tooltip::Position::Top
+            ).into()
+        });
+
+        Row::from_iter(chips)
+            .push(pick_list(QUICK_REACTIONS, None::<&str>, move |emoji| Message::Chat(Chat::React(id, emoji.to_string()))).placeholder("REACT"))
+    }
+
+    fn conversation_id() -> scrollable::Id {
+        scrollable::Id::new("conversation")
+    }
+
+    /// A short quote of a message, for replies and the reply bar.
+    fn preview(&self, conversation: &Conversation, id: MessageId) -> String {
+        match conversation.find(id) {
+            Some(p) => {
+                let body = Conversation::body(p);
+                let quote: String = body.chars().take(PREVIEW_LENGTH).collect();
+                format!("> {}: {quote}{}",
+                    self.author_name(conversation, p.author),
+                    if body.chars().count() > PREVIEW_LENGTH { "..." } else { "" }
+                )
+            }
+            None => String::from("> ORIGINAL MESSAGE UNAVAILABLE")
+        }
+    }
+
+    fn author_name(&self, conversation: &Conversation, author: NodeId) -> String {
+        if conversation.is_remote(author) {
+            self.title()
+        } else {
+            String::from("You")
+        }
+    }
+
+    fn title(&self) -> String {
+        self.remote_name.clone().unwrap_or_else(|| self.remote_id.fmt_short().to_string())
+    }
+
+    /// Build the visible rows, with a separator whenever the day changes. A message directly following one
+    /// from the same author on the same day and within GROUP_WINDOW does not repeat the author's name.
+    fn view_conversation<'a>(&'a self, conversation: &'a Conversation) -> Column<'a, Message> {
+        // Threads are short, so they are shown whole rather than through the window.
+        let visible: Box<dyn Iterator<Item = &Packet> + '_> = match self.thread {
+            Some(parent) => Box::new(conversation.packets.iter().filter(move |p| p.id == Some(parent) || p.reply_to == Some(parent))),
+            None => Box::new(conversation.packets[self.window(conversation)].iter())
+        };
+
+        let mut rows = Column::new().spacing(4).padding(8);
+        let mut previous: Option<&Packet> = None;
+
+        for p in visible {
+            let day = local_time(p.timestamp).date_naive();
+            let new_day = previous.is_none_or(|prev| local_time(prev.timestamp).date_naive() != day);
+
+            if new_day {
+                rows = rows.push(
+                    Container::new(text(day_label(day)).size(12)).center_x(Length::Fill)
+                );
+            }
+
+            let grouped = !new_day && previous.is_some_and(|prev|
+                prev.packet_type != PacketType::Rotation
+                    && conversation.is_remote(prev.author) == conversation.is_remote(p.author)
+                    && p.timestamp.saturating_sub(prev.timestamp) < GROUP_WINDOW
+            );
+
+            rows = rows.push(self.view_packet(conversation, p, grouped));
+            previous = Some(p);
+        }
+        rows
+    }
+
+    fn view_packet<'a>(&'a self, conversation: &'a Conversation, p: &'a Packet, grouped: bool) -> Element<'a, Message> {
+        if p.packet_type == PacketType::Rotation {
+            return Container::new(text(match p.content.as_ref().map(|c| RotationStatement::from_bytes(c)) {
+                Ok(Ok(statement)) => format!("Verified key change: {} is now {}", statement.old.fmt_short(), statement.new.fmt_short()),
+                _ => String::from("Invalid key change")
+            }).size(12)).center_x(Length::Fill).into();
+        }
+
+        let remote = conversation.is_remote(p.author);
+        let body: Element<Message> = match p.id.and_then(|id| conversation.rendered.get(&id)) {
+            Some(items) => markdown::view(items, markdown::Settings::default(), markdown::Style::from_palette(Theme::default().palette()))
+                .map(|url| Message::Global(Global::OpenLink(url.to_string()))),
+            None => text(Conversation::body(p)).into()
+        };
+
+        let footer = text(format!("{}{}",
+            local_time(p.timestamp).format("%H:%M"),
+            if p.revision == Revision::Edited { " · edited" } else { "" }
+        )).size(11);
+
+        let quote = p.reply_to.map(|parent| button(text(self.preview(conversation, parent)).size(12))
+            .style(button::secondary)
+            .on_press(Message::Chat(Chat::JumpTo(parent))));
+
+        let mut bubble = Column::new()
+            .spacing(4)
+            .push_maybe((!grouped).then(|| text(self.author_name(conversation, p.author)).size(12)))
+            .push_maybe(quote)
+            .push(body)
+            .push(footer);
+
+        if let Some(id) = p.id.filter(|_| p.revision != Revision::Deleted) {
+            let replies = conversation.packets.iter().filter(|p| p.reply_to == Some(id)).count();
+            let mut actions = Row::new()
+                .spacing(4)
+                .push_maybe((replies > 0 && self.thread.is_none()).then(|| action(format!("THREAD ({replies})"), Chat::OpenThread(id))))
+                .push(action(String::from("REPLY"), Chat::StartReply(id)))
+                .push(action(String::from("COPY"), Chat::Copy(id)));
+
+            // Only our own messages can be changed.
+            if !remote {
+                actions = actions
+                    .push(action(String::from("EDIT"), Chat::StartEdit(id)))
+                    .push(action(String::from("DELETE"), Chat::Delete(id)));
+            }
+
+            bubble = bubble.push(actions).push(self.view_reactions(conversation, id));
+        }
+
+        let bubble = Container::new(bubble)
+            .padding(10)
+            .max_width(BUBBLE_WIDTH)
+            .style(if remote { container::rounded_box } else { own_bubble });
+
+        // Our messages sit on the right, the contact's on the left.
+        if remote {
+            Row::new().push(bubble).push(Space::with_width(Length::Fill)).into()
+        } else {
+            Row::new().push(Space::with_width(Length::Fill)).push(bubble).into()
+        }
+    }
+}
+
+/// Keep the row at `index` of a window of `len` rows in view. Offsets count from the bottom, as the list is
+/// anchored there.
+fn snap_to_row(index: usize, len: usize) -> Task<Message> {
+    let y = 1.0 - index as f32 / len.saturating_sub(1).max(1) as f32;
+    scrollable::snap_to(ChatPage::conversation_id(), RelativeOffset { x: 0.0, y })
+}
+
+fn action(label: String, message: Chat) -> Element<'static, Message> {
+    button(text(label).size(11))
+        .padding([2, 6])
+        .style(button::text)
+        .on_press(Message::Chat(message))
+        .into()
+}
+
+fn own_bubble(theme: &Theme) -> container::Style {
+    let palette = theme.extended_palette();
+    container::Style {
+        background: Some(palette.primary.weak.color.into()),
+        text_color: Some(palette.primary.weak.text),
+        border: border::rounded(8),
+        ..container::Style::default()
+    }
+}
+
+fn local_time(timestamp: u64) -> DateTime<Local> {
+    Local.timestamp_opt(timestamp as i64, 0).single().unwrap_or_default()
+}
+
+fn day_label(day: NaiveDate) -> String {
+    let today = Local::now().date_naive();
+    if day == today {
+        String::from("Today")
+    } else if Some(day) == today.pred_opt() {
+        String::from("Yesterday")
+    } else {
+        day.format("%A, %-d %B %Y").to_string()
+    }
 }
 
 impl Page for ChatPage {
-    fn view(&self) -> Element<Message> {
+    fn view<'a>(&'a self, conversations: &'a ConversationStore) -> Element<'a, Message> {
+        let conversation = conversations.get(&self.remote_id).filter(|c| c.loaded);
 
         Column::new()
-            .push(text(self.remote_id.to_string()))
             .push(
-                Container::new(Scrollable::new(Column::from_iter(
-                    self.conversation.iter().map(
-                        |p| text(format!("{:?}: {:?}",
-                            if p.author == self.remote_id { "REMOTE" } else { "LOCAL" },
-                            if p.packet_type == PacketType::String {
-                                if let Ok(content) = p.content.clone() {
-                                    match String::from_utf8(content) {
-                                        Ok(message) => message,
-                                        Err(_) => String::from("INVALID UTF-8")
-                                    }
-                                } else { String::from("EMPTY") }
-                            } else { String::from("NOT A STRING") }
-                        )).into()
+                Row::new()
+                    .push(text(self.title()).size(20).width(Length::Fill))
+                    .push(text("DISAPPEARING: "))
+                    .push(
+                        pick_list(
+                            TIMER_CHOICES,
+                            Some(TimerChoice(conversation.and_then(|c| c.timer))),
+                            |choice| Message::Chat(Chat::SetTimer(choice.0))
+                        )
                     )
-                ))).width(Length::Fill).height(Length::Fill))
+                    .push(
+                        button(text(if self.muted { "UNMUTE" } else { "MUTE" }))
+                            .on_press(Message::Chat(Chat::ToggleMute))
+                    )
+                    .push(
+                        button(text("EXPORT"))
+                            .on_press(Message::Global(Global::ExportConversation(self.remote_id)))
+                    )
+            )
+            .push_maybe(
+                conversation.zip(self.thread).map(|(conversation, parent)| Row::new()
+                    .push(text(format!("THREAD: {}", self.preview(conversation, parent))).width(Length::Fill))
+                    .push(button(text("BACK")).on_press(Message::Chat(Chat::CloseThread)))
+                )
+            )
             .push(
-                text_input(&format!("Message {:?}", self.remote_id), &self.message_box)
+                match conversation {
+                    // Anchored to the bottom, so the newest message stays in view as packets arrive.
+                    Some(conversation) => Container::new(Scrollable::new(self.view_conversation(conversation))
+                        .id(Self::conversation_id())
+                        .anchor_bottom()
+                        .on_scroll(|viewport| Message::Chat(Chat::Scrolled(viewport)))
+                    ),
+                    None => Container::new(text("LOADING...")).center(Length::Fill)
+                }.width(Length::Fill).height(Length::Fill))
+            .push_maybe(
+                self.editing.map(|_| Row::new()
+                    .push(text("EDITING MESSAGE").width(Length::Fill))
+                    .push(button(text("CANCEL")).on_press(Message::Chat(Chat::CancelEdit)))
+                )
+            )
+            .push_maybe(
+                conversation.zip(self.replying).map(|(conversation, parent)| Row::new()
+                    .push(text(format!("REPLYING TO {}", self.preview(conversation, parent))).width(Length::Fill))
+                    .push(button(text("CANCEL")).on_press(Message::Chat(Chat::CancelReply)))
+                )
+            )
+            .push(
+                text_input(&format!("Message {}", self.title()), &self.message_box)
                     .on_input(|v| Message::Chat(Chat::MessageBox(v)))
                     .on_submit(Message::Chat(Chat::SendMessage))
             ).into()
     }
 
-    fn update(&mut self, message: Message) -> Task<Message> {
-        if let Message::Chat(chat) = message {
-            match chat {
-                Chat::AddPacketToCache(packet) => self.conversation.push(packet),
-                Chat::SetConversation(mut packets) => self.conversation.append(&mut packets),
-                Chat::MessageBox(new_value) => self.message_box = new_value,
-                Chat::SendMessage => return Message::Global(Global::NetworkTask(NetworkTask::SendMessage(
-                    self.remote_id, std::mem::take(&mut self.message_box).into_bytes(), PacketType::String
-                ))).task()
+    fn update(&mut self, message: Message, conversations: &ConversationStore) -> Task<Message> {
+        let Message::Chat(chat) = message else { return Message::None.task() };
+
+        // The application has already applied any new packets to the store; the page only follows along.
+        match chat {
+            // Usernames sent before a rotation still name this contact.
+            Chat::ContactName(node_id, name) => if node_id == self.remote_id
+                || conversations.get(&self.remote_id).is_some_and(|c| c.is_remote(node_id))
+            {
+                self.remote_name = Some(name)
+            },
+            Chat::MessageBox(new_value) => self.message_box = new_value,
+            Chat::IdentityRotated(old, new) => if self.remote_id == old { self.remote_id = new },
+            Chat::SetTimer(seconds) => return Message::Global(Global::NetworkTask(
+                NetworkTask::SetTimer(self.remote_id, seconds)
+            )).task(),
+            Chat::StartReply(id) => {
+                self.replying = Some(id);
+                if self.editing.take().is_some() {
+                    self.message_box.clear();
+                }
+            },
+            Chat::CancelReply => self.replying = None,
+            Chat::ToggleMute => {
+                self.muted = !self.muted;
+                return Message::Global(Global::SetMuted(self.remote_id, self.muted)).task()
+            },
+            Chat::OpenThread(id) => {
+                self.thread = Some(id);
+                self.replying = Some(id);
+            },
+            Chat::CloseThread => {
+                self.thread = None;
+                self.replying = None;
+            },
+            Chat::CancelEdit => {
+                self.editing = None;
+                self.message_box.clear();
+            },
+            Chat::Delete(id) => return Message::Global(Global::NetworkTask(
+                NetworkTask::DeleteMessage(self.remote_id, id)
+            )).task(),
+            Chat::SendMessage => {
+                let content = std::mem::take(&mut self.message_box).into_bytes();
+                // Replies inside an open thread stay in that thread.
+                let replying = if self.thread.is_some() { self.replying } else { self.replying.take() };
+                return Message::Global(Global::NetworkTask(match (self.editing.take(), replying) {
+                    (Some(id), _) => NetworkTask::EditMessage(self.remote_id, id, content),
+                    (None, Some(parent)) => NetworkTask::Reply(self.remote_id, parent, content),
+                    (None, None) => NetworkTask::SendMessage(self.remote_id, content, PacketType::String)
+                })).task()
+            },
+            chat => {
+                let Some(conversation) = conversations.get(&self.remote_id).filter(|c| c.loaded) else {
+                    return Message::None.task()
+                };
+                return self.update_loaded(chat, conversation);
             }
         }
         Message::None.task()
     }
 }
+
+impl ChatPage {
+    /// Messages that need the conversation to have been loaded.
+    fn update_loaded(&mut self, chat: Chat, conversation: &Conversation) -> Task<Message> {
+        match chat {
+            Chat::AddPacketToCache(node_id, packet) if node_id == self.remote_id => {
+                // Sending always returns to the newest message, even when scrolled up through history.
+                if !conversation.is_remote(packet.author) {
+                    self.window_back = 0;
+                    return scrollable::snap_to(Self::conversation_id(), RelativeOffset::START);
+                }
+
+                // Otherwise keep the same rows in view, unless pinned to the bottom.
+                if self.window_back > 0 && matches!(packet.packet_type, PacketType::String | PacketType::Rotation) {
+                    self.window_back += 1;
+                }
+            },
+            Chat::History(node_id, _, _) if node_id == self.remote_id => {
+                // Only pages the user scrolled back for are requested here; the first comes with opening the chat.
+                let first = !self.loading_history;
+                self.loading_history = false;
+
+                if self.focus.is_some() {
+                    return self.seek_focus(conversation);
+                }
+
+                // The user scrolled to the top to get here, so move on into the rows just loaded.
+                if !first {
+                    return self.scroll_older(conversation);
+                }
+            },
+            Chat::Scrolled(viewport) => {
+                let y = viewport.relative_offset().y;
+                if y >= 1.0 - SCROLL_EDGE {
+                    return self.scroll_older(conversation);
+                } else if y <= SCROLL_EDGE {
+                    return self.scroll_newer(conversation);
+                }
+            },
+            Chat::Copy(id) => if let Some(p) = conversation.find(id) {
+                // Rich text cannot be selected, so the raw markup is what gets copied.
+                return clipboard::write(Conversation::body(p));
+            },
+            Chat::React(id, emoji) => {
+                let added = !conversation.reactions.get(&id)
+                    .and_then(|emojis| emojis.get(&emoji))
+                    .is_some_and(|authors| authors.iter().any(|a| !conversation.is_remote(*a)));
+
+                return Message::Global(Global::NetworkTask(
+                    NetworkTask::React(self.remote_id, Reaction { id, emoji, added })
+                )).task()
+            },
+            Chat::StartEdit(id) => {
+                if let Some(Ok(content)) = conversation.find(id).map(|p| p.content.clone()) {
+                    self.message_box = String::from_utf8(content).unwrap_or_default();
+                    self.editing = Some(id);
+                    self.replying = None;
+                }
+            },
+            Chat::JumpTo(id) => return self.jump_to(conversation, id),
+            _ => {}
+        }
+        Message::None.task()
+    }
+}
@@ -0,0 +1,95 @@
+use iced::{clipboard, widget::{button, text, Column, Row, Scrollable}, Element, Length, Task};
+use iroh::{endpoint::ConnectionType, NodeId};
+
+use crate::{frontend::{application::Page, conversation::ConversationStore, message::{Diagnostics, Global, Message}}, networking::{abstraction::NetworkTask, contact::Contact, network::{NodeDiagnostics, PeerDiagnostics}}};
+
+/// Our own address and how each contact is currently reached, refreshed every tick while open.
+pub struct DiagnosticsPage {
+    contacts: Vec<Contact>,
+    report: Option<NodeDiagnostics>
+}
+
+impl DiagnosticsPage {
+    pub fn new(contacts: Vec<Contact>) -> Self {
+        Self { contacts, report: None }
+    }
+
+    fn name(&self, node_id: NodeId) -> String {
+        self.contacts.iter()
+            .find(|c| c.server_address == node_id)
+            .and_then(|c| c.username.clone())
+            .unwrap_or_else(|| node_id.fmt_short().to_string())
+    }
+
+    fn view_peer(&self, peer: &PeerDiagnostics) -> Element<'_, Message> {
+        Column::new()
+            .push(text(self.name(peer.node_id)).size(16))
+            .push(text(format!("TO THEM: {}", path(peer.outgoing.as_ref()))).size(12))
+            .push(text(format!("TO US: {}", path(peer.incoming.as_ref()))).size(12))
+            .push(text(format!(
+                "RTT {} ms · SENT {} · RECEIVED {}",
+                peer.rtt.as_millis(), bytes(peer.sent), bytes(peer.received)
+            )).size(12))
+            .into()
+    }
+}
+
+fn path(conn_type: Option<&ConnectionType>) -> String {
+    match conn_type {
+        Some(ConnectionType::Direct(addr)) => format!("DIRECT ({addr})"),
+        Some(ConnectionType::Relay(url)) => format!("RELAYED ({url})"),
+        Some(ConnectionType::Mixed(addr, url)) => format!("MIXED ({addr}, {url})"),
+        Some(ConnectionType::None) | None => String::from("NOT CONNECTED")
+    }
+}
+
+fn bytes(count: u64) -> String {
+    match count {
+        0..1024 => format!("{count} B"),
+        1024..1_048_576 => format!("{:.1} KiB", count as f64 / 1024.0),
+        _ => format!("{:.1} MiB", count as f64 / 1_048_576.0)
+    }
+}
+
+impl Page for DiagnosticsPage {
+    fn view<'a>(&'a self, _: &'a ConversationStore) -> Element<'a, Message> {
+        let Some(report) = self.report.as_ref() else {
+            return text("LOADING...").into();
+        };
+
+        let addresses = Column::from_iter(report.node_addr.direct_addresses.iter().map(|addr| text(addr.to_string()).into()));
+        let peers = Column::from_iter(report.peers.iter().map(|peer| self.view_peer(peer))).spacing(8);
+
+        Column::new()
+            .spacing(8)
+            .push(text("NODE ID"))
+            .push(
+                Row::new()
+                    .spacing(8)
+                    .push(text(report.node_addr.node_id.to_string()))
+                    .push(button(text("COPY")).on_press(Message::Diagnostics(Diagnostics::CopyNodeId)))
+            )
+            .push(text("HOME RELAY"))
+            .push(text(report.node_addr.relay_url.as_ref().map(|url| url.to_string()).unwrap_or_else(|| String::from("NONE"))))
+            .push(text("DIRECT ADDRESSES"))
+            .push_maybe(report.node_addr.direct_addresses.is_empty().then(|| text("NONE")))
+            .push(addresses)
+            .push(text("PEERS"))
+            .push_maybe(report.peers.is_empty().then(|| text("NO CONNECTED PEERS")))
+            .push(Scrollable::new(peers).height(Length::Fill))
+            .into()
+    }
+
+    fn update(&mut self, message: Message, _: &ConversationStore) -> Task<Message> {
+        if let Message::Diagnostics(message) = message {
+            match message {
+                Diagnostics::Refresh => return Message::Global(Global::NetworkTask(NetworkTask::RequestDiagnostics)).task(),
+                Diagnostics::Report(report) => self.report = Some(report),
+                Diagnostics::CopyNodeId => if let Some(report) = self.report.as_ref() {
+                    return clipboard::write(report.node_addr.node_id.to_string());
+                }
+            }
+        }
+        Message::None.task()
+    }
+}
@@ -1,2 +1,5 @@
 pub mod chat;
 pub mod add;
+pub mod settings;
+pub mod search;
+pub mod diagnostics;
@@ -0,0 +1,221 @@
+use std::fmt::{Display, Formatter};
+
+use chrono::{Local, NaiveDate, NaiveTime, TimeZone};
+use iced::{font, widget::{button, checkbox, pick_list, rich_text, span, text, text::Span, text_input, Column, Row, Scrollable}, Color, Element, Font, Length, Task};
+use iroh::NodeId;
+
+use crate::{backend::database_interface::{SearchHit, SearchQuery, SNIPPET_END, SNIPPET_START}, error::{Error, Res}, frontend::{application::Page, conversation::ConversationStore, message::{Global, Message, Search}}, networking::contact::Contact};
+
+/// Entry in the contact filter, None meaning every conversation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContactFilter(Option<NodeId>, String);
+
+impl Display for ContactFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.1)
+    }
+}
+
+pub struct SearchPage {
+    contacts: Vec<Contact>,
+    query: String,
+    contact: ContactFilter,
+    from_input: String,
+    to_input: String,
+    has_attachment: bool,
+    /// Number of the latest search sent, so replies to older ones can be dropped.
+    sequence: u64,
+    results: Vec<SearchHit>
+}
+
+impl SearchPage {
+    pub fn new(contacts: Vec<Contact>) -> Self {
+        Self {
+            contacts,
+            query: String::default(),
+            contact: ContactFilter(None, String::from("ALL CONTACTS")),
+            from_input: String::default(),
+            to_input: String::default(),
+            has_attachment: false,
+            sequence: 0,
+            results: Vec::default()
+        }
+    }
+
+    fn name(&self, node_id: NodeId) -> String {
+        self.contacts.iter()
+            .find(|c| c.server_address == node_id)
+            .and_then(|c| c.username.clone())
+            .unwrap_or_else(|| node_id.fmt_short().to_string())
+    }
+
+    fn filters(&self) -> Vec<ContactFilter> {
+        std::iter::once(ContactFilter(None, String::from("ALL CONTACTS")))
+            .chain(self.contacts.iter().map(|c| ContactFilter(Some(c.server_address), self.name(c.server_address))))
+            .collect()
+    }
+
+    fn search(&mut self) -> Task<Message> {
+        match self.build_query() {
+            Ok(query) => {
+                self.sequence += 1;
+                Message::Global(Global::Search(self.sequence, query)).task()
+            }
+            Err(e) => Message::Global(Global::Warn(e)).task()
+        }
+    }
+
+    fn build_query(&self) -> Res<SearchQuery> {
+        Ok(SearchQuery {
+            text: self.query.clone(),
+            conversation: self.contact.0,
+            from: parse_date(&self.from_input, NaiveTime::MIN)?,
+            to: parse_date(&self.to_input, NaiveTime::from_hms_opt(23, 59, 59).unwrap_or(NaiveTime::MIN))?,
+            has_attachment: self.has_attachment
+        })
+    }
+
+    fn view_hit(&self, hit: &SearchHit) -> Element<'_, Message> {
+        let when = Local.timestamp_opt(hit.timestamp as i64, 0).single().unwrap_or_default();
+        let author = if hit.author == hit.conversation { self.name(hit.author) } else { String::from("You") };
+
+        button(
+            Column::new()
+                .push(rich_text(highlight(&hit.snippet)))
+                .push(text(format!("{author} · {}", when.format("%Y-%m-%d %H:%M"))).size(11))
+        )
+            .width(Length::Fill)
+            .style(button::text)
+            .on_press(Message::Global(Global::OpenMessage(hit.conversation, hit.message_id)))
+            .into()
+    }
+}
+
+/// Parse a YYYY-MM-DD date in local time, with an empty input meaning no limit.
+fn parse_date(input: &str, time: NaiveTime) -> Res<Option<u64>> {
+    if input.trim().is_empty() { return Ok(None) }
+
+    let date = NaiveDate::parse_from_str(input.trim(), "%Y-%m-%d").map_err(|_| Error::InvalidDate)?;
+    let local = Local.from_local_datetime(&date.and_time(time)).earliest().ok_or(Error::InvalidDate)?;
+    Ok(Some(local.timestamp().max(0) as u64))
+}
+
+/// Split a snippet into spans, making the matched terms stand out.
+fn highlight(snippet: &str) -> Vec<Span<'static, Message, Font>> {
+    let mut parts = snippet.split(SNIPPET_START);
+    let mut spans = vec![span(parts.next().unwrap_or_default().to_string())];
+
+    for part in parts {
+        let (hit, rest) = part.split_once(SNIPPET_END).unwrap_or((part, ""));
+        spans.push(
+            span(hit.to_string())
+                .font(Font { weight: font::Weight::Bold, ..Font::default() })
+                .background(Color::from_rgba(1.0, 0.8, 0.2, 0.35))
+        );
+        spans.push(span(rest.to_string()));
+    }
+    spans
+}
+
+impl Page for SearchPage {
+    fn view<'a>(&'a self, _: &'a ConversationStore) -> Element<'a, Message> {
+        // Results arrive ordered by conversation, so each run of hits forms one group.
+        let mut results = Column::new().spacing(4);
+        for (i, hit) in self.results.iter().enumerate() {
+            if i == 0 || self.results[i - 1].conversation != hit.conversation {
+                let count = self.results.iter().filter(|h| h.conversation == hit.conversation).count();
+                results = results.push(text(format!("{} ({count})", self.name(hit.conversation))).size(18));
+            }
+            results = results.push(self.view_hit(hit));
+        }
+
+        Column::new()
+            .spacing(8)
+            .push(
+                text_input("Search messages", &self.query)
+                    .on_input(|v| Message::Search(Search::QueryInput(v)))
+                    .on_submit(Message::Search(Search::Submit))
+            )
+            .push(
+                Row::new()
+                    .spacing(8)
+                    .push(pick_list(self.filters(), Some(self.contact.clone()), |c| Message::Search(Search::Contact(c))))
+                    .push(
+                        text_input("FROM (YYYY-MM-DD)", &self.from_input)
+                            .on_input(|v| Message::Search(Search::FromInput(v)))
+                            .on_submit(Message::Search(Search::Submit))
+                    )
+                    .push(
+                        text_input("TO (YYYY-MM-DD)", &self.to_input)
+                            .on_input(|v| Message::Search(Search::ToInput(v)))
+                            .on_submit(Message::Search(Search::Submit))
+                    )
+                    .push(checkbox("HAS LINK", self.has_attachment).on_toggle(|v| Message::Search(Search::Attachment(v))))
+            )
+            .push_maybe((self.results.is_empty() && !self.query.trim().is_empty()).then(|| text("NO RESULTS")))
+            .push(Scrollable::new(results).height(Length::Fill))
+            .into()
+    }
+
+    fn update(&mut self, message: Message, _: &ConversationStore) -> Task<Message> {
+        if let Message::Search(search) = message {
+            match search {
+                // Search while typing, as FTS lookups are cheap.
+                Search::QueryInput(new_value) => {
+                    self.query = new_value;
+                    return self.search();
+                }
+                Search::Contact(contact) => {
+                    self.contact = contact;
+                    return self.search();
+                }
+                Search::FromInput(new_value) => self.from_input = new_value,
+                Search::ToInput(new_value) => self.to_input = new_value,
+                Search::Attachment(has_attachment) => {
+                    self.has_attachment = has_attachment;
+                    return self.search();
+                }
+                Search::Submit => return self.search(),
+                // Searches run concurrently while typing, so a slow reply may land after a newer one.
+                Search::Results(sequence, results) => if sequence == self.sequence { self.results = results }
+            }
+        }
+        Message::None.task()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(snippet: &str) -> SearchHit {
+        let node_id = iroh::SecretKey::generate(&mut rand::rngs::OsRng).public();
+        SearchHit { conversation: node_id, author: node_id, timestamp: 0, message_id: [0; 16], snippet: String::from(snippet) }
+    }
+
+    #[test]
+    fn late_replies_are_dropped() {
+        let store = ConversationStore::default();
+        let mut page = SearchPage::new(Vec::new());
+
+        let _ = page.update(Message::Search(Search::QueryInput(String::from("he"))), &store);
+        let _ = page.update(Message::Search(Search::QueryInput(String::from("hello"))), &store);
+        assert_eq!(page.sequence, 2);
+
+        let _ = page.update(Message::Search(Search::Results(2, vec![hit("hello")])), &store);
+        let _ = page.update(Message::Search(Search::Results(1, vec![hit("he"), hit("help")])), &store);
+        assert_eq!(page.results.len(), 1);
+        assert_eq!(page.results[0].snippet, "hello");
+    }
+
+    #[test]
+    fn attachment_filter_is_part_of_the_query() {
+        let store = ConversationStore::default();
+        let mut page = SearchPage::new(Vec::new());
+
+        assert!(!page.build_query().unwrap().has_attachment);
+        let _ = page.update(Message::Search(Search::Attachment(true)), &store);
+        assert!(page.build_query().unwrap().has_attachment);
+        assert_eq!(page.sequence, 1);
+    }
+}
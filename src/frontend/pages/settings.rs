@@ -0,0 +1,505 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use iced::{clipboard, widget::{button, checkbox, pick_list, text, text_input, Column, Row}, Element, Task};
+
+use iroh::NodeId;
+
+use crate::{frontend::{application::Page, conversation::ConversationStore, message::{Global, Message, Settings}, notifications::{Preview, QuietHours}}, networking::{ticket::Ticket, transport::{self, Discovery, RelayFallback, RelayProbe, RelaySetting, Transport}}};
+
+/// Settings that change or reveal the identity, held until the user confirms them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sensitive {
+    RotateIdentity,
+    ShowRecoveryPhrase
+}
+
+pub struct SettingsPage {
+    passphrase_input: String,
+    passphrase_confirm: String,
+
+    // Changing the lock, rotating the identity and showing its phrase all need the current PIN, if one is set.
+    pin_set: bool,
+    current_pin_input: String,
+    pin_input: String,
+    timeout_input: String,
+    preview: Preview,
+    quiet_hours_input: String,
+    export_path_input: String,
+    transport: Transport,
+    relay_input: String,
+    address_book_input: String,
+
+    // Results of the last relay test, or None while it runs.
+    relay_probes: Option<Option<Vec<RelayProbe>>>,
+    recovery_phrase: Option<String>,
+    rotated_to: Option<NodeId>,
+    asking: Option<Sensitive>
+}
+
+impl SettingsPage {
+    pub fn new(preview: Preview, quiet_hours: Option<QuietHours>, transport: Transport, pin_set: bool) -> Self {
+        Self {
+            passphrase_input: String::default(),
+            passphrase_confirm: String::default(),
+            pin_set,
+            current_pin_input: String::default(),
+            pin_input: String::default(),
+            timeout_input: String::default(),
+            preview,
+            quiet_hours_input: quiet_hours.map(|hours| hours.to_string()).unwrap_or_default(),
+            export_path_input: String::default(),
+            relay_input: transport.relay.to_string(),
+            address_book_input: String::default(),
+            relay_probes: None,
+            transport,
+            recovery_phrase: None,
+            rotated_to: None,
+            asking: None
+        }
+    }
+
+    fn view_current_pin(&self) -> Option<Element<'_, Message>> {
+        self.pin_set.then(|| text_input("Current PIN", &self.current_pin_input)
+            .secure(true)
+            .on_input(|v| Message::Settings(Settings::CurrentPinInput(v)))
+            .into())
+    }
+
+    /// The warning for a sensitive setting, asked before it goes ahead.
+    fn view_confirmation(&self, sensitive: Sensitive) -> Element<'_, Message> {
+        let (warning, action) = match sensitive {
+            Sensitive::RotateIdentity => (
+                "Your identity will be replaced by a new key. Contacts are told, but the old recovery phrase stops working.",
+                "ROTATE"
+            ),
+            Sensitive::ShowRecoveryPhrase => (
+                "Anyone who sees the recovery phrase can take over your identity.",
+                "SHOW"
+            )
+        };
+
+        Column::new()
+            .push(text(warning))
+            .push_maybe(self.view_current_pin())
+            .push(
+                Row::new()
+                    .push(button(text(action)).on_press(Message::Settings(Settings::Confirm)))
+                    .push(button(text("CANCEL")).on_press(Message::Settings(Settings::Cancel)))
+            ).into()
+    }
+
+    fn passphrase_confirmed(&self) -> bool {
+        !self.passphrase_input.is_empty() && self.passphrase_input == self.passphrase_confirm
+    }
+
+    fn view_relay_probes(&self) -> Option<Element<'_, Message>> {
+        Some(match self.relay_probes.as_ref()? {
+            None => text("TESTING RELAYS...").size(12).into(),
+            Some(probes) => Column::from_iter(probes.iter().map(|probe| text(match probe.latency {
+                Some(latency) => format!("{}: REACHABLE ({} ms)", probe.url, latency.as_millis()),
+                None => format!("{}: UNREACHABLE", probe.url)
+            }).size(12).into())).into()
+        })
+    }
+
+    fn view_address_book(&self) -> Element<'_, Message> {
+        Column::from_iter(self.transport.address_book.iter().enumerate().map(|(index, ticket)|
+            Row::new()
+                .spacing(8)
+                .push(text(ticket.username.clone().unwrap_or_else(|| ticket.addr.node_id.fmt_short().to_string())))
+                .push(text(format!("{} ADDRESSES", ticket.addr.direct_addresses.len())).size(12))
+                .push(button(text("REMOVE")).on_press(Message::Settings(Settings::RemoveFromAddressBook(index))))
+                .into()
+        )).into()
+    }
+}
+
+impl Page for SettingsPage {
+    fn view<'a>(&'a self, _: &'a ConversationStore) -> Element<'a, Message> {
+        Column::new()
+            .push(text("DATABASE ENCRYPTION"))
+            .push(
+                text_input("New passphrase", &self.passphrase_input)
+                    .secure(true)
+                    .on_input(|v| Message::Settings(Settings::PassphraseInput(v)))
+            )
+            .push(
+                text_input("Repeat new passphrase", &self.passphrase_confirm)
+                    .secure(true)
+                    .on_input(|v| Message::Settings(Settings::PassphraseConfirm(v)))
+                    .on_submit(Message::Settings(Settings::SetPassphrase))
+            )
+            .push_maybe(
+                (!self.passphrase_confirm.is_empty() && self.passphrase_input != self.passphrase_confirm)
+                    .then(|| text("PASSPHRASES DO NOT MATCH"))
+            )
+            .push(
+                Row::new()
+                    .push(
+                        button(text("SET PASSPHRASE"))
+                            .on_press_maybe(
+                                self.passphrase_confirmed().then_some(Message::Settings(Settings::SetPassphrase))
+                            )
+                    )
+                    .push(
+                        button(text("REMOVE ENCRYPTION"))
+                            .on_press(Message::Settings(Settings::RemovePassphrase))
+                    )
+            )
+            .push(text("APP LOCK"))
+            .push_maybe(self.view_current_pin())
+            .push(
+                text_input("PIN", &self.pin_input)
+                    .secure(true)
+                    .on_input(|v| Message::Settings(Settings::PinInput(v)))
+            )
+            .push(
+                text_input("Lock after idle minutes (default 5, at least 1)", &self.timeout_input)
+                    .on_input(|v| Message::Settings(Settings::TimeoutInput(v)))
+                    .on_submit(Message::Settings(Settings::SetLock))
+            )
+            .push(
+                Row::new()
+                    .push(
+                        button(text("SET LOCK"))
+                            .on_press_maybe(
+                                if self.pin_input.is_empty() { None }
+                                else { Some(Message::Settings(Settings::SetLock)) }
+                            )
+                    )
+                    .push(
+                        button(text("REMOVE LOCK"))
+                            .on_press_maybe(self.pin_set.then_some(Message::Settings(Settings::RemoveLock)))
+                    )
+            )
+            .push(text("NOTIFICATIONS"))
+            .push(
+                Row::new()
+                    .push(text("SHOW: "))
+                    .push(pick_list(Preview::ALL, Some(self.preview), |p| Message::Settings(Settings::Preview(p))))
+            )
+            .push(
+                Row::new()
+                    .push(
+                        text_input("Do not disturb hours, e.g. 22-7 (empty for off)", &self.quiet_hours_input)
+                            .on_input(|v| Message::Settings(Settings::QuietHoursInput(v)))
+                            .on_submit(Message::Settings(Settings::SetQuietHours))
+                    )
+                    .push(
+                        button(text("SET HOURS"))
+                            .on_press(Message::Settings(Settings::SetQuietHours))
+                    )
+            )
+            .push(text("EXPORTED HISTORY"))
+            .push(
+                Row::new()
+                    .push(
+                        text_input("Path of an exported conversation (.tsv)", &self.export_path_input)
+                            .on_input(|v| Message::Settings(Settings::ExportPathInput(v)))
+                            .on_submit(Message::Settings(Settings::VerifyExport))
+                    )
+                    .push(
+                        button(text("VERIFY"))
+                            .on_press_maybe(
+                                (!self.export_path_input.trim().is_empty()).then_some(Message::Settings(Settings::VerifyExport))
+                            )
+                    )
+            )
+            .push(text("NETWORK"))
+            .push(
+                Row::new()
+                    .push(text("DISCOVERY: "))
+                    .push(pick_list(Discovery::ALL, Some(self.transport.discovery), |d| Message::Settings(Settings::Discovery(d))))
+            )
+            .push(
+                Row::new()
+                    .push(
+                        text_input("Relays: default, disabled, or relay URLs separated by spaces", &self.relay_input)
+                            .on_input(|v| Message::Settings(Settings::RelayInput(v)))
+                            .on_submit(Message::Settings(Settings::SaveTransport))
+                    )
+                    .push(
+                        button(text("TEST RELAYS"))
+                            .on_press(Message::Settings(Settings::TestRelays))
+                    )
+            )
+            .push_maybe(self.view_relay_probes())
+            .push(
+                Row::new()
+                    .push(text("IF NO RELAY ANSWERS: "))
+                    .push(pick_list(RelayFallback::ALL, Some(self.transport.fallback), |f| Message::Settings(Settings::Fallback(f))))
+            )
+            .push(text("ADDRESS BOOK"))
+            .push(self.view_address_book())
+            .push(
+                Row::new()
+                    .push(
+                        text_input("Paste a ticket to reach that contact without discovery", &self.address_book_input)
+                            .on_input(|v| Message::Settings(Settings::AddressBookInput(v)))
+                            .on_submit(Message::Settings(Settings::AddToAddressBook))
+                    )
+                    .push(
+                        button(text("ADD"))
+                            .on_press(Message::Settings(Settings::AddToAddressBook))
+                    )
+            )
+            .push(
+                checkbox("Look for pingpong users on this network", self.transport.listen)
+                    .on_toggle(|b| Message::Settings(Settings::Listen(b)))
+            )
+            .push(
+                checkbox("Show me to pingpong users on this network", self.transport.broadcast)
+                    .on_toggle(|b| Message::Settings(Settings::Broadcast(b)))
+            )
+            .push(
+                button(text("SAVE NETWORK SETTINGS"))
+                    .on_press(Message::Settings(Settings::SaveTransport))
+            )
+            .push(text("IDENTITY BACKUP"))
+            .push(
+                match (self.recovery_phrase.as_ref(), self.asking) {
+                    (Some(phrase), _) => Column::new()
+                        .push(text(phrase))
+                        .push(
+                            button(text("COPY"))
+                                .on_press(Message::Settings(Settings::CopyRecoveryPhrase))
+                        ).into(),
+                    (None, Some(Sensitive::ShowRecoveryPhrase)) => self.view_confirmation(Sensitive::ShowRecoveryPhrase),
+                    (None, _) => Element::from(
+                        button(text("SHOW RECOVERY PHRASE"))
+                            .on_press(Message::Settings(Settings::Ask(Sensitive::ShowRecoveryPhrase)))
+                    )
+                }
+            )
+            .push(text("KEY ROTATION"))
+            .push(
+                match self.asking {
+                    Some(Sensitive::RotateIdentity) => self.view_confirmation(Sensitive::RotateIdentity),
+                    _ => button(text("ROTATE IDENTITY"))
+                        .on_press(Message::Settings(Settings::Ask(Sensitive::RotateIdentity)))
+                        .into()
+                }
+            )
+            .push_maybe(
+                self.rotated_to.map(|node_id| text(format!("Identity rotated to {node_id}. Back up the new recovery phrase.")))
+            ).into()
+    }
+
+    fn update(&mut self, message: Message, _: &ConversationStore) -> Task<Message> {
+        if let Message::Settings(message) = message {
+            match message {
+                Settings::PassphraseInput(new_value) => {
+                    self.passphrase_input = new_value;
+                    Message::None.task()
+                }
+
+                Settings::PassphraseConfirm(new_value) => {
+                    self.passphrase_confirm = new_value;
+                    Message::None.task()
+                }
+
+                Settings::SetPassphrase => {
+                    // A mistyped passphrase would leave the database unreadable, so it must be entered twice.
+                    if !self.passphrase_confirmed() { return Message::None.task(); }
+                    self.passphrase_confirm.clear();
+                    let passphrase = std::mem::take(&mut self.passphrase_input);
+                    Message::Global(Global::SetPassphrase(Some(passphrase))).task()
+                }
+
+                Settings::RemovePassphrase => Message::Global(Global::SetPassphrase(None)).task(),
+
+                Settings::PinInput(new_value) => {
+                    self.pin_input = new_value;
+                    Message::None.task()
+                }
+
+                Settings::TimeoutInput(new_value) => {
+                    self.timeout_input = new_value;
+                    Message::None.task()
+                }
+
+                Settings::CurrentPinInput(new_value) => {
+                    self.current_pin_input = new_value;
+                    Message::None.task()
+                }
+
+                Settings::SetLock => {
+                    let pin = std::mem::take(&mut self.pin_input);
+                    if pin.is_empty() { return Message::None.task(); }
+                    let minutes = std::mem::take(&mut self.timeout_input).trim().parse::<u64>().ok();
+                    let current = std::mem::take(&mut self.current_pin_input);
+                    Message::Global(Global::SetLock(current, Some(pin), minutes)).task()
+                }
+
+                Settings::RemoveLock => {
+                    let current = std::mem::take(&mut self.current_pin_input);
+                    Message::Global(Global::SetLock(current, None, None)).task()
+                }
+
+                Settings::LockChanged(pin_set) => {
+                    self.pin_set = pin_set;
+                    Message::None.task()
+                }
+
+                Settings::Ask(sensitive) => {
+                    self.asking = Some(sensitive);
+                    Message::None.task()
+                }
+
+                Settings::Confirm => {
+                    let current = std::mem::take(&mut self.current_pin_input);
+                    match self.asking.take() {
+                        Some(Sensitive::RotateIdentity) => Message::Global(Global::RotateIdentity(current)).task(),
+                        Some(Sensitive::ShowRecoveryPhrase) => Message::Global(Global::ExportIdentity(current)).task(),
+                        None => Message::None.task()
+                    }
+                }
+
+                Settings::Cancel => {
+                    self.asking = None;
+                    self.current_pin_input.clear();
+                    Message::None.task()
+                }
+
+                Settings::Preview(preview) => {
+                    self.preview = preview;
+                    Message::Global(Global::SetPreview(preview)).task()
+                }
+
+                Settings::QuietHoursInput(new_value) => {
+                    self.quiet_hours_input = new_value;
+                    Message::None.task()
+                }
+
+                Settings::SetQuietHours => Message::Global(Global::SetQuietHours(self.quiet_hours_input.clone())).task(),
+
+                Settings::ExportPathInput(new_value) => {
+                    self.export_path_input = new_value;
+                    Message::None.task()
+                }
+
+                Settings::VerifyExport => match self.export_path_input.trim() {
+                    "" => Message::None.task(),
+                    path => Message::Global(Global::VerifyExport(PathBuf::from(path))).task()
+                },
+
+                Settings::Discovery(discovery) => {
+                    self.transport.discovery = discovery;
+                    Message::None.task()
+                }
+
+                Settings::RelayInput(new_value) => {
+                    self.relay_input = new_value;
+                    Message::None.task()
+                }
+
+                Settings::AddressBookInput(new_value) => {
+                    self.address_book_input = new_value;
+                    Message::None.task()
+                }
+
+                Settings::AddToAddressBook => {
+                    // Any one-time secret stays in the entry, as the signature covers it. Only the address is used.
+                    let ticket = match Ticket::from_str(&self.address_book_input) {
+                        Ok(ticket) => ticket,
+                        Err(e) => return Message::Global(Global::Warn(e)).task()
+                    };
+
+                    self.transport.address_book.retain(|entry| entry.addr.node_id != ticket.addr.node_id);
+                    self.transport.address_book.push(ticket);
+                    self.address_book_input.clear();
+                    Message::None.task()
+                }
+
+                Settings::RemoveFromAddressBook(index) => {
+                    if index < self.transport.address_book.len() { self.transport.address_book.remove(index); }
+                    Message::None.task()
+                }
+
+                Settings::Broadcast(broadcast) => {
+                    self.transport.broadcast = broadcast;
+                    Message::None.task()
+                }
+
+                Settings::Listen(listen) => {
+                    self.transport.listen = listen;
+                    Message::None.task()
+                }
+
+                Settings::Fallback(fallback) => {
+                    self.transport.fallback = fallback;
+                    Message::None.task()
+                }
+
+                Settings::TestRelays => match RelaySetting::parse(&self.relay_input) {
+                    Ok(RelaySetting::Custom(urls)) => {
+                        self.relay_probes = Some(None);
+                        Task::perform(transport::probe_relays(urls), |probes| Message::Settings(Settings::RelayProbes(probes)))
+                    }
+                    // Only self-hosted relays are tested. The n0 relays are checked by iroh itself.
+                    Ok(_) => Message::None.task(),
+                    Err(e) => Message::Global(Global::Warn(e)).task()
+                },
+
+                Settings::RelayProbes(probes) => {
+                    self.relay_probes = Some(Some(probes));
+                    Message::None.task()
+                }
+
+                Settings::SaveTransport => match RelaySetting::parse(&self.relay_input) {
+                    Ok(relay) => {
+                        self.transport.relay = relay;
+                        Message::Global(Global::SetTransport(self.transport.clone())).task()
+                    }
+                    Err(e) => Message::Global(Global::Warn(e)).task()
+                },
+
+                Settings::RecoveryPhrase(phrase) => {
+                    self.recovery_phrase = Some(phrase);
+                    Message::None.task()
+                }
+
+                Settings::CopyRecoveryPhrase => match self.recovery_phrase.clone() {
+                    Some(phrase) => clipboard::write(phrase),
+                    None => Message::None.task()
+                },
+
+                Settings::IdentityRotated(node_id) => {
+                    // Any phrase on screen belongs to the retired key.
+                    self.recovery_phrase = None;
+                    self.rotated_to = Some(node_id);
+                    Message::None.task()
+                }
+            }
+        } else {
+            Message::None.task()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensitive_settings_wait_for_confirmation() {
+        let store = ConversationStore::default();
+        let mut page = SettingsPage::new(Preview::default(), None, Transport::default(), true);
+
+        let _ = page.update(Message::Settings(Settings::Ask(Sensitive::RotateIdentity)), &store);
+        let _ = page.update(Message::Settings(Settings::CurrentPinInput(String::from("1234"))), &store);
+        let _ = page.update(Message::Settings(Settings::Cancel), &store);
+        assert_eq!(page.asking, None);
+        assert!(page.current_pin_input.is_empty());
+
+        let _ = page.update(Message::Settings(Settings::Ask(Sensitive::ShowRecoveryPhrase)), &store);
+        assert_eq!(page.asking, Some(Sensitive::ShowRecoveryPhrase));
+        let _ = page.update(Message::Settings(Settings::CurrentPinInput(String::from("1234"))), &store);
+        let _ = page.update(Message::Settings(Settings::Confirm), &store);
+
+        // The PIN is handed on with the request and not kept on the page.
+        assert_eq!(page.asking, None);
+        assert!(page.current_pin_input.is_empty());
+    }
+}
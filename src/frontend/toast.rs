@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iced::widget::{button, container, text, Column, Container};
+use iced::{Element, Length};
+use iroh::NodeId;
+
+use crate::{error::Res, frontend::{message::{Global, Message, PageType}, notifications::{Notification, NotificationBackend}}};
+
+/// How long a toast stays on screen.
+const TOAST_LIFETIME: Duration = Duration::from_secs(5);
+
+/// The oldest toast is dropped once this many are showing.
+const MAX_TOASTS: usize = 4;
+
+const TOAST_WIDTH: f32 = 320.0;
+
+struct Toast {
+    title: String,
+    body: String,
+    contact: Option<NodeId>,
+    shown: Instant
+}
+
+/// Short-lived messages stacked in the corner of the window. Clones are handles onto the same stack,
+/// which is how the in-app notification backend reaches it.
+#[derive(Clone, Default)]
+pub struct Toasts {
+    toasts: Arc<Mutex<VecDeque<Toast>>>
+}
+
+impl Toasts {
+    /// Show a toast, which opens the contact's chat when clicked if one is given.
+    pub fn push(&self, title: String, body: String, contact: Option<NodeId>) {
+        if let Ok(mut toasts) = self.toasts.lock() {
+            if toasts.len() >= MAX_TOASTS { toasts.pop_front(); }
+            toasts.push_back(Toast { title, body, contact, shown: Instant::now() });
+        }
+    }
+
+    /// Drop toasts that have been up for their lifetime.
+    pub fn tick(&self) {
+        if let Ok(mut toasts) = self.toasts.lock() {
+            toasts.retain(|toast| toast.shown.elapsed() < TOAST_LIFETIME);
+        }
+    }
+
+    pub fn view(&self) -> Element<'static, Message> {
+        let Ok(toasts) = self.toasts.lock() else { return Column::new().into() };
+
+        let stack = Column::from_iter(toasts.iter().map(|toast| {
+            button(
+                Column::new()
+                    .push(text(toast.title.clone()).size(14))
+                    .push(text(toast.body.clone()).size(12))
+            )
+                .width(Length::Fill)
+                .style(button::text)
+                .on_press_maybe(toast.contact.map(|c| Message::Global(Global::Load(PageType::Chat(c)))))
+                .into()
+        }).map(|toast: Element<Message>| Container::new(toast)
+            .width(TOAST_WIDTH)
+            .style(container::rounded_box)
+            .into()
+        )).spacing(8);
+
+        Container::new(stack)
+            .padding(16)
+            .align_right(Length::Fill)
+            .align_bottom(Length::Fill)
+            .into()
+    }
+}
+
+impl NotificationBackend for Toasts {
+    fn show(&mut self, notification: &Notification) -> Res<()> {
+        self.push(notification.title.clone(), notification.body.clone(), Some(notification.contact));
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use tracing::warn;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Filter directives in EnvFilter syntax, e.g. PINGPONG_LOG=debug or PINGPONG_LOG=info,pingpong::networking=trace.
+const LEVEL_VARIABLE: &str = "PINGPONG_LOG";
+const DEFAULT_LEVEL: &str = "info,iroh=warn";
+
+/// Message content is only written to the logs when this is set to 1. Log files are not encrypted, so release
+/// builds ignore it.
+const CONTENT_VARIABLE: &str = "PINGPONG_LOG_CONTENT";
+
+const LOG_FOLDER: &str = "logs";
+const KEPT_LOG_FILES: usize = 7;
+
+static LOG_CONTENT: OnceLock<bool> = OnceLock::new();
+
+/// Log to stderr and to a daily rotating file inside the data directory. The returned guard flushes the file
+/// on drop, so it has to be held for as long as the app runs.
+pub fn init(root: &Path) -> Option<WorkerGuard> {
+    let requested = std::env::var(CONTENT_VARIABLE).ok();
+    let content = *LOG_CONTENT.get_or_init(|| content_allowed(requested.as_deref()));
+    let filter = EnvFilter::try_from_env(LEVEL_VARIABLE).unwrap_or_else(|_| EnvFilter::new(DEFAULT_LEVEL));
+
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("pingpong")
+        .filename_suffix("log")
+        .max_log_files(KEPT_LOG_FILES)
+        .build(root.join(LOG_FOLDER))
+        .ok();
+
+    let (file_layer, guard) = match appender.map(tracing_appender::non_blocking) {
+        Some((writer, guard)) => (Some(fmt::layer().with_ansi(false).with_writer(writer)), Some(guard)),
+        None => (None, None)
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(file_layer)
+        .init();
+
+    if content {
+        warn!("{CONTENT_VARIABLE} is set, message text is being written to the unencrypted log files");
+    } else if requested.is_some() {
+        warn!("{CONTENT_VARIABLE} is ignored by release builds");
+    }
+    guard
+}
+
+fn content_allowed(requested: Option<&str>) -> bool {
+    cfg!(debug_assertions) && requested == Some("1")
+}
+
+/// Whether message content is being written to the logs, which the user has to be told about.
+pub fn logs_content() -> bool {
+    LOG_CONTENT.get().copied().unwrap_or(false)
+}
+
+/// Message content as it may appear in the logs: just its length, unless content logging is enabled.
+pub fn redact(content: &[u8]) -> String {
+    match logs_content() {
+        true => String::from_utf8_lossy(content).into_owned(),
+        false => format!("<{} bytes redacted>", content.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_is_redacted_unless_requested() {
+        assert_eq!(redact(b"secret plans"), "<12 bytes redacted>");
+
+        assert!(!content_allowed(None));
+        assert!(!content_allowed(Some("0")));
+        assert!(!content_allowed(Some("true")));
+        assert_eq!(content_allowed(Some("1")), cfg!(debug_assertions));
+    }
+}
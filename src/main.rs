@@ -1,3 +1,4 @@
+use backend::directory::Directory;
 use frontend::{application::Application, message::{Global, Message}};
 use iced::Task;
 
@@ -5,11 +6,16 @@ mod networking;
 mod frontend;
 mod backend;
 mod error;
+mod logging;
 
 fn main() -> iced::Result {
+    let root = Directory::create_or_load().expect("[FATAL] Cannot proceed without creating directory, which failed.");
+    let _log_guard = logging::init(root.get_ref());
+
     iced::application("Pingpong", Application::update, Application::view)
-        .run_with(|| (
-                Application::default(),
+        .subscription(Application::subscription)
+        .run_with(move || (
+                Application::new(root),
                 Task::batch(vec![
                     Message::Global(Global::StartNetworkRelays).task(),
                     Message::Global(Global::LoadContacts).task()
@@ -1,17 +1,28 @@
 use std::collections::HashMap;
-use std::str::FromStr;
 
 use async_channel::{Receiver, Sender};
-use iroh::NodeId;
+use iroh::{NodeAddr, NodeId, SecretKey};
+use rand::rngs::OsRng;
 use tokio::time::sleep;
 use tokio::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
 
 use crate::backend::database::DataLink;
+use crate::backend::database_interface::DatabaseInterface;
 use crate::error::{Error, Res};
 use crate::networking::network::ForeignNodeContact;
 use crate::networking::packet::Packet;
-use crate::networking::network::Server;
+use crate::networking::network::{Nearby, NodeDiagnostics, PeerDiagnostics, Server};
 use crate::networking::packet::PacketType;
+use crate::networking::packet::AddressClaim;
+use crate::networking::packet::RotationStatement;
+use crate::networking::packet::SignedMessage;
+use crate::networking::packet::MessageId;
+use crate::networking::packet::Reaction;
+use crate::networking::packet::timestamp;
+use crate::networking::ticket::{Invite, InviteSecret, Ticket};
+use crate::networking::transport::{RelaySetting, Transport};
 
 use super::contact::Contact;
 
@@ -24,40 +35,145 @@ pub struct Network {
     conversations: HashMap<NodeId, ForeignNode>,
     client_to_server: HashMap<NodeId, NodeId>,
     incoming: Server,
-    username: Option<String>
+    username: Option<String>,
+    transport: Transport,
+
+    // Disappearing message timers in seconds, kept for every conversation that has one, connected or not.
+    timers: HashMap<NodeId, u64>,
+    expiry: ExpirySchedule
 }
 
+/// When the oldest message of each conversation with a timer expires, so the database is only asked to delete
+/// anything once something is due. A conversation missing here is checked on the next sweep, and one holding
+/// None has nothing stored that could expire.
+#[derive(Default)]
+struct ExpirySchedule(HashMap<NodeId, Option<u64>>);
+
+impl ExpirySchedule {
+    fn is_due(&self, conversation: &NodeId, now: u64) -> bool {
+        self.0.get(conversation).is_none_or(|due| due.is_some_and(|due| due <= now))
+    }
+
+    /// Schedule from the oldest message still stored in the conversation.
+    fn set(&mut self, conversation: NodeId, oldest: Option<u64>, seconds: u64) {
+        self.0.insert(conversation, oldest.map(|oldest| oldest.saturating_add(seconds)));
+    }
+
+    /// Bring the expiry forward if a newly stored message, which may carry an old timestamp, expires sooner.
+    fn stored(&mut self, conversation: NodeId, timestamp: u64, seconds: Option<u64>) {
+        if let (Some(seconds), Some(due)) = (seconds, self.0.get_mut(&conversation)) {
+            let expires = timestamp.saturating_add(seconds);
+            *due = Some(due.map_or(expires, |due| due.min(expires)));
+        }
+    }
+
+    /// Check the conversation again on the next sweep, after its timer changed or it moved to a new key.
+    fn forget(&mut self, conversation: &NodeId) {
+        self.0.remove(conversation);
+    }
+}
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many messages are loaded from the database at a time.
+const HISTORY_PAGE: usize = 100;
+
 #[derive(Debug, Clone)]
 pub enum NetworkTask {
     RequestConversation(NodeId),
+    RequestHistory(NodeId, usize),
     SendMessage(NodeId, Vec<u8>, PacketType),
     SetUsername(String),
-    Connect(NodeId)
+    // Connect to a contact, presenting the one-time secret from their invite if there was one. The contact, if
+    // given, is saved once connected.
+    Connect(NodeAddr, Option<InviteSecret>, Option<Contact>),
+    CreateInvite(bool),
+    RotateIdentity,
+    SetTimer(NodeId, Option<u64>),
+    EditMessage(NodeId, MessageId, Vec<u8>),
+    DeleteMessage(NodeId, MessageId),
+    Reply(NodeId, MessageId, Vec<u8>),
+    React(NodeId, Reaction),
+    RequestDiagnostics
+}
+
+impl NetworkTask {
+    /// Name and peer of the task for logging, leaving out any message content.
+    fn describe(&self) -> (&'static str, Option<NodeId>) {
+        match self {
+            Self::RequestConversation(node_id) => ("request_conversation", Some(*node_id)),
+            Self::RequestHistory(node_id, _) => ("request_history", Some(*node_id)),
+            Self::SendMessage(node_id, _, _) => ("send_message", Some(*node_id)),
+            Self::SetUsername(_) => ("set_username", None),
+            Self::Connect(addr, _, _) => ("connect", Some(addr.node_id)),
+            Self::CreateInvite(_) => ("create_invite", None),
+            Self::RotateIdentity => ("rotate_identity", None),
+            Self::SetTimer(node_id, _) => ("set_timer", Some(*node_id)),
+            Self::EditMessage(node_id, _, _) => ("edit_message", Some(*node_id)),
+            Self::DeleteMessage(node_id, _) => ("delete_message", Some(*node_id)),
+            Self::Reply(node_id, _, _) => ("reply", Some(*node_id)),
+            Self::React(node_id, _) => ("react", Some(*node_id)),
+            Self::RequestDiagnostics => ("request_diagnostics", None)
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum NetworkOutput {
-    AddPacket(Packet),
+    // A packet to show in the conversation with the given contact.
+    AddPacket(NodeId, Packet),
     NonFatalError(Error),
-    ConversationRecord(Vec<Packet>),
+    // A page of history, oldest first, with the cursor for the page before it if there may be more.
+    ConversationRecord(NodeId, Vec<Packet>, Option<usize>),
     AddChat(Contact),
-    ContactName(NodeId, String)
+    ContactName(NodeId, String),
+    IdentityRotated(NodeId, NodeId),
+    OwnIdentityRotated(NodeId),
+    TimerChanged(NodeId, Option<u64>),
+    MessagesExpired(NodeId, u64),
+
+    // Reactions in a conversation, as (author, reaction) pairs to apply in order.
+    Reactions(NodeId, Vec<(NodeId, Reaction)>),
+    Diagnostics(NodeDiagnostics),
+    Invite(Ticket),
+    // Someone used one of our one-time invites, and should be saved as a contact.
+    InviteRedeemed(Contact),
+    // Connected to someone the user added, who should be saved as a contact.
+    ContactConnected(Contact),
+    Nearby(Nearby)
 }
 
-pub async fn run_network(tasks: Receiver<NetworkTask>, output: Sender<NetworkOutput>, db: DataLink, username: Option<String>) -> Res<()> {
+pub async fn run_network(tasks: Receiver<NetworkTask>, output: Sender<NetworkOutput>, db: DataLink, username: Option<String>, transport: Transport) -> Res<()> {
+
+    let identity = DatabaseInterface::select_identity(db.clone()).await?;
+    let server: Server = Server::spawn(identity, &transport).await?;
 
-    let server: Server = Server::spawn(db.clone()).await?;
+    // Self-hosted relays are probed in the background, so one that is down does not hold up startup.
+    // Endpoints made in the meantime try every configured relay.
+    let mut relay_probe = Some(tokio::spawn(transport.clone().resolve()));
     let mut network: Network = Network {
         conversations: HashMap::new(),
         client_to_server: HashMap::new(),
         incoming: server,
-        username
+        username,
+        transport,
+        timers: DatabaseInterface::select_timers(db.clone()).await,
+        expiry: ExpirySchedule::default()
     };
 
-    println!("NODE_ID: {}", network.incoming.get_address().node_id);
+    info!(
+        node_id = %network.incoming.get_address().node_id,
+        discovery = network.transport.discovery.key(),
+        relay = %network.transport.relay,
+        "network started"
+    );
 
-    let message_receiver: Receiver<Packet> = network.yield_receiver();
+    network.incoming.announce(network.username.as_deref());
+
+    let mut message_receiver: Receiver<Packet> = network.yield_receiver();
+    let mut nearby_receiver: Receiver<Nearby> = network.incoming.yield_nearby();
     let mut cycle_output: Vec<NetworkOutput> = Vec::new();
+    let mut last_sweep = Instant::now();
 
     if let Some(username) = network.username.as_ref() {
         for mutable_value in network.conversations.values_mut() {
@@ -67,56 +183,67 @@ pub async fn run_network(tasks: Receiver<NetworkTask>, output: Sender<NetworkOut
 
     loop {
         // First, check if there are any new messages. If there was a new client that failed to respond appropriately, emit an error.
-        while let Ok(mut incoming) = message_receiver.try_recv() {
+        while let Ok(incoming) = message_receiver.try_recv() {
 
             // Parse the incoming message and tell the application to track the new chat if it exists.
-            match network.add_message(incoming.clone(), &db).await {
+            match network.add_message(incoming, &db).await {
+                Ok(Some(NetworkOutput::IdentityRotated(old, new))) => {
+                    cycle_output.push(NetworkOutput::IdentityRotated(old, new));
+                    if let Some(notice) = network.conversations.get(&new).and_then(|node| node.conversation.last()) {
+                        cycle_output.push(NetworkOutput::AddPacket(new, notice.clone()));
+                    }
+                }
                 Ok(Some(message)) => cycle_output.push(message),
                 Ok(None) => {},
                 Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
             }
+        }
 
-            if incoming.packet_type == PacketType::String {
-                if let Some(author) = network.client_to_server.get(&incoming.author) {
-                    incoming.author = *author;
-                    cycle_output.push(NetworkOutput::AddPacket(incoming));
+        if relay_probe.as_ref().is_some_and(|probe| probe.is_finished())
+            && let Some(probe) = relay_probe.take()
+            && let Ok((resolved, errors)) = probe.await
+        {
+            cycle_output.extend(errors.into_iter().map(NetworkOutput::NonFatalError));
+            match network.use_relays(resolved, &db).await {
+                Ok(true) => {
+                    message_receiver = network.yield_receiver();
+                    nearby_receiver = network.incoming.yield_nearby();
                 }
+                Ok(false) => {},
+                Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
             }
         }
 
+        // Pass on pingpong users appearing and disappearing on the local network.
+        while let Ok(nearby) = nearby_receiver.try_recv() {
+            cycle_output.push(NetworkOutput::Nearby(nearby));
+        }
+
         // Second, parse any tasks that have been assigned to the network thread.
         while let Ok(task) = tasks.try_recv() {
+            let (name, peer) = task.describe();
+            debug!(task = name, peer = ?peer, "network task");
             match task {
                 NetworkTask::RequestConversation(node_id) => {
-                    if let Some(client) = network.conversations.get(&node_id) {
-                        cycle_output.push(
-                            NetworkOutput::ConversationRecord(client.conversation.clone())
-                        )
-                    } else {
-                        cycle_output.push(
-                            NetworkOutput::NonFatalError(Error::NoSuchClient)
-                        )
+                    cycle_output.extend(load_history(&db, node_id, None).await);
+                    cycle_output.push(NetworkOutput::TimerChanged(node_id, network.timers.get(&node_id).copied()));
+                    match DatabaseInterface::select_reactions(db.clone(), node_id).await {
+                        Ok(reactions) => cycle_output.push(NetworkOutput::Reactions(node_id, reactions)),
+                        Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
                     }
                 }
 
-                NetworkTask::SendMessage(target, packet, packet_type) => {
-
-                    match network.send_message(target, packet.clone(), packet_type, &db).await {
-                        Ok(potential_new_node) => {
+                NetworkTask::RequestHistory(node_id, before) => {
+                    cycle_output.extend(load_history(&db, node_id, Some(before)).await);
+                }
 
-                            // Firstly, check if we need to add a new contact. This should not happen.
-                            if let Some(new_contact) = potential_new_node {
-                                cycle_output.push(NetworkOutput::AddChat(Contact::from_node_id(new_contact)));
-                            }
+                NetworkTask::RequestDiagnostics => cycle_output.push(NetworkOutput::Diagnostics(network.diagnostics())),
 
-                            // Second, add our own message onto the conversation stack mirrored in application.
-                            cycle_output.push(NetworkOutput::AddPacket(Packet {
-                                author: network.incoming.get_address().node_id,
-                                content: Ok(packet),
-                                packet_type
-                            }))
+                NetworkTask::SendMessage(target, packet, packet_type) => {
 
-                        }
+                    // Add our own message onto the conversation stack mirrored in application.
+                    match network.send_message(target, packet, packet_type, &db).await {
+                        Ok(packet) => cycle_output.push(NetworkOutput::AddPacket(target, packet)),
                         Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
                     }
                 }
@@ -125,17 +252,81 @@ pub async fn run_network(tasks: Receiver<NetworkTask>, output: Sender<NetworkOut
                     for mutable_value in network.conversations.values_mut() {
                         let _ = mutable_value.send_client.send(username.as_bytes().to_vec(), PacketType::Username).await;
                     }
+                    network.incoming.announce(Some(&username));
                     network.username = Some(username);
                 }
 
-                NetworkTask::Connect(node_id) => {
-                    if let Some(output) = network.connect(node_id).await {
-                        cycle_output.push(output);
+                NetworkTask::Connect(addr, secret, save) => {
+                    match network.connect(addr, secret, save, &db).await {
+                        Ok(Some(output)) => cycle_output.push(output),
+                        Ok(None) => {}
+                        Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
+                    }
+                }
+
+                NetworkTask::CreateInvite(one_time) => {
+                    let secret = one_time.then(rand::random::<InviteSecret>);
+                    if let Some(secret) = secret {
+                        DatabaseInterface::insert_invite(db.clone(), secret);
+                    }
+                    cycle_output.push(NetworkOutput::Invite(network.ticket(secret)));
+                }
+
+                NetworkTask::Reply(target, parent, content) => {
+                    match network.send_signed(target, rand::random(), Some(parent), content, PacketType::String, &db).await {
+                        Ok(packet) => cycle_output.push(NetworkOutput::AddPacket(target, packet)),
+                        Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
+                    }
+                }
+
+                NetworkTask::React(target, reaction) => {
+                    match network.react(target, reaction, &db).await {
+                        Ok(output) => cycle_output.push(output),
+                        Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
+                    }
+                }
+
+                NetworkTask::EditMessage(target, id, content) => {
+                    match network.amend_message(target, id, content, PacketType::Edit, &db).await {
+                        Ok(packet) => cycle_output.push(NetworkOutput::AddPacket(target, packet)),
+                        Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
+                    }
+                }
+
+                NetworkTask::DeleteMessage(target, id) => {
+                    match network.amend_message(target, id, Vec::new(), PacketType::Delete, &db).await {
+                        Ok(packet) => cycle_output.push(NetworkOutput::AddPacket(target, packet)),
+                        Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
+                    }
+                }
+
+                NetworkTask::SetTimer(node_id, seconds) => {
+                    match network.set_timer(node_id, seconds, &db).await {
+                        Ok(_) => cycle_output.push(NetworkOutput::TimerChanged(node_id, seconds)),
+                        Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
+                    }
+                }
+
+                NetworkTask::RotateIdentity => {
+                    match network.rotate_identity(&db).await {
+                        Ok(new) => {
+                            // The old server has been replaced, so listen to the new one instead.
+                            message_receiver = network.yield_receiver();
+                            nearby_receiver = network.incoming.yield_nearby();
+                            cycle_output.push(NetworkOutput::OwnIdentityRotated(new));
+                        }
+                        Err(e) => cycle_output.push(NetworkOutput::NonFatalError(e))
                     }
                 }
             }
         }
 
+        // Third, delete any messages that have outlived their conversation's timer.
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            last_sweep = Instant::now();
+            cycle_output.extend(network.sweep_expired(&db).await);
+        }
+
         // Finally output anything stored in the cycle list.
         for o in std::mem::take(&mut cycle_output) {
             if output.send(o).await.is_err() {
@@ -148,46 +339,195 @@ pub async fn run_network(tasks: Receiver<NetworkTask>, output: Sender<NetworkOut
     }
 }
 
+/// Load one page of history from the database, which survives restarts and offline contacts.
+async fn load_history(db: &DataLink, node_id: NodeId, before: Option<usize>) -> Vec<NetworkOutput> {
+    match DatabaseInterface::select_conversation_page(db.clone(), node_id, before, HISTORY_PAGE).await {
+        Ok((packets, rejected, cursor)) => {
+            let mut output = Vec::new();
+            if rejected > 0 {
+                output.push(NetworkOutput::NonFatalError(Error::InvalidSignature));
+            }
+            output.push(NetworkOutput::ConversationRecord(node_id, packets, cursor));
+            output
+        }
+        Err(e) => vec![NetworkOutput::NonFatalError(e)]
+    }
+}
+
+/// Timer packets carry the timer in seconds, with zero meaning disabled.
+fn decode_timer(content: &[u8]) -> Res<Option<u64>> {
+    let bytes: [u8; 8] = content.try_into().map_err(|_| Error::MalformedPacket)?;
+    Ok(Some(u64::from_be_bytes(bytes)).filter(|seconds| *seconds > 0))
+}
+
+/// Send a contact every statement of our rotations it has not received yet, oldest first, so it can follow
+/// us onto our current identity. A statement stays queued until it has been sent.
+async fn deliver_rotations(contact: &mut ForeignNodeContact, node_id: NodeId, db: &DataLink) {
+    let pending = match DatabaseInterface::select_pending_rotations(db.clone(), node_id).await {
+        Ok(pending) => pending,
+        Err(e) => {
+            warn!(peer = %node_id, error = %e, "could not load pending rotations");
+            return;
+        }
+    };
+
+    for (id, statement) in pending {
+        if contact.send(statement, PacketType::Rotation).await.is_err() { return; }
+        DatabaseInterface::delete_pending_rotation(db.clone(), id);
+    }
+}
+
 impl Network {
 
+    /// Address packet payload proving that this client endpoint belongs to our server identity.
+    fn address_claim(&self, contact: &ForeignNodeContact) -> Vec<u8> {
+        AddressClaim::new(self.incoming.identity(), contact.node_id()).to_bytes()
+    }
+
     /// Yield a receiver that receives all messages. The implementation is responsible for adding this into the conversation synchronously.
     pub fn yield_receiver(&self) -> Receiver<Packet> {
         self.incoming.yield_receiver()
     }
 
-    pub async fn connect(&mut self, id: NodeId) -> Option<NetworkOutput> {
+    /// Our address and the state of the connections to and from every contact.
+    pub fn diagnostics(&self) -> NodeDiagnostics {
+        let peers = self.conversations.iter().map(|(node_id, node)| {
+            let incoming = self.client_to_server.iter()
+                .find(|(_, server)| *server == node_id)
+                .and_then(|(client, _)| self.incoming.incoming(*client));
+
+            PeerDiagnostics {
+                node_id: *node_id,
+                outgoing: node.send_client.conn_type(),
+                incoming: incoming.as_ref().and_then(|(conn_type, _)| conn_type.clone()),
+                rtt: node.send_client.rtt(),
+                sent: node.send_client.bytes_sent(),
+                received: incoming.map(|(_, received)| received).unwrap_or(0)
+            }
+        }).collect();
+
+        NodeDiagnostics { node_addr: self.incoming.current_address(), peers }
+    }
 
-        if let std::collections::hash_map::Entry::Vacant(e) = self.conversations.entry(id) {
+    /// A ticket for reaching us at our current address.
+    pub fn ticket(&self, secret: Option<InviteSecret>) -> Ticket {
+        Ticket::new(self.incoming.identity(), self.incoming.current_address(), self.username.clone(), secret)
+    }
 
-            let mut contact = ForeignNodeContact::client(id).await.ok()?;
+    pub async fn connect(&mut self, addr: NodeAddr, secret: Option<InviteSecret>, save: Option<Contact>, db: &DataLink) -> Res<Option<NetworkOutput>> {
+        let id = addr.node_id;
+        if self.conversations.contains_key(&id) { return Ok(save.map(NetworkOutput::ContactConnected)); }
 
-            let _ = contact.send(self.incoming.get_address().node_id.to_string().into_bytes(), PacketType::Address).await;
-            if let Some(username) = self.username.as_ref() {
-                 let _ = contact.send(username.as_bytes().to_vec(), PacketType::Username).await;
-            }
+        let mut contact = self.open_client(addr, db).await
+            .inspect_err(|e| warn!(peer = %id, error = %e, "could not connect to contact"))?;
 
-            e.insert(ForeignNode {
-                send_client: contact,
-                conversation: Vec::new()
-            });
+        if let Some(secret) = secret {
+            let invite = Invite { secret, ticket: self.ticket(None) };
+            let _ = contact.send(invite.to_bytes(), PacketType::Invite).await;
+        }
+
+        self.conversations.insert(id, ForeignNode {
+            send_client: contact,
+            conversation: Vec::new()
+        });
+
+        Ok(Some(match save {
+            Some(contact) => NetworkOutput::ContactConnected(contact),
+            None => NetworkOutput::AddChat(Contact::from_node_id(id))
+        }))
+    }
 
+    /// Open a client to a contact's server, prove which server we speak for, and catch the contact up on any
+    /// of our rotations it missed.
+    async fn open_client(&self, addr: impl Into<NodeAddr>, db: &DataLink) -> Res<ForeignNodeContact> {
+        let addr = addr.into();
+        let node_id = addr.node_id;
 
-            Some(NetworkOutput::AddChat(Contact::from_node_id(id)))
-        } else {
-            None
+        let mut send_client = ForeignNodeContact::client(addr, &self.transport).await?;
+        let _ = send_client.send(self.address_claim(&send_client), PacketType::Address).await;
+        if let Some(username) = self.username.as_ref() {
+            let _ = send_client.send(username.as_bytes().to_vec(), PacketType::Username).await;
         }
+
+        deliver_rotations(&mut send_client, node_id, db).await;
+        Ok(send_client)
     }
 
     /// Asynchronously add a message into the conversation stack, spawning a new foreign node if required.
     /// If a new foreign node was successfuly spawned, Option<NodeId> contains the foreign address.
     pub async fn add_message(&mut self, mut packet: Packet, db: &DataLink) -> Res<Option<NetworkOutput>> {
-        
+
+        if packet.packet_type == PacketType::Invite {
+            return match (self.client_to_server.get(&packet.author).copied(), packet.content) {
+                (Some(server), Ok(content)) => self.redeem_invite(server, &content, db).await.map(Some),
+                _ => Ok(None)
+            };
+        }
+
+        if packet.packet_type == PacketType::Rotation {
+            return match (self.client_to_server.get(&packet.author).copied(), packet.content) {
+                (Some(server), Ok(content)) => {
+                    let statement = RotationStatement::from_bytes(&content)?;
+                    let new = statement.verify(server)?;
+                    self.migrate_contact(statement.old, new, content, db).await.map(Some)
+                }
+                _ => Ok(None)
+            };
+        }
+
         match self.client_to_server.get(&packet.author) {
             Some(author) => if let Some(mut_ref) = self.conversations.get_mut(author) {
                 packet.author = *author;
                 
                 match packet.packet_type {
-                    PacketType::String => mut_ref.conversation.push(packet),
+                    PacketType::String => {
+                        let recipient = self.incoming.get_address().node_id;
+                        let message = SignedMessage::from_bytes(&packet.content?)?;
+
+                        // Drop anything not signed by the identity this client proved it speaks for.
+                        message.verify(PacketType::String, *author, recipient)?;
+                        let packet = message.into_packet(*author, PacketType::String);
+
+                        DatabaseInterface::insert_message(db.clone(), *author, recipient, &packet);
+                        self.expiry.stored(*author, packet.timestamp, self.timers.get(author).copied());
+                        mut_ref.conversation.push(packet.clone());
+                        return Ok(Some(NetworkOutput::AddPacket(*author, packet)));
+                    },
+                    PacketType::Edit | PacketType::Delete => {
+                        let packet_type = packet.packet_type;
+                        let recipient = self.incoming.get_address().node_id;
+                        let message = SignedMessage::from_bytes(&packet.content?)?;
+                        message.verify(packet_type, *author, recipient)?;
+
+                        // Only the original author may change a message, whether or not it is loaded in memory.
+                        let keys = DatabaseInterface::select_author_keys(db.clone(), message.id).await?;
+                        if !keys.contains(author) {
+                            return Err(Error::NotAuthor);
+                        }
+                        if DatabaseInterface::is_message_deleted(db.clone(), message.id).await? {
+                            return Err(Error::NoSuchMessage);
+                        }
+
+                        let packet = message.into_packet(*author, packet_type);
+                        let _ = Packet::amend(&mut mut_ref.conversation, &packet, |original| keys.contains(&original));
+
+                        DatabaseInterface::insert_message(db.clone(), *author, recipient, &packet);
+                        self.expiry.stored(*author, packet.timestamp, self.timers.get(author).copied());
+                        return Ok(Some(NetworkOutput::AddPacket(*author, packet)));
+                    },
+                    PacketType::Reaction => {
+                        let reaction = Reaction::from_bytes(&packet.content?)?;
+
+                        // Only messages from this conversation can be reacted to, and only while they exist.
+                        let keys = DatabaseInterface::select_author_keys(db.clone(), reaction.id).await?;
+                        if (!keys.contains(author) && !keys.contains(&self.incoming.get_address().node_id))
+                            || DatabaseInterface::is_message_deleted(db.clone(), reaction.id).await? {
+                            return Err(Error::NoSuchMessage);
+                        }
+
+                        DatabaseInterface::set_reaction(db.clone(), *author, *author, &reaction);
+                        return Ok(Some(NetworkOutput::Reactions(*author, vec![(*author, reaction)])));
+                    },
                     PacketType::Username => {
                         if let Ok(content) = packet.content {
                             if let Ok(username) = String::from_utf8(content) {
@@ -195,34 +535,38 @@ impl Network {
                             }
                         }
                     },
+                    PacketType::Timer => {
+                        // The contact changed the timer on their side, so honor it on ours too.
+                        let seconds = decode_timer(&packet.content?)?;
+                        match seconds {
+                            Some(seconds) => self.timers.insert(*author, seconds),
+                            None => self.timers.remove(author)
+                        };
+                        self.expiry.forget(author);
+                        DatabaseInterface::set_timer(db.clone(), *author, seconds);
+                        return Ok(Some(NetworkOutput::TimerChanged(*author, seconds)));
+                    },
                     _ => {}
                 }
             }
 
             None => if packet.packet_type == PacketType::Address {
                 if let Ok(content) = packet.content {
-                    if let Ok(string) = String::from_utf8(content) {
-                        if let Ok(node_id) = NodeId::from_str(&string) {
-
-                            // Associate the foreign client with the foreign server
-                            self.client_to_server.insert(packet.author, node_id);
-
-                            // Create a new converstation with the foreign server, do not include address packet
-                            self.conversations.insert(node_id, ForeignNode {
-                                send_client: ForeignNodeContact::client(node_id).await?,
-                                conversation: Vec::new()
-                            });
-
-                            if let Some(mut_ref) = self.conversations.get_mut(&node_id) {
-                                let _ = mut_ref.send_client.send(self.incoming.get_address().node_id.to_string().into_bytes(), PacketType::Address).await;
-                                if let Some(username) = self.username.as_ref() {
-                                     let _ = mut_ref.send_client.send(username.as_bytes().to_vec(), PacketType::Username).await;
-                                }
-                            }
+                    // Reject clients that cannot prove they speak for the server they claim.
+                    let node_id = AddressClaim::from_bytes(&content)?.verify(packet.author)?;
 
-                            return Ok(Some(NetworkOutput::AddChat(Contact::from_node_id(node_id))));
-                        }
-                    }
+                    // Associate the foreign client with the foreign server
+                    self.client_to_server.insert(packet.author, node_id);
+
+                    // Create a new converstation with the foreign server, do not include address packet
+                    let send_client = self.open_client(node_id, db).await?;
+
+                    self.conversations.insert(node_id, ForeignNode {
+                        send_client,
+                        conversation: Vec::new()
+                    });
+
+                    return Ok(Some(NetworkOutput::AddChat(Contact::from_node_id(node_id))));
                 }
             }
         }
@@ -231,23 +575,298 @@ impl Network {
 
     }
 
-    /// Send a message to a target address, forming a connection if it does not already exist to their server.
-    /// If a new foreign node was successfuly spawned, Option<NodeId> contains the foreign address.
-    pub async fn send_message(&mut self, recipient: NodeId, packet: Vec<u8>, packet_type: PacketType, db: &DataLink) -> Res<Option<NodeId>> {
+    /// Accept a contact presenting one of our one-time secrets. If connecting back to them failed when their
+    /// address claim arrived, try again through the address in their ticket, which needs no discovery.
+    async fn redeem_invite(&mut self, server: NodeId, content: &[u8], db: &DataLink) -> Res<NetworkOutput> {
+        let invite = Invite::from_bytes(content)?;
 
-        let new_node = if let Some(mut_ref) = self.conversations.get_mut(&recipient) {
-            mut_ref.send_client.send(packet.clone(), packet_type).await?;
-            mut_ref.conversation.push(Packet {
-                author: self.incoming.get_address().node_id,
-                content: Ok(packet),
-                packet_type
+        // The ticket must be for the identity this client already proved it speaks for.
+        if invite.ticket.addr.node_id != server || !DatabaseInterface::consume_invite(db.clone(), invite.secret).await? {
+            return Err(Error::InvalidInvite);
+        }
+
+        if !self.conversations.contains_key(&server) {
+            let send_client = self.open_client(invite.ticket.addr.clone(), db).await?;
+
+            self.conversations.insert(server, ForeignNode {
+                send_client,
+                conversation: Vec::new()
             });
-            None
-        } else {
+        }
+
+        Ok(NetworkOutput::InviteRedeemed(Contact { server_address: server, username: invite.ticket.username }))
+    }
+
+    /// Move a contact that has rotated its identity onto its new server, keeping the conversation.
+    /// The statement is kept in the conversation so the key change can be shown and re-verified.
+    async fn migrate_contact(&mut self, old: NodeId, new: NodeId, statement: Vec<u8>, db: &DataLink) -> Res<NetworkOutput> {
 
-            Some(recipient)
+        // A contact that rotated while we were offline reaches us under its new identity before the statement.
+        let (send_client, mut recent) = match self.conversations.remove(&new) {
+            Some(node) => (node.send_client, node.conversation),
+            None => (self.open_client(new, db).await?, Vec::new())
         };
 
-        Ok(new_node)
+        let notice = Packet::new(new, Ok(statement.clone()), PacketType::Rotation);
+
+        let mut conversation = self.conversations.remove(&old).map(|node| node.conversation).unwrap_or_default();
+        conversation.append(&mut recent);
+        conversation.push(notice.clone());
+        self.conversations.insert(new, ForeignNode { send_client, conversation });
+
+        // Clients the contact opened before rotating keep working under the new identity.
+        for server in self.client_to_server.values_mut() {
+            if *server == old { *server = new; }
+        }
+
+        if let Some(seconds) = self.timers.remove(&old) {
+            self.timers.insert(new, seconds);
+        }
+        self.expiry.forget(&old);
+        self.expiry.forget(&new);
+
+        DatabaseInterface::record_rotation(db.clone(), old, new, &statement);
+        DatabaseInterface::insert_message(db.clone(), new, self.incoming.get_address().node_id, &notice);
+        Ok(NetworkOutput::IdentityRotated(old, new))
+    }
+
+    /// Switch to the relays that answered the startup probe. Keeping only the reachable self-hosted relays
+    /// changes nothing for running endpoints, which never pick an unreachable relay as their home. Taking the
+    /// fallback replaces the server and reopens every client under it, which makes each contact connect back
+    /// to the new server. Returns whether the server was replaced.
+    pub async fn use_relays(&mut self, resolved: Transport, db: &DataLink) -> Res<bool> {
+        let replace = resolved.relay != self.transport.relay && !matches!(resolved.relay, RelaySetting::Custom(_));
+        self.transport = resolved;
+        if !replace { return Ok(false) }
+
+        let server = Server::spawn(self.incoming.identity().clone(), &self.transport).await?;
+        server.announce(self.username.as_deref());
+        self.incoming = server;
+
+        let contacts: Vec<(NodeId, NodeAddr)> = self.conversations.iter()
+            .map(|(node_id, node)| (*node_id, node.send_client.server_addr().unwrap_or_else(|| NodeAddr::new(*node_id))))
+            .collect();
+        for (node_id, addr) in contacts {
+            match self.open_client(addr, db).await {
+                Ok(send_client) => if let Some(node) = self.conversations.get_mut(&node_id) { node.send_client = send_client },
+                Err(e) => warn!(peer = %node_id, error = %e, "could not reconnect under the new relays")
+            }
+        }
+        Ok(true)
+    }
+
+    /// Replace our identity with a fresh key, telling every contact through a statement signed by both keys.
+    /// Contacts that are not connected get the statement the next time we open a client to them.
+    pub async fn rotate_identity(&mut self, db: &DataLink) -> Res<NodeId> {
+
+        let mut rng = OsRng;
+        let old = self.incoming.identity().clone();
+        let new = SecretKey::generate(&mut rng);
+        let statement = RotationStatement::new(&old, &new).to_bytes();
+
+        // Nothing is saved until the new server is up, so a failure leaves the old identity in place.
+        let server = Server::spawn(new.clone(), &self.transport).await?;
+        DatabaseInterface::store_node_id(db.clone(), new.clone()).await?;
+
+        DatabaseInterface::queue_rotation(db.clone(), &statement, self.conversations.keys().copied().collect()).await?;
+        for (node_id, node) in self.conversations.iter_mut() {
+            deliver_rotations(&mut node.send_client, *node_id, db).await;
+        }
+
+        DatabaseInterface::record_rotation(db.clone(), old.public(), new.public(), &statement);
+        server.announce(self.username.as_deref());
+        self.incoming = server;
+        Ok(new.public())
+    }
+
+    /// Change the disappearing message timer for a conversation and tell the contact to apply it as well.
+    pub async fn set_timer(&mut self, node_id: NodeId, seconds: Option<u64>, db: &DataLink) -> Res<()> {
+        let node = self.conversations.get_mut(&node_id).ok_or(Error::NoSuchClient)?;
+        node.send_client.send(seconds.unwrap_or(0).to_be_bytes().to_vec(), PacketType::Timer).await?;
+
+        match seconds {
+            Some(seconds) => self.timers.insert(node_id, seconds),
+            None => self.timers.remove(&node_id)
+        };
+        self.expiry.forget(&node_id);
+        DatabaseInterface::set_timer(db.clone(), node_id, seconds);
+        Ok(())
+    }
+
+    /// Drop every message older than its conversation's timer from memory and the database. Conversations are
+    /// only touched once their oldest message is due, and reported only when something was deleted.
+    pub async fn sweep_expired(&mut self, db: &DataLink) -> Vec<NetworkOutput> {
+        let now = timestamp();
+        let mut output = Vec::new();
+
+        for (node_id, seconds) in self.timers.iter() {
+            if !self.expiry.is_due(node_id, now) { continue }
+
+            let cutoff = now.saturating_sub(*seconds);
+            let mut oldest = match DatabaseInterface::select_oldest_message(db.clone(), *node_id).await {
+                Ok(oldest) => oldest,
+                Err(e) => {
+                    warn!(conversation = %node_id, error = %e, "could not check for expired messages");
+                    continue;
+                }
+            };
+
+            if oldest.is_some_and(|oldest| oldest <= cutoff) {
+                if let Some(node) = self.conversations.get_mut(node_id) {
+                    node.conversation.retain(|packet| packet.timestamp > cutoff || !packet.packet_type.is_signed());
+                }
+
+                DatabaseInterface::delete_expired(db.clone(), *node_id, cutoff);
+                output.push(NetworkOutput::MessagesExpired(*node_id, cutoff));
+                oldest = DatabaseInterface::select_oldest_message(db.clone(), *node_id).await.unwrap_or(None);
+            }
+
+            self.expiry.set(*node_id, oldest, *seconds);
+        }
+
+        output
+    }
+
+    /// Send a message to a connected server. Text is signed with our identity key under a fresh message id,
+    /// and stored with its signature. Returns the packet as it should appear in our own conversation.
+    pub async fn send_message(&mut self, recipient: NodeId, packet: Vec<u8>, packet_type: PacketType, db: &DataLink) -> Res<Packet> {
+
+        if packet_type != PacketType::String {
+            let mut_ref = self.conversations.get_mut(&recipient).ok_or(Error::NoSuchClient)?;
+            mut_ref.send_client.send(packet.clone(), packet_type).await?;
+            return Ok(Packet::new(self.incoming.get_address().node_id, Ok(packet), packet_type));
+        }
+
+        self.send_signed(recipient, rand::random(), None, packet, packet_type, db).await
+    }
+
+    /// Edit or delete one of our earlier messages, which the contact will apply to their copy.
+    pub async fn amend_message(&mut self, recipient: NodeId, id: MessageId, content: Vec<u8>, packet_type: PacketType, db: &DataLink) -> Res<Packet> {
+
+        let keys = DatabaseInterface::select_author_keys(db.clone(), id).await?;
+        if !keys.contains(&self.incoming.get_address().node_id) {
+            return Err(Error::NotAuthor);
+        }
+        if DatabaseInterface::is_message_deleted(db.clone(), id).await? {
+            return Err(Error::NoSuchMessage);
+        }
+
+        self.send_signed(recipient, id, None, content, packet_type, db).await
+    }
+
+    /// Add or remove one of our reactions on a message in the conversation with a contact.
+    pub async fn react(&mut self, recipient: NodeId, reaction: Reaction, db: &DataLink) -> Res<NetworkOutput> {
+
+        if !Reaction::is_valid_emoji(&reaction.emoji) { return Err(Error::InvalidReaction); }
+        if reaction.added && DatabaseInterface::is_message_deleted(db.clone(), reaction.id).await? {
+            return Err(Error::NoSuchMessage);
+        }
+
+        let mut_ref = self.conversations.get_mut(&recipient).ok_or(Error::NoSuchClient)?;
+        mut_ref.send_client.send(reaction.to_bytes(), PacketType::Reaction).await?;
+
+        let author = self.incoming.get_address().node_id;
+        DatabaseInterface::set_reaction(db.clone(), recipient, author, &reaction);
+        Ok(NetworkOutput::Reactions(recipient, vec![(author, reaction)]))
+    }
+
+    /// Sign and send a message under the given id, optionally as a reply to an earlier message in the conversation.
+    async fn send_signed(&mut self, recipient: NodeId, id: MessageId, reply_to: Option<MessageId>, content: Vec<u8>, packet_type: PacketType, db: &DataLink) -> Res<Packet> {
+
+        let mut_ref = self.conversations.get_mut(&recipient).ok_or(Error::NoSuchClient)?;
+        let message = SignedMessage::new(self.incoming.identity(), recipient, packet_type, id, reply_to, content);
+        mut_ref.send_client.send(message.to_bytes(), packet_type).await?;
+
+        let packet = message.into_packet(self.incoming.get_address().node_id, packet_type);
+        DatabaseInterface::insert_message(db.clone(), recipient, recipient, &packet);
+        self.expiry.stored(recipient, packet.timestamp, self.timers.get(&recipient).copied());
+
+        match packet_type {
+            PacketType::String => mut_ref.conversation.push(packet.clone()),
+            // Our own key is the only one that could have passed the author check before sending.
+            _ => { let _ = Packet::amend(&mut mut_ref.conversation, &packet, |_| true); }
+        }
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use iroh::RelayUrl;
+    use tokio::time::timeout;
+
+    use crate::backend::database::Database;
+    use crate::networking::transport::Discovery;
+
+    use super::*;
+
+    fn node_id() -> NodeId {
+        SecretKey::generate(&mut OsRng).public()
+    }
+
+    fn offline(relay: RelaySetting) -> Transport {
+        Transport { discovery: Discovery::None, relay, ..Transport::default() }
+    }
+
+    #[tokio::test]
+    async fn falling_back_reconnects_every_contact() {
+        let database = Database::temporary();
+        let db = database.derive();
+        DatabaseInterface::make_tables(db.clone()).unwrap();
+
+        // Nothing listens on the discard port, so the probe would find this relay unreachable.
+        let unreachable = offline(RelaySetting::Custom(vec![RelayUrl::from_str("http://127.0.0.1:9").unwrap()]));
+        let contact = Server::spawn(SecretKey::generate(&mut OsRng), &offline(RelaySetting::Disabled)).await.unwrap();
+        let mut network = Network {
+            conversations: HashMap::new(),
+            client_to_server: HashMap::new(),
+            incoming: Server::spawn(SecretKey::generate(&mut OsRng), &unreachable).await.unwrap(),
+            username: None,
+            transport: unreachable,
+            timers: HashMap::new(),
+            expiry: ExpirySchedule::default()
+        };
+        network.connect(contact.get_address(), None, None, &db).await.unwrap();
+
+        assert!(network.use_relays(offline(RelaySetting::Disabled), &db).await.unwrap());
+        assert!(!network.use_relays(offline(RelaySetting::Disabled), &db).await.unwrap());
+
+        // The contact hears from a fresh client, which it will connect back through to the new server.
+        let clients = timeout(Duration::from_secs(10), async {
+            let mut clients = Vec::new();
+            while clients.len() < 2 {
+                let packet = contact.yield_receiver().recv().await.unwrap();
+                if packet.packet_type == PacketType::Address && !clients.contains(&packet.author) { clients.push(packet.author) }
+            }
+            clients
+        }).await.expect("a second client");
+        assert_eq!(clients[1], network.conversations[&contact.get_address().node_id].send_client.node_id());
+    }
+
+    #[test]
+    fn expiry_is_only_due_once_the_oldest_message_expires() {
+        let mut expiry = ExpirySchedule::default();
+        let (conversation, empty) = (node_id(), node_id());
+
+        // Unknown conversations are checked once.
+        assert!(expiry.is_due(&conversation, 0));
+
+        expiry.set(conversation, Some(100), 60);
+        expiry.set(empty, None, 60);
+        assert!(!expiry.is_due(&conversation, 159));
+        assert!(expiry.is_due(&conversation, 160));
+        assert!(!expiry.is_due(&empty, u64::MAX));
+
+        // A message arriving with an old timestamp expires sooner than what was stored before.
+        expiry.stored(conversation, 50, Some(60));
+        assert!(expiry.is_due(&conversation, 110));
+        expiry.stored(empty, 200, Some(60));
+        assert!(!expiry.is_due(&empty, 259));
+        assert!(expiry.is_due(&empty, 260));
+
+        expiry.forget(&empty);
+        assert!(expiry.is_due(&empty, 0));
     }
 }
@@ -14,7 +14,7 @@ impl Contact {
     pub fn new(server: String, username: String) -> Res<Self> {
         match NodeId::from_str(&server) {
             Ok(server_address) => Ok(Self { server_address, username: Some(username) }),
-            Err(_) => Err(Error::RemoteIDFailed)
+            Err(_) => Err(Error::InvalidNodeId)
         }
     }
 
@@ -2,3 +2,5 @@ pub mod network;
 pub mod packet;
 pub mod abstraction;
 pub mod contact;
+pub mod ticket;
+pub mod transport;
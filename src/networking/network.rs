@@ -1,20 +1,31 @@
-use crate::backend::database::DataLink;
-use crate::backend::database_interface::DatabaseInterface;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use crate::error::{Error, Res};
+use crate::logging::redact;
 use crate::networking::packet::Packet;
+use crate::networking::transport::Transport;
 
+use iroh::discovery::{DiscoveryEvent, UserData};
 use iroh::protocol::{AcceptError, ProtocolHandler, Router};
-use iroh::{Endpoint, NodeAddr, NodeId, Watcher};
-use iroh::endpoint::{Connection, ReadToEndError, RecvStream};
+use iroh::{Endpoint, NodeAddr, NodeId, SecretKey, Watcher};
+use iroh::endpoint::{Connection, ConnectionType, ReadToEndError, RecvStream};
 
 use async_channel::Sender;
 use async_channel::Receiver;
 use async_channel::unbounded;
+use n0_future::StreamExt;
+use tokio::task::JoinHandle;
+use tracing::{debug, instrument, trace, warn};
 
 use super::packet::PacketType;
 
 const ALPN: &[u8] = b"hchap1/pingpong";
 
+/// Discovery user data of a server that opted in to being seen nearby, optionally followed by ":" and its username.
+const NEARBY_TAG: &str = "pingpong";
+
 /* -- PROTOCOL --
 
  - Each node runs a server and a client.
@@ -24,21 +35,77 @@ const ALPN: &[u8] = b"hchap1/pingpong";
 
 */
 
+/// Snapshot of our address and every connected peer, for the diagnostics page.
+#[derive(Clone, Debug)]
+pub struct NodeDiagnostics {
+    pub node_addr: NodeAddr,
+    pub peers: Vec<PeerDiagnostics>
+}
+
+/// Each contact is reached over two connections: ours to their server, and theirs to ours.
+#[derive(Clone, Debug)]
+pub struct PeerDiagnostics {
+    pub node_id: NodeId,
+    pub outgoing: Option<ConnectionType>,
+    pub incoming: Option<ConnectionType>,
+    pub rtt: Duration,
+    pub sent: u64,
+    pub received: u64
+}
+
+/// A pingpong server broadcasting on the local network.
+#[derive(Clone, Debug)]
+pub struct NearbyPeer {
+    pub addr: NodeAddr,
+    pub username: Option<String>
+}
+
+/// Changes to the set of nearby pingpong servers, as local discovery reports them.
+#[derive(Clone, Debug)]
+pub enum Nearby {
+    Found(NearbyPeer),
+    Lost(NodeId)
+}
+
+/// Forward servers seen through local discovery. Other iroh nodes, our own client endpoints, and servers
+/// that have not opted in carry no tag and are skipped.
+async fn watch_nearby(endpoint: Endpoint, relay: Sender<Nearby>) {
+    let mut events = endpoint.discovery_stream();
+    while let Some(event) = events.next().await {
+        let nearby = match event {
+            Ok(DiscoveryEvent::Discovered(item)) => {
+                let Some(tag) = item.user_data() else { continue };
+                let username = match tag.as_ref().split_once(':') {
+                    Some((NEARBY_TAG, username)) => Some(username.to_string()),
+                    None if tag.as_ref() == NEARBY_TAG => None,
+                    _ => continue
+                };
+                Nearby::Found(NearbyPeer { addr: item.into_node_addr(), username })
+            }
+            Ok(DiscoveryEvent::Expired(node_id)) => Nearby::Lost(node_id),
+            Err(_) => continue
+        };
+
+        if relay.send(nearby).await.is_err() { break; }
+    }
+}
+
 /// Local client connected to a foreign server.
 #[derive(Debug)]
 pub struct ForeignNodeContact {
-    _endpoint: Endpoint,
+    endpoint: Endpoint,
     connection: Connection
 }
 
 /// Consume bytes from recv stream and forward to relay stream.
+#[instrument(level = "debug", skip_all, fields(peer = %foreign, stream = %recv.id()))]
 async fn relay_bytes(foreign: NodeId, mut recv: RecvStream, relay: Sender<Packet>) {
     loop {
-        println!("LISTENING TO CHANNEL FROM {foreign}");
+        debug!("listening");
         // Attempt to find packet to forward, else handle errors gracefully.
         let (forward, close) = match recv.read_to_end(4096).await {
             Ok(read) => {
-                println!("RECEIVED: {read:?}");
+                trace!(bytes = read.len(), content = %redact(&read), "received");
                 let empty = read.is_empty();
                 (
                     if empty { vec![Packet::failure(foreign, Error::StreamClosed)] } else { Packet::success(foreign, read) },
@@ -46,10 +113,10 @@ async fn relay_bytes(foreign: NodeId, mut recv: RecvStream, relay: Sender<Packet
                 )
             },
             Err(e) => {
-                println!("CHANNEL FAILURE FROM {foreign}");
+                warn!(error = %e, "stream failed");
                 (
                 vec![match e {
-                    ReadToEndError::Read(_) => Packet::failure(foreign, Error::StreamReadFailed),
+                    ReadToEndError::Read(e) => Packet::failure(foreign, Error::StreamReadFailed(Arc::new(e))),
                     ReadToEndError::TooLong => Packet::failure(foreign, Error::TooLong)
                 }], true
             )}
@@ -68,19 +135,48 @@ async fn relay_bytes(foreign: NodeId, mut recv: RecvStream, relay: Sender<Packet
 impl ForeignNodeContact {
 
     /// Establish a channel to a NodeAddr to send it packets.
-    pub async fn client(addr: NodeId) -> Res<Self> {
-        let endpoint = Endpoint::builder().discovery_n0().bind().await?;
-        let connection = endpoint.connect(addr, ALPN).await?;
+    #[instrument(level = "debug", skip_all)]
+    pub async fn client(addr: impl Into<NodeAddr>, transport: &Transport) -> Res<Self> {
+        let addr = addr.into();
+        let endpoint = transport.builder().bind().await?;
+        let connection = endpoint.connect(addr.clone(), ALPN).await?;
+        debug!(peer = %addr.node_id, "connected");
 
         Ok(Self {
-            _endpoint: endpoint,
+            endpoint,
             connection,
         })
     }
 
+    /// The throwaway id of this client endpoint, as seen by the foreign server.
+    pub fn node_id(&self) -> NodeId {
+        self.endpoint.node_id()
+    }
+
+    /// Every address this client has learned for the foreign server, so it can be reached again without discovery.
+    pub fn server_addr(&self) -> Option<NodeAddr> {
+        let server = self.connection.remote_node_id().ok()?;
+        self.endpoint.remote_info(server).map(NodeAddr::from)
+    }
+
+    /// Whether the connection to the foreign server currently runs direct or through a relay.
+    pub fn conn_type(&self) -> Option<ConnectionType> {
+        let server = self.connection.remote_node_id().ok()?;
+        self.endpoint.conn_type(server).map(|mut watcher| watcher.get())
+    }
+
+    pub fn rtt(&self) -> Duration {
+        self.connection.rtt()
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.connection.stats().udp_tx.bytes
+    }
+
     /// Encode the packet such that it can be split up using length headers
     pub async fn send(&mut self, mut packet: Vec<u8>, packet_type: PacketType) -> Res<()> {
         let len = packet.len() as u32;
+        trace!(bytes = len, ?packet_type, content = %redact(&packet), "sending");
 
         let mut header = Vec::with_capacity(5);
         header.push(packet_type.to_u8());
@@ -107,25 +203,46 @@ impl ForeignNodeContact {
 #[derive(Debug)]
 pub struct Server {
     node_addr: NodeAddr,
-    _router: Router,
+    identity: SecretKey,
+    router: Router,
+
+    // Connections foreign clients hold to us, keyed by their client id.
+    connections: Arc<Mutex<HashMap<NodeId, Connection>>>,
+
+    // Whether we tag our discovery records so we show up in other people's nearby lists.
+    broadcast: bool,
+    nearby: Receiver<Nearby>,
+    // Only running when the user chose to see people nearby.
+    nearby_watcher: Option<JoinHandle<()>>,
+
     _send_stream: Sender<Packet>,
     recv_stream: Receiver<Packet>
 }
 
 impl Server {
     
-    /// Create a server, will store permanent address in db
-    pub async fn spawn(db: DataLink) -> Res<Self> {
+    /// Create a server listening as the given identity. Storing the identity is left to the caller, so a key
+    /// is only saved once a server has come up with it.
+    pub async fn spawn(identity: SecretKey, transport: &Transport) -> Res<Self> {
 
-        let address = DatabaseInterface::get_node_id_blocking(db).await.0;
-        
         let (send_stream, recv_stream) = unbounded();
-        let endpoint = Endpoint::builder().secret_key(address).discovery_n0().bind().await?;
-        let router = Router::builder(endpoint).accept(ALPN, PacketRelay { relay: send_stream.clone() }).spawn();
+        let endpoint = transport.server_builder().secret_key(identity.clone()).bind().await?;
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+        let router = Router::builder(endpoint)
+            .accept(ALPN, PacketRelay { relay: send_stream.clone(), connections: connections.clone() })
+            .spawn();
+
+        let (nearby_sender, nearby) = unbounded();
+        let nearby_watcher = transport.listen.then(|| tokio::spawn(watch_nearby(router.endpoint().clone(), nearby_sender)));
 
         Ok(Server {
             node_addr: router.endpoint().node_addr().initialized().await,
-            _router: router,
+            identity,
+            router,
+            connections,
+            broadcast: transport.broadcast,
+            nearby,
+            nearby_watcher,
             _send_stream: send_stream,
             recv_stream
         })
@@ -135,30 +252,168 @@ impl Server {
         self.node_addr.clone()
     }
 
+    /// Our address as it stands now. Direct addresses and the home relay can change after startup.
+    pub fn current_address(&self) -> NodeAddr {
+        self.router.endpoint().node_addr().get().unwrap_or_else(|| self.node_addr.clone())
+    }
+
+    /// Path and bytes received over the connection a foreign client holds to us.
+    pub fn incoming(&self, client: NodeId) -> Option<(Option<ConnectionType>, u64)> {
+        let received = self.connections.lock().ok()?.get(&client)?.stats().udp_rx.bytes;
+        let conn_type = self.router.endpoint().conn_type(client).map(|mut watcher| watcher.get());
+        Some((conn_type, received))
+    }
+
+    /// Long-term identity key, shared by the server endpoint and used to authenticate our clients.
+    pub fn identity(&self) -> &SecretKey {
+        &self.identity
+    }
+
     pub fn yield_receiver(&self) -> Receiver<Packet> {
         self.recv_stream.clone()
     }
+
+    pub fn yield_nearby(&self) -> Receiver<Nearby> {
+        self.nearby.clone()
+    }
+
+    /// Tag our discovery records with the username, if the user opted in to being seen nearby.
+    /// Names too long for a discovery record are left out.
+    pub fn announce(&self, username: Option<&str>) {
+        if !self.broadcast { return; }
+        let tag = match username {
+            Some(username) => format!("{NEARBY_TAG}:{username}"),
+            None => NEARBY_TAG.to_string()
+        };
+        if let Ok(tag) = UserData::try_from(tag).or_else(|_| UserData::try_from(NEARBY_TAG.to_string())) {
+            self.router.endpoint().set_user_data_for_discovery(Some(tag));
+        }
+    }
+}
+
+impl Drop for Server {
+    // The watcher holds the endpoint, which would otherwise outlive a server replaced by identity rotation.
+    fn drop(&mut self) {
+        if let Some(watcher) = self.nearby_watcher.as_ref() { watcher.abort(); }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct PacketRelay {
 
     // Relay messages onto the server message stack
-    relay: Sender<Packet>
+    relay: Sender<Packet>,
+    connections: Arc<Mutex<HashMap<NodeId, Connection>>>
 }
 
 impl ProtocolHandler for PacketRelay {
     async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
 
         let node_id = connection.remote_node_id()?;
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.insert(node_id, connection.clone());
+        }
 
-        loop {
-            let recv = connection.accept_uni().await?;
-
-            // Relay until stream is closed by the other end.
-            relay_bytes(node_id, recv, self.relay.clone()).await;
+        let error = loop {
+            match connection.accept_uni().await {
+                // Relay until stream is closed by the other end.
+                Ok(recv) => relay_bytes(node_id, recv, self.relay.clone()).await,
+                Err(e) => break e
+            }
+            debug!(peer = %node_id, "stream ended, waiting for the next");
+        };
 
-            println!("\nNEW UNI CHANNEL\n");
+        // A client that reconnected before this connection noticed it was closed has already replaced it.
+        if let Ok(mut connections) = self.connections.lock()
+            && connections.get(&node_id).is_some_and(|current| current.stable_id() == connection.stable_id())
+        {
+            connections.remove(&node_id);
         }
+        Err(error.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use tokio::time::{sleep, timeout};
+
+    use crate::networking::ticket::Ticket;
+    use crate::networking::transport::{Discovery, RelaySetting};
+
+    use super::*;
+
+    fn offline() -> Transport {
+        Transport { discovery: Discovery::None, relay: RelaySetting::Disabled, ..Transport::default() }
+    }
+
+    /// Id of the connection the server holds for a client, once it satisfies the condition.
+    async fn held(server: &Server, client: NodeId, until: impl Fn(Option<usize>) -> bool) -> Option<usize> {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let id = server.connections.lock().ok().and_then(|c| c.get(&client).map(|c| c.stable_id()));
+                if until(id) { return id }
+                sleep(Duration::from_millis(20)).await;
+            }
+        }).await.expect("server connection state")
+    }
+
+    #[tokio::test]
+    async fn reconnecting_clients_stay_tracked() {
+        let server = Server::spawn(SecretKey::generate(&mut OsRng), &offline()).await.unwrap();
+        let client = offline().builder().bind().await.unwrap();
+
+        let first = client.connect(server.get_address(), ALPN).await.unwrap();
+        let first_id = held(&server, client.node_id(), |id| id.is_some()).await;
+
+        let second = client.connect(server.get_address(), ALPN).await.unwrap();
+        held(&server, client.node_id(), |id| id.is_some() && id != first_id).await;
+
+        // Closing the old connection must not forget the new one.
+        first.close(0u32.into(), b"replaced");
+        sleep(Duration::from_millis(300)).await;
+        assert!(held(&server, client.node_id(), |_| true).await.is_some());
+
+        second.close(0u32.into(), b"done");
+        held(&server, client.node_id(), |id| id.is_none()).await;
+        client.close().await;
+    }
+
+    #[tokio::test]
+    async fn address_book_works_without_discovery() {
+        let server = Server::spawn(SecretKey::generate(&mut OsRng), &offline()).await.unwrap();
+        let ticket = Ticket::new(server.identity(), server.get_address(), None, None);
+
+        // Known only by id, the server cannot be found without the address book.
+        let stranger = offline().builder().bind().await.unwrap();
+        assert!(timeout(Duration::from_secs(2), stranger.connect(server.get_address().node_id, ALPN)).await.map_or(true, |r| r.is_err()));
+
+        let client = Transport { address_book: vec![ticket], ..offline() }.builder().bind().await.unwrap();
+        let connection = client.connect(server.get_address().node_id, ALPN).await.unwrap();
+        connection.close(0u32.into(), b"done");
+        client.close().await;
+        stranger.close().await;
+    }
+
+    #[tokio::test]
+    async fn looking_and_being_seen_are_separate() {
+        let local = |broadcast, listen| Transport { broadcast, listen, ..offline() };
+        let seen = Server::spawn(SecretKey::generate(&mut OsRng), &local(true, false)).await.unwrap();
+        let looking = Server::spawn(SecretKey::generate(&mut OsRng), &local(false, true)).await.unwrap();
+        seen.announce(Some("alice"));
+
+        let found = timeout(Duration::from_secs(10), async {
+            loop {
+                match looking.yield_nearby().recv().await {
+                    Ok(Nearby::Found(peer)) if peer.addr.node_id == seen.get_address().node_id => break peer,
+                    Ok(_) => continue,
+                    Err(e) => panic!("{e}")
+                }
+            }
+        }).await.expect("advertised server found");
+        assert_eq!(found.username.as_deref(), Some("alice"));
+
+        // Being seen does not mean looking.
+        assert!(seen.nearby_watcher.is_none());
     }
 }
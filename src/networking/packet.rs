@@ -1,13 +1,39 @@
-use iroh::NodeId;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::Signature;
+use iroh::{NodeId, SecretKey};
+use unicode_segmentation::UnicodeSegmentation;
 
 use crate::error::{Error, Res};
 
+const ROTATION_CONTEXT: &[u8] = b"pingpong/rotate";
+// The second version tags the optional reply and prefixes the content with its length. Signatures made under
+// the first, where a reply id could not be told apart from the start of the text, no longer verify.
+const MESSAGE_CONTEXT: &[u8] = b"pingpong/message/v2";
+
+// Longest reaction accepted, in bytes. Enough for flags, skin tones and family sequences.
+const MAX_EMOJI_BYTES: usize = 32;
+
+/// Random id given to every text message so that later packets can refer back to it.
+pub type MessageId = [u8; 16];
+
+/// Seconds since the unix epoch.
+pub fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum PacketType {
     Error,
     String,
     Address,
     Username,
+    Rotation,
+    Timer,
+    Edit,
+    Delete,
+    Reaction,
+    Invite,
 }
 
 impl PacketType {
@@ -16,28 +42,103 @@ impl PacketType {
             1 => Self::String,
             2 => Self::Address,
             3 => Self::Username,
+            4 => Self::Rotation,
+            5 => Self::Timer,
+            6 => Self::Edit,
+            7 => Self::Delete,
+            8 => Self::Reaction,
+            9 => Self::Invite,
             _ => Self::Error
         }
     }
 
+    /// Packet types whose payload is a SignedMessage.
+    pub fn is_signed(self) -> bool {
+        matches!(self, Self::String | Self::Edit | Self::Delete)
+    }
+
     pub fn to_u8(self) -> u8 {
         match self {
             Self::String => 1,
             Self::Address => 2,
             Self::Username => 3,
+            Self::Rotation => 4,
+            Self::Timer => 5,
+            Self::Edit => 6,
+            Self::Delete => 7,
+            Self::Reaction => 8,
+            Self::Invite => 9,
             _ => 0
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum Revision {
+    #[default]
+    Original,
+    Edited,
+    Deleted
+}
+
 #[derive(Debug, Clone)]
 pub struct Packet {
     pub author: NodeId,
     pub content: Res<Vec<u8>>,
-    pub packet_type: PacketType
+    pub packet_type: PacketType,
+    pub timestamp: u64,
+
+    // Author's signature over a signed packet, see SignedMessage.
+    pub signature: Option<Signature>,
+
+    // Id of a text message, or of the message an edit or delete refers to.
+    pub id: Option<MessageId>,
+    pub revision: Revision,
+
+    // Message this one replies to, if any.
+    pub reply_to: Option<MessageId>
 }
 
 impl Packet {
+    pub fn new(author: NodeId, content: Res<Vec<u8>>, packet_type: PacketType) -> Self {
+        Self {
+            author,
+            content,
+            packet_type,
+            timestamp: timestamp(),
+            signature: None,
+            id: None,
+            revision: Revision::Original,
+            reply_to: None
+        }
+    }
+
+    /// Apply an edit or delete to the text message it refers to. Only the original author may amend a message,
+    /// which `by_author` decides from the key the message was sent under, as the author may have rotated since.
+    pub fn amend(conversation: &mut [Packet], amendment: &Packet, by_author: impl Fn(NodeId) -> bool) -> Res<()> {
+        let target = conversation.iter_mut()
+            .find(|p| p.packet_type == PacketType::String && p.id.is_some() && p.id == amendment.id)
+            .ok_or(Error::NoSuchMessage)?;
+
+        if target.author != amendment.author && !by_author(target.author) { return Err(Error::NotAuthor); }
+        // A deleted message stays deleted, whatever arrives after it.
+        if target.revision == Revision::Deleted { return Err(Error::NoSuchMessage); }
+
+        match amendment.packet_type {
+            PacketType::Edit => {
+                target.content = amendment.content.clone();
+                target.revision = Revision::Edited;
+            }
+            PacketType::Delete => {
+                target.content = Ok(Vec::new());
+                target.revision = Revision::Deleted;
+            }
+            _ => return Err(Error::MalformedPacket)
+        }
+
+        Ok(())
+    }
+
     pub fn success(author: NodeId, content: Vec<u8>) -> Vec<Self> {
         let mut packets = Vec::new();
         let mut buf = content.as_slice();
@@ -53,11 +154,7 @@ impl Packet {
 
             let payload = buf[5..5 + payload_len].to_vec();
 
-            packets.push(Self {
-                author,
-                content: Ok(payload),
-                packet_type,
-            });
+            packets.push(Self::new(author, Ok(payload), packet_type));
 
             buf = &buf[5 + payload_len..];
         }
@@ -66,6 +163,358 @@ impl Packet {
     }
 
     pub fn failure(author: NodeId, error: Error) -> Self {
-        Self { author, content: Err(error), packet_type: PacketType::Error }
+        Self::new(author, Err(error), PacketType::Error)
+    }
+}
+
+/// Payload of a Reaction packet, adding or removing one emoji on an earlier message. Reactions ride on the
+/// authenticated connection like usernames and timers, and never become messages of their own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reaction {
+    pub id: MessageId,
+    pub emoji: String,
+    pub added: bool
+}
+
+impl Reaction {
+    /// A reaction is one grapheme, such as a single emoji with its modifiers, and never longer than
+    /// MAX_EMOJI_BYTES.
+    pub fn is_valid_emoji(emoji: &str) -> bool {
+        emoji.len() <= MAX_EMOJI_BYTES
+            && emoji.graphemes(true).count() == 1
+            && !emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.added as u8];
+        bytes.extend_from_slice(&self.id);
+        bytes.extend_from_slice(self.emoji.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Res<Self> {
+        if bytes.len() < 18 { return Err(Error::MalformedPacket); }
+
+        let emoji = String::from_utf8(bytes[17..].to_vec()).map_err(|_| Error::MalformedPacket)?;
+        if !Self::is_valid_emoji(&emoji) { return Err(Error::InvalidReaction); }
+
+        Ok(Self {
+            added: bytes[0] != 0,
+            id: bytes[1..17].try_into().map_err(|_| Error::MalformedPacket)?,
+            emoji
+        })
+    }
+}
+
+/// Sent as the payload of an Address packet. Client endpoints use throwaway keys, so the identity key
+/// signs the client endpoint id to prove that the client speaks for the claimed server.
+#[derive(Debug, Clone)]
+pub struct AddressClaim {
+    pub server: NodeId,
+    signature: Signature
+}
+
+impl AddressClaim {
+    pub fn new(identity: &SecretKey, client: NodeId) -> Self {
+        Self { server: identity.public(), signature: identity.sign(client.as_bytes()) }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.server.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Res<Self> {
+        if bytes.len() != 96 { return Err(Error::HandshakeFailed); }
+
+        let server_bytes: [u8; 32] = bytes[..32].try_into().map_err(|_| Error::HandshakeFailed)?;
+        let signature_bytes: [u8; 64] = bytes[32..].try_into().map_err(|_| Error::HandshakeFailed)?;
+
+        Ok(Self {
+            server: NodeId::from_bytes(&server_bytes).map_err(|_| Error::HandshakeFailed)?,
+            signature: Signature::from_bytes(&signature_bytes)
+        })
+    }
+
+    /// Check that the claim was signed for the client endpoint it arrived from.
+    pub fn verify(&self, client: NodeId) -> Res<NodeId> {
+        match self.server.verify(client.as_bytes(), &self.signature) {
+            Ok(_) => Ok(self.server),
+            Err(_) => Err(Error::HandshakeFailed)
+        }
+    }
+}
+
+/// Sent to every contact when we rotate identity. The old key vouches for the new one, and the new key
+/// signs the same statement so that nobody can rotate onto a key they do not hold.
+#[derive(Debug, Clone)]
+pub struct RotationStatement {
+    pub old: NodeId,
+    pub new: NodeId,
+    old_signature: Signature,
+    new_signature: Signature
+}
+
+impl RotationStatement {
+    fn message(old: NodeId, new: NodeId) -> Vec<u8> {
+        let mut message = ROTATION_CONTEXT.to_vec();
+        message.extend_from_slice(old.as_bytes());
+        message.extend_from_slice(new.as_bytes());
+        message
+    }
+
+    pub fn new(old: &SecretKey, new: &SecretKey) -> Self {
+        let message = Self::message(old.public(), new.public());
+        Self {
+            old: old.public(),
+            new: new.public(),
+            old_signature: old.sign(&message),
+            new_signature: new.sign(&message)
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.old.as_bytes().to_vec();
+        bytes.extend_from_slice(self.new.as_bytes());
+        bytes.extend_from_slice(&self.old_signature.to_bytes());
+        bytes.extend_from_slice(&self.new_signature.to_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Res<Self> {
+        if bytes.len() != 192 { return Err(Error::InvalidRotation); }
+
+        let old_bytes: [u8; 32] = bytes[..32].try_into().map_err(|_| Error::InvalidRotation)?;
+        let new_bytes: [u8; 32] = bytes[32..64].try_into().map_err(|_| Error::InvalidRotation)?;
+        let old_signature: [u8; 64] = bytes[64..128].try_into().map_err(|_| Error::InvalidRotation)?;
+        let new_signature: [u8; 64] = bytes[128..].try_into().map_err(|_| Error::InvalidRotation)?;
+
+        Ok(Self {
+            old: NodeId::from_bytes(&old_bytes).map_err(|_| Error::InvalidRotation)?,
+            new: NodeId::from_bytes(&new_bytes).map_err(|_| Error::InvalidRotation)?,
+            old_signature: Signature::from_bytes(&old_signature),
+            new_signature: Signature::from_bytes(&new_signature)
+        })
+    }
+
+    /// Check both signatures, and that the statement came from one of the two servers it names. A contact we
+    /// could not reach when it rotated delivers the statement from its new server.
+    pub fn verify(&self, sender: NodeId) -> Res<NodeId> {
+        let message = Self::message(self.old, self.new);
+
+        if (self.old == sender || self.new == sender)
+            && self.old.verify(&message, &self.old_signature).is_ok()
+            && self.new.verify(&message, &self.new_signature).is_ok() {
+            Ok(self.new)
+        } else {
+            Err(Error::InvalidRotation)
+        }
+    }
+}
+
+/// Payload of a String, Edit or Delete packet. The author's identity key signs the text together with the
+/// packet type, both parties, the message id, the send time and the message replied to (if any), so stored and
+/// exported history can be proven to come from the claimed author. Edits and deletes carry the id of the
+/// message they change.
+#[derive(Debug, Clone)]
+pub struct SignedMessage {
+    pub id: MessageId,
+    pub timestamp: u64,
+    pub signature: Signature,
+    pub reply_to: Option<MessageId>,
+    pub content: Vec<u8>
+}
+
+impl SignedMessage {
+    fn message(&self, packet_type: PacketType, author: NodeId, recipient: NodeId) -> Vec<u8> {
+        Self::signed_bytes(packet_type, author, recipient, &self.id, self.timestamp, self.reply_to.as_ref(), &self.content)
+    }
+
+    fn signed_bytes(
+        packet_type: PacketType, author: NodeId, recipient: NodeId, id: &MessageId, timestamp: u64, reply_to: Option<&MessageId>, content: &[u8]
+    ) -> Vec<u8> {
+        let mut message = MESSAGE_CONTEXT.to_vec();
+        message.push(packet_type.to_u8());
+        message.extend_from_slice(author.as_bytes());
+        message.extend_from_slice(recipient.as_bytes());
+        message.extend_from_slice(id);
+        message.extend_from_slice(&timestamp.to_be_bytes());
+
+        match reply_to {
+            Some(reply_to) => {
+                message.push(1);
+                message.extend_from_slice(reply_to);
+            }
+            None => message.push(0)
+        }
+
+        message.extend_from_slice(&(content.len() as u64).to_be_bytes());
+        message.extend_from_slice(content);
+        message
+    }
+
+    pub fn new(identity: &SecretKey, recipient: NodeId, packet_type: PacketType, id: MessageId, reply_to: Option<MessageId>, content: Vec<u8>) -> Self {
+        let timestamp = timestamp();
+        let signed = Self::signed_bytes(packet_type, identity.public(), recipient, &id, timestamp, reply_to.as_ref(), &content);
+
+        Self {
+            id,
+            timestamp,
+            signature: identity.sign(&signed),
+            reply_to,
+            content
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.to_vec();
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.signature.to_bytes());
+
+        match self.reply_to.as_ref() {
+            Some(reply_to) => {
+                bytes.push(1);
+                bytes.extend_from_slice(reply_to);
+            }
+            None => bytes.push(0)
+        }
+
+        bytes.extend_from_slice(&self.content);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Res<Self> {
+        if bytes.len() < 89 { return Err(Error::InvalidSignature); }
+
+        let id: MessageId = bytes[..16].try_into().map_err(|_| Error::InvalidSignature)?;
+        let timestamp: [u8; 8] = bytes[16..24].try_into().map_err(|_| Error::InvalidSignature)?;
+        let signature: [u8; 64] = bytes[24..88].try_into().map_err(|_| Error::InvalidSignature)?;
+
+        let (reply_to, content) = match bytes[88] {
+            0 => (None, &bytes[89..]),
+            _ if bytes.len() >= 105 => (Some(bytes[89..105].try_into().map_err(|_| Error::InvalidSignature)?), &bytes[105..]),
+            _ => return Err(Error::InvalidSignature)
+        };
+
+        Ok(Self {
+            id,
+            timestamp: u64::from_be_bytes(timestamp),
+            signature: Signature::from_bytes(&signature),
+            reply_to,
+            content: content.to_vec()
+        })
+    }
+
+    pub fn verify(&self, packet_type: PacketType, author: NodeId, recipient: NodeId) -> Res<()> {
+        author.verify(&self.message(packet_type, author, recipient), &self.signature).map_err(|_| Error::InvalidSignature)
+    }
+
+    /// The packet as it is kept in the conversation, holding only the text.
+    pub fn into_packet(self, author: NodeId, packet_type: PacketType) -> Packet {
+        Packet {
+            author,
+            content: Ok(self.content),
+            packet_type,
+            timestamp: self.timestamp,
+            signature: Some(self.signature),
+            id: Some(self.id),
+            revision: Revision::Original,
+            reply_to: self.reply_to
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn key() -> SecretKey {
+        SecretKey::generate(&mut OsRng)
+    }
+
+    #[test]
+    fn address_claim_verifies_for_its_client_only() {
+        let (server, client, other) = (key(), key().public(), key().public());
+        let claim = AddressClaim::from_bytes(&AddressClaim::new(&server, client).to_bytes()).unwrap();
+
+        assert_eq!(claim.verify(client).unwrap(), server.public());
+        assert!(matches!(claim.verify(other), Err(Error::HandshakeFailed)));
+    }
+
+    #[test]
+    fn address_claim_rejects_bad_lengths() {
+        let bytes = AddressClaim::new(&key(), key().public()).to_bytes();
+        assert!(AddressClaim::from_bytes(&bytes[..95]).is_err());
+        assert!(AddressClaim::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+    }
+
+    #[test]
+    fn reply_ids_cannot_pass_for_text() {
+        let (author, recipient) = (key(), key().public());
+        let (id, reply_to): (MessageId, MessageId) = (rand::random(), rand::random());
+
+        // The same bytes once as a reply and once as plain text starting with the reply id.
+        let reply = SignedMessage::new(&author, recipient, PacketType::String, id, Some(reply_to), b"text".to_vec());
+        reply.verify(PacketType::String, author.public(), recipient).unwrap();
+
+        let forged = SignedMessage { reply_to: None, content: [reply_to.as_slice(), b"text"].concat(), ..reply.clone() };
+        assert!(forged.verify(PacketType::String, author.public(), recipient).is_err());
+
+        let roundtrip = SignedMessage::from_bytes(&reply.to_bytes()).unwrap();
+        assert_eq!(roundtrip.reply_to, Some(reply_to));
+        roundtrip.verify(PacketType::String, author.public(), recipient).unwrap();
+    }
+
+    #[test]
+    fn deleted_messages_refuse_later_amendments() {
+        let (author, recipient, id) = (key(), key().public(), rand::random());
+        let signed = |packet_type, text: &[u8]| SignedMessage::new(&author, recipient, packet_type, id, None, text.to_vec())
+            .into_packet(author.public(), packet_type);
+        let mut conversation = vec![signed(PacketType::String, b"first")];
+
+        Packet::amend(&mut conversation, &signed(PacketType::Delete, b""), |_| false).unwrap();
+        assert!(matches!(Packet::amend(&mut conversation, &signed(PacketType::Edit, b"back"), |_| false), Err(Error::NoSuchMessage)));
+        assert_eq!(conversation[0].revision, Revision::Deleted);
+        assert_eq!(conversation[0].content.as_deref().unwrap(), b"");
+    }
+
+    #[test]
+    fn reactions_are_a_single_short_emoji() {
+        for emoji in ["👍", "❤️", "👍🏽", "🇳🇱", "👨‍👩‍👧‍👦", "1️⃣"] {
+            assert!(Reaction::is_valid_emoji(emoji), "{emoji}");
+        }
+        // The last is one grapheme, but far past the byte cap.
+        for emoji in ["", "👍👍", "ok", " ", "\n", "👍 ", &format!("👍{}", "\u{301}".repeat(20))] {
+            assert!(!Reaction::is_valid_emoji(emoji), "{emoji:?}");
+        }
+
+        let reaction = Reaction { id: rand::random(), emoji: String::from("🎉"), added: true };
+        assert_eq!(Reaction::from_bytes(&reaction.to_bytes()).unwrap().emoji, "🎉");
+
+        let spam = Reaction { emoji: "🎉".repeat(1000), ..reaction };
+        assert!(matches!(Reaction::from_bytes(&spam.to_bytes()), Err(Error::InvalidReaction)));
+    }
+
+    #[test]
+    fn rotation_verifies_from_either_server() {
+        let (old, new) = (key(), key());
+        let statement = RotationStatement::from_bytes(&RotationStatement::new(&old, &new).to_bytes()).unwrap();
+
+        assert_eq!(statement.verify(old.public()).unwrap(), new.public());
+        assert_eq!(statement.verify(new.public()).unwrap(), new.public());
+        assert!(matches!(statement.verify(key().public()), Err(Error::InvalidRotation)));
+    }
+
+    #[test]
+    fn rotation_rejects_a_missing_signature() {
+        let (old, new, forger) = (key(), key(), key());
+
+        // Signed by the new key and a stranger rather than the key being rotated away from.
+        let mut bytes = RotationStatement::new(&forger, &new).to_bytes();
+        bytes[..32].copy_from_slice(old.public().as_bytes());
+        let statement = RotationStatement::from_bytes(&bytes).unwrap();
+        assert!(matches!(statement.verify(new.public()), Err(Error::InvalidRotation)));
     }
 }
@@ -0,0 +1,255 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use ed25519_dalek::Signature;
+use iroh::{NodeAddr, NodeId, RelayUrl, SecretKey};
+
+use crate::error::{Error, Res};
+
+/// Upper case hex after the prefix keeps the whole ticket within the QR alphanumeric character set.
+const PREFIX: &str = "PINGPONG:";
+const VERSION: u8 = 2;
+
+/// Domain separation for ticket signatures, so they cannot be replayed as any other signed pingpong payload.
+const TICKET_CONTEXT: &[u8] = b"pingpong/ticket";
+
+/// Random secret a one-time invite carries. The contact presenting it is saved without further steps.
+pub type InviteSecret = [u8; 16];
+
+/// Everything needed to reach us without discovery: the full address, our username, and optionally a
+/// one-time secret. Signed by the node it points to, so nobody else can hand out a ticket putting a name
+/// or address to that node.
+#[derive(Clone, Debug)]
+pub struct Ticket {
+    pub addr: NodeAddr,
+    pub username: Option<String>,
+    pub secret: Option<InviteSecret>,
+    signature: Signature
+}
+
+impl Ticket {
+    /// A ticket for the identity's own address.
+    pub fn new(identity: &SecretKey, addr: NodeAddr, username: Option<String>, secret: Option<InviteSecret>) -> Self {
+        let addr = NodeAddr { node_id: identity.public(), ..addr };
+        let signature = identity.sign(&[TICKET_CONTEXT, &Self::body(&addr, username.as_deref(), secret)].concat());
+        Self { addr, username, secret, signature }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Self::body(&self.addr, self.username.as_deref(), self.secret);
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes
+    }
+
+    /// Everything the signature covers.
+    fn body(addr: &NodeAddr, username: Option<&str>, secret: Option<InviteSecret>) -> Vec<u8> {
+        let mut bytes = vec![VERSION];
+        bytes.extend_from_slice(addr.node_id.as_bytes());
+        push_field(&mut bytes, addr.relay_url.as_ref().map(|url| url.to_string()).unwrap_or_default().as_bytes());
+
+        bytes.push(addr.direct_addresses.len().min(u8::MAX as usize) as u8);
+        for addr in addr.direct_addresses.iter().take(u8::MAX as usize) {
+            match addr.ip() {
+                IpAddr::V4(ip) => { bytes.push(4); bytes.extend_from_slice(&ip.octets()); }
+                IpAddr::V6(ip) => { bytes.push(6); bytes.extend_from_slice(&ip.octets()); }
+            }
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+        }
+
+        push_field(&mut bytes, username.unwrap_or_default().as_bytes());
+
+        match secret {
+            Some(secret) => { bytes.push(1); bytes.extend_from_slice(&secret); }
+            None => bytes.push(0)
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Res<Self> {
+        let mut reader = Reader { bytes };
+        if reader.take(1)? != [VERSION] { return Err(Error::InvalidTicket) }
+
+        let node_id = NodeId::from_bytes(&reader.array()?).map_err(|_| Error::InvalidTicket)?;
+        let relay_url = match reader.field()? {
+            [] => None,
+            url => Some(RelayUrl::from_str(std::str::from_utf8(url).map_err(|_| Error::InvalidTicket)?).map_err(|_| Error::InvalidTicket)?)
+        };
+
+        let count = reader.take(1)?[0];
+        let mut direct_addresses = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let ip = match reader.take(1)?[0] {
+                4 => IpAddr::V4(Ipv4Addr::from(reader.array::<4>()?)),
+                6 => IpAddr::V6(Ipv6Addr::from(reader.array::<16>()?)),
+                _ => return Err(Error::InvalidTicket)
+            };
+            direct_addresses.push(SocketAddr::new(ip, u16::from_be_bytes(reader.array()?)));
+        }
+
+        let username = match reader.field()? {
+            [] => None,
+            name => Some(String::from_utf8(name.to_vec()).map_err(|_| Error::InvalidTicket)?)
+        };
+
+        let secret = match reader.take(1)?[0] {
+            0 => None,
+            1 => Some(reader.array()?),
+            _ => return Err(Error::InvalidTicket)
+        };
+
+        let body = &bytes[..bytes.len() - reader.bytes.len()];
+        let signature = Signature::from_bytes(&reader.array()?);
+        if !reader.bytes.is_empty() { return Err(Error::InvalidTicket) }
+        node_id.verify(&[TICKET_CONTEXT, body].concat(), &signature).map_err(|_| Error::InvalidTicket)?;
+
+        Ok(Self { addr: NodeAddr::from_parts(node_id, relay_url, direct_addresses), username, secret, signature })
+    }
+}
+
+impl Display for Ticket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{PREFIX}{}", hex::encode_upper(self.to_bytes()))
+    }
+}
+
+impl FromStr for Ticket {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Res<Self> {
+        let s = s.trim();
+        let encoded = s.get(..PREFIX.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(PREFIX))
+            .map(|_| &s[PREFIX.len()..])
+            .ok_or(Error::InvalidTicket)?;
+
+        Self::from_bytes(&hex::decode(encoded).map_err(|_| Error::InvalidTicket)?)
+    }
+}
+
+/// Payload of an Invite packet: the one-time secret from the ticket we were given, and our own ticket so
+/// the inviter can connect back without discovery.
+#[derive(Clone, Debug)]
+pub struct Invite {
+    pub secret: InviteSecret,
+    pub ticket: Ticket
+}
+
+impl Invite {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.secret.to_vec();
+        bytes.extend_from_slice(&self.ticket.to_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Res<Self> {
+        if bytes.len() < 16 { return Err(Error::MalformedPacket) }
+        let secret = bytes[..16].try_into().map_err(|_| Error::MalformedPacket)?;
+        Ok(Self { secret, ticket: Ticket::from_bytes(&bytes[16..])? })
+    }
+}
+
+/// Variable length fields are prefixed with their length as a big endian u16.
+fn push_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    let field = &field[..field.len().min(u16::MAX as usize)];
+    bytes.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(field);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Res<&'a [u8]> {
+        if self.bytes.len() < n { return Err(Error::InvalidTicket) }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Res<[u8; N]> {
+        self.take(N)?.try_into().map_err(|_| Error::InvalidTicket)
+    }
+
+    fn field(&mut self) -> Res<&'a [u8]> {
+        let len = u16::from_be_bytes(self.array()?);
+        self.take(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    fn ticket(identity: &SecretKey) -> Ticket {
+        let addr = NodeAddr::from_parts(
+            identity.public(),
+            Some(RelayUrl::from_str("https://relay.example.com").unwrap()),
+            ["192.168.1.2:4433".parse().unwrap(), "[fe80::1]:4433".parse().unwrap()]
+        );
+        Ticket::new(identity, addr, Some(String::from("alice")), Some([7; 16]))
+    }
+
+    #[test]
+    fn tickets_round_trip() {
+        let identity = SecretKey::generate(&mut OsRng);
+        let original = ticket(&identity);
+
+        let parsed = Ticket::from_str(&original.to_string()).unwrap();
+        assert_eq!(parsed.addr, original.addr);
+        assert_eq!(parsed.username.as_deref(), Some("alice"));
+        assert_eq!(parsed.secret, Some([7; 16]));
+
+        // Lower case input, as some QR scanners produce, and surrounding whitespace are accepted.
+        assert!(Ticket::from_str(&format!("  {}\n", original.to_string().to_lowercase())).is_ok());
+
+        let invite = Invite { secret: [9; 16], ticket: original };
+        let parsed = Invite::from_bytes(&invite.to_bytes()).unwrap();
+        assert_eq!(parsed.secret, [9; 16]);
+        assert_eq!(parsed.ticket.addr.node_id, identity.public());
+    }
+
+    #[test]
+    fn forged_tickets_are_rejected() {
+        let (identity, other) = (SecretKey::generate(&mut OsRng), SecretKey::generate(&mut OsRng));
+        let bytes = ticket(&identity).to_bytes();
+
+        // Someone else's name put to our node.
+        let mut renamed = ticket(&identity);
+        renamed.username = Some(String::from("mallory"));
+        assert!(matches!(Ticket::from_bytes(&renamed.to_bytes()), Err(Error::InvalidTicket)));
+
+        // A ticket signed by a different key than the node it names.
+        let mut stolen = ticket(&other).to_bytes();
+        stolen[1..33].copy_from_slice(identity.public().as_bytes());
+        assert!(matches!(Ticket::from_bytes(&stolen), Err(Error::InvalidTicket)));
+
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(Ticket::from_bytes(&flipped), Err(Error::InvalidTicket)));
+    }
+
+    #[test]
+    fn malformed_tickets_are_rejected() {
+        let bytes = ticket(&SecretKey::generate(&mut OsRng)).to_bytes();
+
+        for len in 0..bytes.len() {
+            assert!(matches!(Ticket::from_bytes(&bytes[..len]), Err(Error::InvalidTicket)), "truncated to {len}");
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(Ticket::from_bytes(&trailing), Err(Error::InvalidTicket)));
+
+        let mut version = bytes.clone();
+        version[0] = 1;
+        assert!(matches!(Ticket::from_bytes(&version), Err(Error::InvalidTicket)));
+
+        assert!(Ticket::from_str("PINGPONG:not hex").is_err());
+        assert!(Ticket::from_str(&hex::encode_upper(&bytes)).is_err());
+        assert!(matches!(Invite::from_bytes(&[0; 8]), Err(Error::MalformedPacket)));
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use iroh::discovery::mdns::MdnsDiscovery;
+use iroh::discovery::static_provider::StaticProvider;
+use iroh::endpoint::Builder;
+use iroh::{Endpoint, RelayMap, RelayMode, RelayUrl, Watcher};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::error::{Error, Res};
+use crate::networking::ticket::Ticket;
+
+/// How long a relay has to accept us before it counts as unreachable.
+const RELAY_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a contact known only by key is located.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Discovery {
+    #[default]
+    N0,
+    Local,
+    AddressBook,
+    None
+}
+
+impl Discovery {
+    pub const ALL: [Self; 4] = [Self::N0, Self::Local, Self::AddressBook, Self::None];
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Self::N0 => "n0",
+            Self::Local => "local",
+            Self::AddressBook => "address_book",
+            Self::None => "none"
+        }
+    }
+
+    pub fn from_key(key: &str) -> Self {
+        Self::ALL.into_iter().find(|discovery| discovery.key() == key).unwrap_or_default()
+    }
+}
+
+impl Display for Discovery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::N0 => "INTERNET (N0 DNS)",
+            Self::Local => "LOCAL NETWORK (MDNS)",
+            Self::AddressBook => "ADDRESS BOOK ONLY",
+            Self::None => "NONE (TICKETS ONLY)"
+        })
+    }
+}
+
+/// Where traffic goes when no direct path exists.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum RelaySetting {
+    #[default]
+    Default,
+    // Self-hosted relays. Each endpoint picks the one with the lowest latency as its home relay.
+    Custom(Vec<RelayUrl>),
+    Disabled
+}
+
+impl RelaySetting {
+    /// Empty or "default" for the n0 relays, "disabled" for none, anything else is a list of relay URLs
+    /// separated by spaces or commas.
+    pub fn parse(input: &str) -> Res<Self> {
+        match input.trim() {
+            "" | "default" => Ok(Self::Default),
+            "disabled" => Ok(Self::Disabled),
+            urls => urls.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|url| !url.is_empty())
+                .map(|url| RelayUrl::from_str(url).map_err(|_| Error::InvalidRelayUrl))
+                .collect::<Res<Vec<_>>>()
+                .map(Self::Custom)
+        }
+    }
+
+    fn mode(&self) -> RelayMode {
+        match self {
+            Self::Default => RelayMode::Default,
+            Self::Custom(urls) => RelayMode::Custom(urls.iter().cloned().collect::<RelayMap>()),
+            Self::Disabled => RelayMode::Disabled
+        }
+    }
+}
+
+impl Display for RelaySetting {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default => f.write_str("default"),
+            Self::Custom(urls) => f.write_str(&urls.iter().map(|url| url.to_string()).collect::<Vec<_>>().join(" ")),
+            Self::Disabled => f.write_str("disabled")
+        }
+    }
+}
+
+/// What to do when none of the self-hosted relays answers at startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RelayFallback {
+    // Never send traffic through a third party the user chose to avoid.
+    #[default]
+    DirectOnly,
+    N0
+}
+
+impl RelayFallback {
+    pub const ALL: [Self; 2] = [Self::DirectOnly, Self::N0];
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Self::DirectOnly => "direct_only",
+            Self::N0 => "n0"
+        }
+    }
+
+    pub fn from_key(key: &str) -> Self {
+        Self::ALL.into_iter().find(|fallback| fallback.key() == key).unwrap_or_default()
+    }
+
+    /// What taking the fallback means for the user.
+    pub fn consequence(self) -> &'static str {
+        match self {
+            Self::DirectOnly => "Only direct connections will work.",
+            Self::N0 => "Falling back to the public n0 relays."
+        }
+    }
+}
+
+impl Display for RelayFallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::DirectOnly => "DIRECT CONNECTIONS ONLY",
+            Self::N0 => "PUBLIC N0 RELAYS"
+        })
+    }
+}
+
+/// Outcome of connecting to one relay: how long it took to be accepted, or None if it never did.
+#[derive(Clone, Debug)]
+pub struct RelayProbe {
+    pub url: RelayUrl,
+    pub latency: Option<Duration>
+}
+
+/// Check that a relay accepts us by binding a throwaway endpoint that may only use that relay, and waiting
+/// for it to become our home relay. A local `iroh-relay --dev` answers at http://localhost:3340.
+pub async fn probe_relay(url: RelayUrl) -> RelayProbe {
+    let start = Instant::now();
+    let endpoint = match Endpoint::builder().relay_mode(RelayMode::Custom(RelayMap::from(url.clone()))).bind().await {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            warn!(relay = %url, error = %e, "could not bind relay probe");
+            return RelayProbe { url, latency: None };
+        }
+    };
+
+    let latency = timeout(RELAY_PROBE_TIMEOUT, endpoint.home_relay().initialized()).await.ok().map(|_| start.elapsed());
+    endpoint.close().await;
+    RelayProbe { url, latency }
+}
+
+/// Probe every relay at once, so an unreachable one costs a single timeout rather than one each.
+pub async fn probe_relays(urls: Vec<RelayUrl>) -> Vec<RelayProbe> {
+    let handles: Vec<_> = urls.into_iter().map(|url| tokio::spawn(probe_relay(url))).collect();
+    let mut probes = Vec::with_capacity(handles.len());
+    for handle in handles {
+        if let Ok(probe) = handle.await { probes.push(probe); }
+    }
+    probes
+}
+
+/// Discovery and relay configuration shared by the server endpoint and every client endpoint, so both
+/// halves of a conversation travel the same way.
+#[derive(Clone, Debug, Default)]
+pub struct Transport {
+    pub discovery: Discovery,
+    pub relay: RelaySetting,
+
+    // Known addresses of contacts, as tickets, for networks without any discovery service.
+    pub address_book: Vec<Ticket>,
+
+    // Opt-in to appearing, with our username, in the nearby list of pingpong users on the same network.
+    pub broadcast: bool,
+
+    // Opt-in to listing pingpong users on the same network. Separate from broadcast, so people can look
+    // without being seen, or be seen without looking.
+    pub listen: bool,
+    pub fallback: RelayFallback
+}
+
+impl Transport {
+    /// The address book is stored as whitespace separated tickets. Entries that fail to parse are dropped.
+    pub fn parse_address_book(stored: &str) -> Vec<Ticket> {
+        stored.split_whitespace().filter_map(|ticket| Ticket::from_str(ticket).ok()).collect()
+    }
+
+    pub fn address_book_key(&self) -> String {
+        self.address_book.iter().map(|ticket| ticket.to_string()).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Drop self-hosted relays that fail a probe, applying the fallback if none is left. Returns the
+    /// transport to start with and an error for each relay skipped or fallback taken.
+    pub async fn resolve(mut self) -> (Self, Vec<Error>) {
+        let RelaySetting::Custom(urls) = &self.relay else { return (self, Vec::new()) };
+
+        let mut errors = Vec::new();
+        let mut reachable = Vec::new();
+        for probe in probe_relays(urls.clone()).await {
+            match probe.latency {
+                Some(latency) => {
+                    info!(relay = %probe.url, latency_ms = latency.as_millis() as u64, "relay reachable");
+                    reachable.push(probe.url);
+                }
+                None => {
+                    warn!(relay = %probe.url, "relay unreachable");
+                    errors.push(Error::RelayUnreachable(probe.url));
+                }
+            }
+        }
+
+        if reachable.is_empty() {
+            errors.push(Error::NoRelayReachable(self.fallback.consequence()));
+            self.relay = match self.fallback {
+                RelayFallback::DirectOnly => RelaySetting::Disabled,
+                RelayFallback::N0 => RelaySetting::Default
+            };
+        } else {
+            self.relay = RelaySetting::Custom(reachable);
+        }
+        (self, errors)
+    }
+
+    /// A client endpoint builder configured for this transport. Addresses in the address book are known
+    /// whichever discovery is chosen. Local discovery only looks, so our throwaway ids are never advertised.
+    pub fn builder(&self) -> Builder {
+        match self.discovery {
+            Discovery::Local => self.base().add_discovery(MdnsDiscovery::builder().advertise(false)),
+            _ => self.base()
+        }
+    }
+
+    /// The server endpoint advertises itself on the local network when contacts are found there, or when the
+    /// user chose to be seen by people nearby. Looking for people nearby only needs to listen.
+    pub fn server_builder(&self) -> Builder {
+        if self.discovery == Discovery::Local || self.broadcast {
+            self.base().add_discovery(MdnsDiscovery::builder())
+        } else if self.listen {
+            self.base().add_discovery(MdnsDiscovery::builder().advertise(false))
+        } else {
+            self.base()
+        }
+    }
+
+    /// Relays, the address book and any discovery other than the local network. The caller adds the secret key
+    /// if it has one.
+    fn base(&self) -> Builder {
+        let builder = Endpoint::builder()
+            .relay_mode(self.relay.mode())
+            .add_discovery(StaticProvider::from_node_info(self.address_book.iter().map(|ticket| ticket.addr.clone())));
+        match self.discovery {
+            Discovery::N0 => builder.discovery_n0(),
+            Discovery::Local | Discovery::AddressBook | Discovery::None => builder
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::discovery::DiscoveryEvent;
+    use n0_future::StreamExt;
+
+    use super::*;
+
+    // Nothing listens on the discard port, so this relay never answers.
+    const DEAD_RELAY: &str = "http://127.0.0.1:9";
+    // Start with `iroh-relay --dev`.
+    const DEV_RELAY: &str = "http://localhost:3340";
+
+    fn custom(urls: &[&str], fallback: RelayFallback) -> Transport {
+        let urls = urls.iter().map(|url| RelayUrl::from_str(url).unwrap()).collect();
+        Transport { relay: RelaySetting::Custom(urls), fallback, ..Transport::default() }
+    }
+
+    #[tokio::test]
+    async fn unreachable_relays_take_the_fallback() {
+        let (direct, errors) = custom(&[DEAD_RELAY], RelayFallback::DirectOnly).resolve().await;
+        assert_eq!(direct.relay, RelaySetting::Disabled);
+        assert!(matches!(errors.as_slice(), [Error::RelayUnreachable(_), Error::NoRelayReachable(_)]));
+        assert!(errors[1].to_string().contains(RelayFallback::DirectOnly.consequence()));
+
+        let (public, errors) = custom(&[DEAD_RELAY], RelayFallback::N0).resolve().await;
+        assert_eq!(public.relay, RelaySetting::Default);
+        assert!(matches!(errors.as_slice(), [Error::RelayUnreachable(_), Error::NoRelayReachable(_)]));
+        assert!(errors[1].to_string().contains(RelayFallback::N0.consequence()));
+    }
+
+    #[tokio::test]
+    async fn client_endpoints_are_never_advertised() {
+        let local = |listen| Transport { discovery: Discovery::Local, relay: RelaySetting::Disabled, listen, ..Transport::default() };
+        let watcher = local(true).server_builder().bind().await.unwrap();
+        let server = local(false).server_builder().bind().await.unwrap();
+        let client = local(false).builder().bind().await.unwrap();
+
+        // Watch until the server has been found, and a little longer for the client.
+        let mut events = watcher.discovery_stream();
+        let mut seen = Vec::new();
+        let mut deadline = Instant::now() + Duration::from_secs(10);
+        while let Ok(Some(event)) = timeout(deadline.saturating_duration_since(Instant::now()), events.next()).await {
+            if let Ok(DiscoveryEvent::Discovered(item)) = event { seen.push(item.node_id()) }
+            if seen.contains(&server.node_id()) { deadline = deadline.min(Instant::now() + Duration::from_secs(2)) }
+        }
+
+        assert!(seen.contains(&server.node_id()));
+        assert!(!seen.contains(&client.node_id()));
+        for endpoint in [watcher, server, client] { endpoint.close().await }
+    }
+
+    #[tokio::test]
+    async fn only_custom_relays_are_probed() {
+        let (transport, errors) = Transport::default().resolve().await;
+        assert_eq!(transport.relay, RelaySetting::Default);
+        assert!(errors.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local `iroh-relay --dev`"]
+    async fn reachable_relays_are_kept_whatever_the_fallback() {
+        let live = RelayUrl::from_str(DEV_RELAY).unwrap();
+        for fallback in RelayFallback::ALL {
+            let (transport, errors) = custom(&[DEV_RELAY, DEAD_RELAY], fallback).resolve().await;
+            assert_eq!(transport.relay, RelaySetting::Custom(vec![live.clone()]));
+            assert!(matches!(errors.as_slice(), [Error::RelayUnreachable(_)]));
+        }
+    }
+}